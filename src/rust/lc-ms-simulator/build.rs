@@ -1,12 +1,66 @@
+use std::env;
+use std::path::PathBuf;
+
+/// Enum fields, which prost generates as `i32`, with the enum that names their values
+/// in JSON
+const ENUM_FIELDS: &[(&str, &str)] = &[
+    ("SpectrumEncoding.mz", "MzEncoding"),
+    ("SpectrumEncoding.intensity", "IntensityEncoding"),
+    ("EncodedSpectrum.mz_encoding", "MzEncoding"),
+    ("EncodedSpectrum.intensity_encoding", "IntensityEncoding"),
+    ("ScanFilter.polarity", "Polarity"),
+    ("ScanMessage.fragmentation_type", "FragmentationType"),
+    ("ScanMessage.polarity", "Polarity"),
+    ("SessionClosed.final_state", "AcquisitionState"),
+    ("StatusResponse.state", "AcquisitionState"),
+    ("StatusResponse.instrument_state", "InstrumentState"),
+    ("SimulationParameters.fragmentation_type", "FragmentationType"),
+    ("InstrumentSummary.state", "AcquisitionState"),
+    ("InstrumentSummary.instrument_state", "InstrumentState"),
+    ("StatusLogRecord.acquisition_state", "AcquisitionState"),
+    ("StatusLogRecord.instrument_state", "InstrumentState"),
+    ("SetInstrumentModeRequest.mode", "InstrumentMode"),
+    ("InstrumentModeResponse.mode", "InstrumentMode"),
+    ("InstrumentModeResponse.state", "InstrumentState"),
+    ("ReplayOptions.pacing", "ReplayPacing"),
+    ("RecordingOptions.format", "RecordingFormat"),
+    ("StreamLogHeader.compression", "StreamLogCompression"),
+];
+
+/// Repeated enum fields
+const ENUM_LIST_FIELDS: &[(&str, &str)] = &[(
+    "InstrumentInfoResponse.supported_fragmentation_types",
+    "FragmentationType",
+)];
+
 fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let out_dir = PathBuf::from(env::var("OUT_DIR")?);
+
     // Compile the protobuf file
-    tonic_build::configure()
+    let mut builder = tonic_build::configure()
         .build_server(true)
-        .build_client(false)
-        .compile_protos(
-            &["../../../protos/simulator.proto"],
-            &["../../../protos"],
-        )?;
+        .build_client(true)
+        // Descriptor set for gRPC server reflection
+        .file_descriptor_set_path(out_dir.join("simulator_descriptor.bin"))
+        // JSON for the REST gateway, with proto field names and enum value names
+        .type_attribute(".", "#[derive(serde::Serialize, serde::Deserialize)]")
+        .message_attribute(".", "#[serde(default)]");
+    for (field, enumeration) in ENUM_FIELDS {
+        builder = builder.field_attribute(
+            format!(".orbitrap.simulator.v1.{}", field),
+            format!("#[serde(with = \"crate::proto::json::EnumName::<{}>\")]", enumeration),
+        );
+    }
+    for (field, enumeration) in ENUM_LIST_FIELDS {
+        builder = builder.field_attribute(
+            format!(".orbitrap.simulator.v1.{}", field),
+            format!("#[serde(with = \"crate::proto::json::EnumNames::<{}>\")]", enumeration),
+        );
+    }
+    builder.compile_protos(
+        &["../../../protos/simulator.proto"],
+        &["../../../protos"],
+    )?;
 
     // Rerun if proto file changes
     println!("cargo:rerun-if-changed=../../../protos/simulator.proto");
@@ -0,0 +1,150 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use anyhow::{bail, Context, Result};
+use tonic::service::Interceptor;
+use tonic::{Request, Status};
+use tracing::warn;
+
+/// Access level granted to an authenticated client.
+///
+/// Observers may stream scans and read status/instrument info; controllers may
+/// additionally start, stop, pause and resume acquisitions; admins may also inject faults.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Role {
+    Observer,
+    Controller,
+    Admin,
+}
+
+impl FromStr for Role {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.trim().to_lowercase().as_str() {
+            "observer" => Ok(Role::Observer),
+            "controller" => Ok(Role::Controller),
+            "admin" => Ok(Role::Admin),
+            other => bail!("Unknown role '{}' (expected observer, controller or admin)", other),
+        }
+    }
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Role::Observer => write!(f, "observer"),
+            Role::Controller => write!(f, "controller"),
+            Role::Admin => write!(f, "admin"),
+        }
+    }
+}
+
+/// Validates bearer tokens / API keys and attaches the caller's [`Role`] to the request.
+///
+/// Tokens are read from `authorization: Bearer <token>` or `x-api-key: <token>`.
+/// With no tokens configured, authentication is disabled and every caller is an admin.
+#[derive(Clone, Default)]
+pub struct AuthInterceptor {
+    tokens: Arc<HashMap<String, Role>>,
+}
+
+impl AuthInterceptor {
+    pub fn new(tokens: HashMap<String, Role>) -> Self {
+        Self {
+            tokens: Arc::new(tokens),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.tokens.is_empty()
+    }
+
+    /// Parses a `<role>:<token>` specification as given on the command line
+    pub fn parse_token_spec(spec: &str) -> Result<(String, Role)> {
+        let (role, token) = spec
+            .split_once(':')
+            .with_context(|| format!("Invalid token spec '{}' (expected <role>:<token>)", spec))?;
+        let token = token.trim();
+        if token.is_empty() {
+            bail!("Empty token for role '{}'", role);
+        }
+        Ok((token.to_string(), role.parse()?))
+    }
+
+    /// Loads `<role>:<token>` lines from a file; blank lines and `#` comments are ignored
+    pub fn load_token_file(path: &Path) -> Result<Vec<(String, Role)>> {
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("Cannot read token file {}", path.display()))?;
+
+        contents
+            .lines()
+            .enumerate()
+            .map(|(i, line)| (i, line.trim()))
+            .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+            .map(|(i, line)| {
+                Self::parse_token_spec(line)
+                    .with_context(|| format!("{}:{}", path.display(), i + 1))
+            })
+            .collect()
+    }
+
+    fn extract_token(request: &Request<()>) -> Option<&str> {
+        let metadata = request.metadata();
+
+        if let Some(value) = metadata.get("authorization").and_then(|v| v.to_str().ok()) {
+            return value
+                .strip_prefix("Bearer ")
+                .or_else(|| value.strip_prefix("bearer "))
+                .map(str::trim);
+        }
+
+        metadata
+            .get("x-api-key")
+            .and_then(|v| v.to_str().ok())
+            .map(str::trim)
+    }
+}
+
+impl Interceptor for AuthInterceptor {
+    fn call(&mut self, mut request: Request<()>) -> Result<Request<()>, Status> {
+        let role = if self.is_enabled() {
+            let token = Self::extract_token(&request).ok_or_else(|| {
+                Status::unauthenticated("Missing bearer token or x-api-key metadata")
+            })?;
+
+            match self.tokens.get(token) {
+                Some(role) => *role,
+                None => {
+                    warn!(
+                        "Rejected request with unknown token from {:?}",
+                        request.remote_addr()
+                    );
+                    return Err(Status::unauthenticated("Invalid token"));
+                }
+            }
+        } else {
+            Role::Admin
+        };
+
+        request.extensions_mut().insert(role);
+        Ok(request)
+    }
+}
+
+/// Checks that the caller holds at least `required`.
+///
+/// Requests that did not pass through [`AuthInterceptor`] carry no role and are rejected.
+pub fn authorize<T>(request: &Request<T>, required: Role) -> Result<(), Status> {
+    match request.extensions().get::<Role>() {
+        Some(role) if *role >= required => Ok(()),
+        Some(role) => Err(Status::permission_denied(format!(
+            "This call requires the {} role; token grants {}",
+            required, role
+        ))),
+        None => Err(Status::unauthenticated("Request was not authenticated")),
+    }
+}
@@ -0,0 +1,148 @@
+use std::time::Duration;
+
+use prost::Message;
+use tokio::sync::mpsc;
+use tokio::time::{timeout_at, Instant};
+use tokio_stream::wrappers::errors::BroadcastStreamRecvError;
+use tokio_stream::wrappers::ReceiverStream;
+use tokio_stream::{Stream, StreamExt};
+use tonic::Status;
+use tracing::warn;
+
+use crate::encoding::SpectrumEncoder;
+use crate::metrics::SubscriberGuard;
+use crate::proto::{stream_event, ScanBatch, StreamEvent, StreamScanBatchesRequest};
+
+const DEFAULT_MAX_SCANS: usize = 1000;
+const DEFAULT_MAX_BYTES: usize = 1024 * 1024;
+const DEFAULT_MAX_LINGER: Duration = Duration::from_millis(10);
+
+/// Flush thresholds for a batching subscriber
+#[derive(Debug, Clone, Copy)]
+pub struct BatchLimits {
+    pub max_scans: usize,
+    pub max_bytes: usize,
+    pub max_linger: Duration,
+}
+
+impl BatchLimits {
+    pub fn from_request(req: &StreamScanBatchesRequest) -> Self {
+        Self {
+            max_scans: if req.max_scans > 0 {
+                req.max_scans as usize
+            } else {
+                DEFAULT_MAX_SCANS
+            },
+            max_bytes: if req.max_bytes > 0 {
+                req.max_bytes as usize
+            } else {
+                DEFAULT_MAX_BYTES
+            },
+            max_linger: if req.max_linger_ms > 0 {
+                Duration::from_millis(req.max_linger_ms as u64)
+            } else {
+                DEFAULT_MAX_LINGER
+            },
+        }
+    }
+}
+
+/// Groups broadcast scans into [`ScanBatch`]es for one subscriber.
+///
+/// A batch is flushed when it reaches `max_scans` or `max_bytes`, when `max_linger`
+/// has passed since its first scan, or when the session closes. A scan that would take
+/// a batch past `max_bytes` goes into the next one, so a scan larger than `max_bytes` is
+/// sent on its own. Because the acquisition loop emits each timer tick's scans back to
+/// back, a tick normally arrives as a single batch. A pending batch is flushed before
+/// the stream ends.
+pub fn batch_scans(
+    mut events: impl Stream<Item = Result<StreamEvent, BroadcastStreamRecvError>>
+        + Send
+        + Unpin
+        + 'static,
+    encoder: Option<SpectrumEncoder>,
+    limits: BatchLimits,
+    subscriber: SubscriberGuard,
+) -> ReceiverStream<Result<ScanBatch, Status>> {
+    let (tx, rx) = mpsc::channel(4);
+
+    tokio::spawn(async move {
+        let mut batch = ScanBatch::default();
+        let mut batch_bytes = 0usize;
+        let mut deadline: Option<Instant> = None;
+
+        loop {
+            // `None` means the linger time of the pending batch elapsed.
+            let next = async {
+                match deadline {
+                    Some(deadline) => timeout_at(deadline, events.next()).await.ok(),
+                    None => Some(events.next().await),
+                }
+            };
+            let received = tokio::select! {
+                _ = tx.closed() => break, // Client disconnected
+                received = next => received,
+            };
+
+            let mut flush = received.is_none();
+            let mut finished = false;
+
+            match received {
+                Some(Some(Ok(event))) => match event.event {
+                    Some(stream_event::Event::Scan(mut scan)) => {
+                        if let Some(encoder) = &encoder {
+                            encoder.encode(&mut scan);
+                        }
+                        let scan_bytes = scan.encoded_len();
+                        // A scan that would take the batch past max_bytes starts the next one
+                        if !batch.scans.is_empty() && batch_bytes + scan_bytes > limits.max_bytes {
+                            if tx.send(Ok(std::mem::take(&mut batch))).await.is_err() {
+                                // Client disconnected
+                                break;
+                            }
+                            batch_bytes = 0;
+                            deadline = None;
+                        }
+                        batch_bytes += scan_bytes;
+                        subscriber.delivered(scan_bytes);
+                        batch.scans.push(scan);
+                        deadline.get_or_insert_with(|| Instant::now() + limits.max_linger);
+
+                        flush = batch.scans.len() >= limits.max_scans
+                            || batch_bytes >= limits.max_bytes;
+                    }
+                    Some(stream_event::Event::SessionClosed(_)) => {
+                        subscriber.delivered(0);
+                        flush = true;
+                    }
+                    _ => subscriber.delivered(0),
+                },
+                Some(Some(Err(BroadcastStreamRecvError::Lagged(missed)))) => {
+                    warn!("Batch subscriber lagged, {} events dropped", missed);
+                    subscriber.dropped(missed);
+                    batch.missed_scans += missed as i64;
+                }
+                Some(None) => {
+                    flush = true;
+                    finished = true;
+                }
+                None => {}
+            }
+
+            if flush && !batch.scans.is_empty() {
+                if tx.send(Ok(std::mem::take(&mut batch))).await.is_err() {
+                    // Client disconnected
+                    break;
+                }
+                batch_bytes = 0;
+                deadline = None;
+            }
+
+            if finished {
+                break;
+            }
+        }
+    });
+
+    ReceiverStream::new(rx)
+}
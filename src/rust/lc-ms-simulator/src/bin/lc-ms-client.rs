@@ -0,0 +1,590 @@
+//! Command line client for the LC-MS Orbitrap simulator.
+
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Context, Result};
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use lc_ms_simulator::client::{ClientOptions, ReconnectPolicy, SimulatorClient, DEFAULT_ENDPOINT};
+use lc_ms_simulator::proto::stream_event::Event;
+use lc_ms_simulator::proto::*;
+use lc_ms_simulator::stream_log::StreamLogWriter;
+use serde::Serialize;
+use tokio_stream::StreamExt;
+use tonic::Status;
+
+/// LC-MS Orbitrap Simulator client
+///
+/// Queries and drives a running simulator, for scripts and shell-based test rigs.
+/// Exits non-zero when a call fails or the simulator refuses a request.
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+struct Cli {
+    /// Simulator address; https:// connects over TLS
+    #[arg(short, long, env = "LCMS_ENDPOINT", default_value = DEFAULT_ENDPOINT)]
+    endpoint: String,
+
+    /// Bearer token, when the simulator requires auth
+    #[arg(short, long, env = "LCMS_TOKEN", hide_env_values = true)]
+    token: Option<String>,
+
+    /// Instrument to address [default: the simulator's default instrument]
+    #[arg(short, long)]
+    instrument_id: Option<String>,
+
+    /// PEM CA bundle that signed the simulator's certificate
+    #[arg(long)]
+    ca_cert: Option<PathBuf>,
+
+    /// PEM client certificate for mutual TLS
+    #[arg(long, requires = "client_key")]
+    client_cert: Option<PathBuf>,
+
+    /// PEM private key of the client certificate
+    #[arg(long, requires = "client_cert")]
+    client_key: Option<PathBuf>,
+
+    /// Seconds to wait for the connection
+    #[arg(long, default_value_t = 5.0)]
+    connect_timeout: f64,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Show the acquisition and instrument state
+    Status(JsonArg),
+    /// Show the instrument's identity and capabilities
+    Info(JsonArg),
+    /// Start an acquisition and print its session ID
+    Start(StartArgs),
+    /// Stop the running acquisition
+    Stop(StopArgs),
+    /// Print scans and session events as they are acquired
+    Stream(StreamArgs),
+    /// Record scans and session events with their timing to a stream log, which the
+    /// simulator can replay exactly
+    Capture(CaptureArgs),
+}
+
+#[derive(Args, Debug)]
+struct JsonArg {
+    /// Print the response as JSON
+    #[arg(long)]
+    json: bool,
+}
+
+#[derive(Args, Debug)]
+struct StartArgs {
+    /// Stop after this many scans
+    #[arg(long)]
+    max_scans: Option<i32>,
+
+    /// Stop after this many seconds
+    #[arg(long)]
+    max_duration: Option<f64>,
+
+    /// Scans per second [default: the simulator's]
+    #[arg(long)]
+    scan_rate: Option<f64>,
+
+    /// MS2 scans per MS1 scan [default: the simulator's]
+    #[arg(long)]
+    ms2_per_ms1: Option<i32>,
+
+    /// Random seed, for reproducible data
+    #[arg(long)]
+    seed: Option<i64>,
+
+    /// Wait for TriggerStart before acquiring
+    #[arg(long)]
+    wait_for_trigger: bool,
+
+    /// Seconds to wait for the trigger [default: the simulator's]
+    #[arg(long, requires = "wait_for_trigger")]
+    trigger_timeout: Option<f64>,
+
+    /// Record the session on the simulator host
+    #[arg(long)]
+    record: bool,
+
+    /// Recording file name, without extension [default: <instrument>_<session>]
+    #[arg(long, requires = "record")]
+    recording_name: Option<String>,
+
+    /// Recording file format
+    #[arg(long, value_enum, default_value_t = RecordingFormatArg::Mzml, requires = "record")]
+    recording_format: RecordingFormatArg,
+
+    /// Write the recording uncompressed
+    #[arg(long, requires = "record")]
+    no_compression: bool,
+
+    /// Replay this file from the simulator's replay directory instead of synthesizing
+    #[arg(long, value_name = "FILE")]
+    replay: Option<String>,
+
+    /// Spacing of replayed scans [default: real-time, or accelerated with --speed]
+    #[arg(long, value_enum, requires = "replay")]
+    pacing: Option<Pacing>,
+
+    /// Speed-up of accelerated replay [default: the simulator's]
+    #[arg(long, requires = "replay")]
+    speed: Option<f64>,
+
+    #[command(flatten)]
+    output: JsonArg,
+}
+
+#[derive(Args, Debug)]
+struct StopArgs {
+    /// Session to stop [default: the running one]
+    #[arg(long, default_value = "")]
+    session_id: String,
+
+    #[command(flatten)]
+    output: JsonArg,
+}
+
+#[derive(Args, Debug)]
+struct StreamArgs {
+    /// Output format
+    #[arg(short, long, value_enum, default_value_t = Format::Pretty)]
+    format: Format,
+
+    /// Only scans of this MS order (1 or 2)
+    #[arg(long)]
+    ms_order: Option<i32>,
+
+    /// Exit after this many scans
+    #[arg(short = 'n', long)]
+    count: Option<u64>,
+
+    /// Exit when the acquisition session ends
+    #[arg(long)]
+    until_closed: bool,
+
+    /// Exit when the stream breaks instead of reconnecting
+    #[arg(long)]
+    no_reconnect: bool,
+}
+
+#[derive(Args, Debug)]
+struct CaptureArgs {
+    /// Stream log to write; must not exist yet
+    file: PathBuf,
+
+    /// Only scans of this MS order (1 or 2)
+    #[arg(long)]
+    ms_order: Option<i32>,
+
+    /// Exit after this many scans
+    #[arg(short = 'n', long)]
+    count: Option<u64>,
+
+    /// Exit when the acquisition session ends
+    #[arg(long)]
+    until_closed: bool,
+
+    /// Exit when the stream breaks instead of reconnecting
+    #[arg(long)]
+    no_reconnect: bool,
+
+    /// Write the log without zstd compression
+    #[arg(long)]
+    no_compression: bool,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum RecordingFormatArg {
+    /// Indexed mzML with the session's spectra
+    Mzml,
+    /// Every event of the session with its timing, for exact replay
+    StreamLog,
+}
+
+impl From<RecordingFormatArg> for RecordingFormat {
+    fn from(format: RecordingFormatArg) -> Self {
+        match format {
+            RecordingFormatArg::Mzml => RecordingFormat::Mzml,
+            RecordingFormatArg::StreamLog => RecordingFormat::StreamLog,
+        }
+    }
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum Pacing {
+    /// The original retention time spacing
+    RealTime,
+    /// The original spacing shortened by --speed
+    Accelerated,
+    /// Each scan as soon as the previous one is published
+    AsFastAsPossible,
+}
+
+impl From<Pacing> for ReplayPacing {
+    fn from(pacing: Pacing) -> Self {
+        match pacing {
+            Pacing::RealTime => ReplayPacing::RealTime,
+            Pacing::Accelerated => ReplayPacing::Accelerated,
+            Pacing::AsFastAsPossible => ReplayPacing::AsFastAsPossible,
+        }
+    }
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum Format {
+    /// One human-readable line per event
+    Pretty,
+    /// One JSON object per event, for piping into other tools
+    Ndjson,
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    match run().await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            match e.downcast_ref::<Status>() {
+                Some(status) => eprintln!("Error: {} ({:?})", status.message(), status.code()),
+                None => eprintln!("Error: {:#}", e),
+            }
+            ExitCode::FAILURE
+        }
+    }
+}
+
+async fn run() -> Result<()> {
+    tracing_subscriber::fmt()
+        .with_env_filter(
+            tracing_subscriber::EnvFilter::try_from_default_env().unwrap_or_else(|_| "warn".into()),
+        )
+        .with_writer(io::stderr)
+        .init();
+
+    let cli = Cli::parse();
+    let options = ClientOptions {
+        endpoint: cli.endpoint,
+        token: cli.token,
+        instrument_id: cli.instrument_id,
+        ca_cert: cli.ca_cert,
+        client_cert: cli.client_cert,
+        client_key: cli.client_key,
+        connect_timeout: Duration::from_secs_f64(cli.connect_timeout),
+    };
+    let client = SimulatorClient::connect(&options).await?;
+
+    match cli.command {
+        Command::Status(output) => status(&client, output.json).await,
+        Command::Info(output) => info(&client, output.json).await,
+        Command::Start(args) => start(&client, args).await,
+        Command::Stop(args) => stop(&client, args).await,
+        Command::Stream(args) => stream(&client, args).await,
+        Command::Capture(args) => capture(&client, args).await,
+    }
+}
+
+async fn status(client: &SimulatorClient, json: bool) -> Result<()> {
+    let status = client.get_status().await?;
+    if json {
+        return print_json(&status);
+    }
+
+    println!(
+        "State:            {}",
+        short_name(status.state().as_str_name())
+    );
+    println!(
+        "Instrument state: {}",
+        short_name(status.instrument_state().as_str_name())
+    );
+    if !status.session_id.is_empty() {
+        println!("Session:          {}", status.session_id);
+    }
+    println!("Scans:            {}", status.scan_count);
+    println!("Retention time:   {:.3} min", status.current_retention_time);
+    if !status.error_message.is_empty() {
+        println!("Error:            {}", status.error_message);
+    }
+    Ok(())
+}
+
+async fn info(client: &SimulatorClient, json: bool) -> Result<()> {
+    let info = client.get_instrument_info().await?;
+    if json {
+        return print_json(&info);
+    }
+
+    let fragmentation: Vec<_> = info
+        .supported_fragmentation_types()
+        .map(|kind| short_name(kind.as_str_name()))
+        .collect();
+    let resolutions: Vec<_> = info
+        .supported_resolutions
+        .iter()
+        .map(|resolution| resolution.to_string())
+        .collect();
+
+    println!("Name:             {}", info.instrument_name);
+    println!("ID:               {}", info.instrument_id);
+    println!("Model:            {}", info.model);
+    println!("Profile:          {}", info.profile);
+    println!("Serial number:    {}", info.serial_number);
+    println!("Firmware:         {}", info.firmware_version);
+    println!("Simulator:        {}", info.simulator_version);
+    println!("m/z range:        {} - {}", info.min_mz, info.max_mz);
+    println!("Max resolution:   {}", info.max_resolution);
+    println!("Resolutions:      {}", resolutions.join(", "));
+    println!("Analyzers:        {}", info.supported_analyzers.join(", "));
+    println!("Fragmentation:    {}", fragmentation.join(", "));
+    println!("Max scan rate:    {} scans/s", info.max_scan_rate);
+    Ok(())
+}
+
+async fn start(client: &SimulatorClient, args: StartArgs) -> Result<()> {
+    let request = StartAcquisitionRequest {
+        max_scans: args.max_scans,
+        max_duration_seconds: args.max_duration,
+        // Zero keeps the simulator's setting
+        simulation: Some(SimulationParameters {
+            scan_rate: args.scan_rate.unwrap_or(0.0),
+            ms2_per_ms1: args.ms2_per_ms1.unwrap_or(0),
+            random_seed: args.seed.unwrap_or(0),
+            ..Default::default()
+        }),
+        wait_for_trigger: args.wait_for_trigger,
+        trigger_timeout_seconds: args.trigger_timeout,
+        recording: args.record.then(|| RecordingOptions {
+            name: args.recording_name.unwrap_or_default(),
+            format: RecordingFormat::from(args.recording_format) as i32,
+            // Unset keeps the simulator's setting
+            zlib_compression: args.no_compression.then_some(false),
+            zstd_compression: args.no_compression.then_some(false),
+        }),
+        replay: args.replay.map(|file| {
+            let pacing = args.pacing.unwrap_or(match args.speed {
+                Some(_) => Pacing::Accelerated,
+                None => Pacing::RealTime,
+            });
+            ReplayOptions {
+                file,
+                pacing: ReplayPacing::from(pacing) as i32,
+                speed: args.speed.unwrap_or(0.0),
+            }
+        }),
+        ..Default::default()
+    };
+    let response = client.start_acquisition(request).await?;
+    if args.output.json {
+        print_json(&response)?;
+    } else if response.success {
+        println!("{}", response.session_id);
+        if !response.recording_path.is_empty() {
+            eprintln!("Recording to {}", response.recording_path);
+        }
+    }
+    if !response.success {
+        bail!("Acquisition not started: {}", response.error_message);
+    }
+    Ok(())
+}
+
+async fn stop(client: &SimulatorClient, args: StopArgs) -> Result<()> {
+    let response = client.stop_acquisition(&args.session_id).await?;
+    if args.output.json {
+        print_json(&response)?;
+    } else if response.success {
+        println!("Stopped after {} scans", response.final_scan_count);
+    }
+    if !response.success {
+        bail!("Acquisition not stopped: {}", response.error_message);
+    }
+    Ok(())
+}
+
+async fn stream(client: &SimulatorClient, args: StreamArgs) -> Result<()> {
+    let request = StreamScansRequest {
+        filter: args.ms_order.map(|ms_order| ScanFilter {
+            ms_order,
+            ..Default::default()
+        }),
+        ..Default::default()
+    };
+    let policy = if args.no_reconnect {
+        ReconnectPolicy::never()
+    } else {
+        ReconnectPolicy::default()
+    };
+    let mut events = client.resume_events(request, policy);
+
+    let mut out = io::stdout().lock();
+    let mut scans = 0;
+    while let Some(event) = events.next().await {
+        let event = event?;
+        let written = match args.format {
+            Format::Pretty => writeln!(out, "{}", describe(&event)),
+            Format::Ndjson => serde_json::to_writer(&mut out, &event)
+                .map_err(io::Error::from)
+                .and_then(|()| writeln!(out)),
+        };
+        // A closed pipe, e.g. from `| head`, just ends the output
+        match written {
+            Err(e) if e.kind() == io::ErrorKind::BrokenPipe => return Ok(()),
+            written => written?,
+        }
+
+        match &event.event {
+            Some(Event::Scan(_)) => scans += 1,
+            Some(Event::SessionClosed(_)) if args.until_closed => break,
+            _ => {}
+        }
+        if args.count.is_some_and(|count| scans >= count) {
+            break;
+        }
+    }
+    Ok(())
+}
+
+async fn capture(client: &SimulatorClient, args: CaptureArgs) -> Result<()> {
+    let instrument_id = client.get_instrument_info().await?.instrument_id;
+    let compression = if args.no_compression {
+        StreamLogCompression::None
+    } else {
+        StreamLogCompression::Zstd
+    };
+    let header = StreamLogHeader {
+        instrument_id,
+        start_timestamp_ms: std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_millis() as i64),
+        compression: compression as i32,
+        writer: format!("lc-ms-client {}", env!("CARGO_PKG_VERSION")),
+        ..Default::default()
+    };
+    let mut log = StreamLogWriter::create(&args.file, header)
+        .with_context(|| format!("Cannot create {}", args.file.display()))?;
+
+    let request = StreamScansRequest {
+        filter: args.ms_order.map(|ms_order| ScanFilter {
+            ms_order,
+            ..Default::default()
+        }),
+        ..Default::default()
+    };
+    let policy = if args.no_reconnect {
+        ReconnectPolicy::never()
+    } else {
+        ReconnectPolicy::default()
+    };
+    let mut events = client.resume_events(request, policy);
+
+    // Interrupting the capture still finishes the log
+    let interrupted = tokio::signal::ctrl_c();
+    tokio::pin!(interrupted);
+    let mut origin = None;
+    let mut scans = 0;
+    let ended = loop {
+        let event = tokio::select! {
+            event = events.next() => event,
+            _ = &mut interrupted => break Ok(()),
+        };
+        let event = match event {
+            Some(Ok(event)) => event,
+            Some(Err(e)) => break Err(e),
+            None => break Ok(()),
+        };
+        let elapsed = origin.get_or_insert_with(Instant::now).elapsed();
+        log.write(elapsed, &event)
+            .with_context(|| format!("Cannot write {}", args.file.display()))?;
+
+        match &event.event {
+            Some(Event::Scan(_)) => scans += 1,
+            Some(Event::SessionClosed(_)) if args.until_closed => break Ok(()),
+            _ => {}
+        }
+        if args.count.is_some_and(|count| scans >= count) {
+            break Ok(());
+        }
+    };
+
+    let events = log.record_count();
+    log.finish()
+        .with_context(|| format!("Cannot write {}", args.file.display()))?;
+    eprintln!(
+        "Captured {} events ({} scans) to {}",
+        events,
+        scans,
+        args.file.display()
+    );
+    Ok(ended?)
+}
+
+/// One line describing a stream event
+fn describe(event: &StreamEvent) -> String {
+    match &event.event {
+        Some(Event::Scan(scan)) => {
+            let mut line = format!(
+                "#{:<6} MS{}  RT {:>8.3} min  {:>5} peaks  TIC {:.3e}  base peak {:.4}",
+                scan.scan_number,
+                scan.ms_order,
+                scan.retention_time,
+                scan.mz_values.len(),
+                scan.total_ion_current,
+                scan.base_peak_mz,
+            );
+            if let Some(mz) = scan.precursor_mass {
+                line.push_str(&format!("  precursor {:.4}", mz));
+                if let Some(charge) = scan.precursor_charge {
+                    line.push_str(&format!(" {}+", charge));
+                }
+            }
+            line
+        }
+        Some(Event::SessionOpened(opened)) => {
+            let mut line = format!("Session {} opened", event.session_id);
+            if let Some(simulation) = &opened.simulation {
+                line.push_str(&format!(
+                    ": {} scans/s, {} MS2 per MS1",
+                    simulation.scan_rate, simulation.ms2_per_ms1
+                ));
+            }
+            if let Some(replay) = &opened.replay {
+                line.push_str(&format!(": replaying {}", replay.file));
+            }
+            line
+        }
+        Some(Event::SessionClosed(closed)) => format!(
+            "Session {} closed: {} after {} scans",
+            event.session_id,
+            short_name(closed.final_state().as_str_name()),
+            closed.final_scan_count
+        ),
+        Some(Event::GapDetected(gap)) => format!(
+            "Gap: {} events missed after scan {}",
+            gap.missed_events, gap.last_scan_number
+        ),
+        Some(Event::ParameterChanged(change)) => format!(
+            "Parameter {} changed: {} -> {}",
+            change.name, change.old_value, change.new_value
+        ),
+        None => "Unknown event".to_string(),
+    }
+}
+
+/// A proto enum value name without its type prefix, e.g. `READY` for
+/// `INSTRUMENT_STATE_READY`
+fn short_name(name: &str) -> &str {
+    ["ACQUISITION_STATE_", "INSTRUMENT_STATE_", "FRAGMENTATION_"]
+        .iter()
+        .find_map(|prefix| name.strip_prefix(prefix))
+        .unwrap_or(name)
+}
+
+fn print_json(message: &impl Serialize) -> Result<()> {
+    println!("{}", serde_json::to_string_pretty(message)?);
+    Ok(())
+}
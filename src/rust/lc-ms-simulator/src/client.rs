@@ -0,0 +1,567 @@
+//! Client for the simulator's gRPC API.
+//!
+//! [`SimulatorClient`] wraps every RPC in a typed call and sends the auth token and the
+//! addressed instrument as metadata. The `resume_*` streams reconnect when the connection
+//! drops or the server ends the stream, with exponential backoff; a reconnected event
+//! stream reports the scans it missed in between as a `GapDetected` event, just as the
+//! server does for a lagging subscriber.
+
+use std::future::Future;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::codec::{CompressionEncoding, Streaming};
+use tonic::metadata::{Ascii, MetadataValue};
+use tonic::service::interceptor::InterceptedService;
+use tonic::service::Interceptor;
+use tonic::transport::{Certificate, Channel, ClientTlsConfig, Endpoint, Identity};
+use tonic::{Code, Request, Status};
+use tracing::warn;
+
+use crate::proto::simulator_service_client::SimulatorServiceClient;
+use crate::proto::stream_event::Event;
+use crate::proto::*;
+
+/// Address of a simulator with default settings
+pub const DEFAULT_ENDPOINT: &str = "http://localhost:31417";
+
+/// Metadata key naming the addressed instrument, as read by the server
+const INSTRUMENT_ID_METADATA: &str = "instrument-id";
+
+/// Largest response message accepted; scan batches can be several megabytes
+const MAX_MESSAGE_BYTES: usize = 64 * 1024 * 1024;
+
+/// Items buffered between a reconnecting stream's task and its consumer
+const RESUME_BUFFER: usize = 256;
+
+/// How to reach a simulator
+#[derive(Debug, Clone)]
+pub struct ClientOptions {
+    /// e.g. `http://localhost:31417`; `https://` connects over TLS
+    pub endpoint: String,
+    /// Sent as `authorization: Bearer <token>` when the server requires auth
+    pub token: Option<String>,
+    /// Instrument every call addresses; the server's default instrument when unset
+    pub instrument_id: Option<String>,
+    /// PEM CA bundle that signed the server certificate; required for `https://`
+    pub ca_cert: Option<PathBuf>,
+    /// PEM client certificate and key for mutual TLS
+    pub client_cert: Option<PathBuf>,
+    pub client_key: Option<PathBuf>,
+    pub connect_timeout: Duration,
+}
+
+impl Default for ClientOptions {
+    fn default() -> Self {
+        Self {
+            endpoint: DEFAULT_ENDPOINT.to_string(),
+            token: None,
+            instrument_id: None,
+            ca_cert: None,
+            client_cert: None,
+            client_key: None,
+            connect_timeout: Duration::from_secs(5),
+        }
+    }
+}
+
+/// When and how fast the `resume_*` streams reconnect
+#[derive(Debug, Clone)]
+pub struct ReconnectPolicy {
+    /// Consecutive failed attempts before the stream gives up; `None` retries forever.
+    /// Zero disables reconnecting.
+    pub max_attempts: Option<u32>,
+    /// Delay before the first attempt, doubled after every further failure
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl ReconnectPolicy {
+    /// Never reconnect; the stream ends with the first error
+    pub fn never() -> Self {
+        Self {
+            max_attempts: Some(0),
+            ..Self::default()
+        }
+    }
+
+    fn backoff(&self, attempt: u32) -> Duration {
+        let factor = 2u32.saturating_pow(attempt.saturating_sub(1));
+        self.initial_backoff
+            .saturating_mul(factor)
+            .min(self.max_backoff)
+    }
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: Some(10),
+            initial_backoff: Duration::from_millis(250),
+            max_backoff: Duration::from_secs(10),
+        }
+    }
+}
+
+/// A stream that survives reconnects; once the policy gives up it ends, with the last
+/// error if there was one
+pub type ResumingStream<T> = ReceiverStream<Result<T, Status>>;
+
+/// Adds the auth token and instrument ID to every call
+#[derive(Clone, Default)]
+struct CallMetadata {
+    authorization: Option<MetadataValue<Ascii>>,
+    instrument_id: Option<MetadataValue<Ascii>>,
+}
+
+impl Interceptor for CallMetadata {
+    fn call(&mut self, mut request: Request<()>) -> Result<Request<()>, Status> {
+        if let Some(authorization) = &self.authorization {
+            request
+                .metadata_mut()
+                .insert("authorization", authorization.clone());
+        }
+        if let Some(instrument_id) = &self.instrument_id {
+            request
+                .metadata_mut()
+                .insert(INSTRUMENT_ID_METADATA, instrument_id.clone());
+        }
+        Ok(request)
+    }
+}
+
+type Inner = SimulatorServiceClient<InterceptedService<Channel, CallMetadata>>;
+
+/// Typed client for one instrument of a simulator. Cloning is cheap and shares the
+/// connection.
+#[derive(Clone)]
+pub struct SimulatorClient {
+    channel: Channel,
+    metadata: CallMetadata,
+    inner: Inner,
+}
+
+impl SimulatorClient {
+    /// Connects to a simulator
+    pub async fn connect(options: &ClientOptions) -> Result<Self> {
+        let mut endpoint = Endpoint::from_shared(options.endpoint.clone())
+            .with_context(|| format!("Invalid endpoint '{}'", options.endpoint))?
+            .connect_timeout(options.connect_timeout);
+
+        if options.endpoint.starts_with("https://") {
+            endpoint = endpoint
+                .tls_config(tls_config(options)?)
+                .context("Invalid TLS settings")?;
+        } else if options.ca_cert.is_some() || options.client_cert.is_some() {
+            bail!("TLS certificates need an https:// endpoint");
+        }
+
+        let channel = endpoint
+            .connect()
+            .await
+            .with_context(|| format!("Cannot connect to {}", options.endpoint))?;
+
+        let metadata = CallMetadata {
+            authorization: options
+                .token
+                .as_deref()
+                .map(|token| metadata_value(&format!("Bearer {}", token), "token"))
+                .transpose()?,
+            instrument_id: options
+                .instrument_id
+                .as_deref()
+                .map(|id| metadata_value(id, "instrument ID"))
+                .transpose()?,
+        };
+        Ok(Self::new(channel, metadata))
+    }
+
+    fn new(channel: Channel, metadata: CallMetadata) -> Self {
+        let inner = SimulatorServiceClient::with_interceptor(channel.clone(), metadata.clone())
+            .accept_compressed(CompressionEncoding::Zstd)
+            .accept_compressed(CompressionEncoding::Gzip)
+            .max_decoding_message_size(MAX_MESSAGE_BYTES);
+        Self {
+            channel,
+            metadata,
+            inner,
+        }
+    }
+
+    /// The same connection addressing another instrument
+    pub fn for_instrument(&self, instrument_id: &str) -> Result<Self> {
+        let metadata = CallMetadata {
+            instrument_id: Some(metadata_value(instrument_id, "instrument ID")?),
+            ..self.metadata.clone()
+        };
+        Ok(Self::new(self.channel.clone(), metadata))
+    }
+
+    pub async fn get_status(&self) -> Result<StatusResponse, Status> {
+        let response = self.inner.clone().get_status(GetStatusRequest {}).await?;
+        Ok(response.into_inner())
+    }
+
+    pub async fn get_instrument_info(&self) -> Result<InstrumentInfoResponse, Status> {
+        let response = self
+            .inner
+            .clone()
+            .get_instrument_info(GetInstrumentInfoRequest {})
+            .await?;
+        Ok(response.into_inner())
+    }
+
+    /// Every instrument the server hosts, regardless of the addressed one
+    pub async fn list_instruments(&self) -> Result<Vec<InstrumentSummary>, Status> {
+        let response = self
+            .inner
+            .clone()
+            .list_instruments(ListInstrumentsRequest {})
+            .await?;
+        Ok(response.into_inner().instruments)
+    }
+
+    pub async fn start_acquisition(
+        &self,
+        request: StartAcquisitionRequest,
+    ) -> Result<StartAcquisitionResponse, Status> {
+        let response = self.inner.clone().start_acquisition(request).await?;
+        Ok(response.into_inner())
+    }
+
+    pub async fn stop_acquisition(
+        &self,
+        session_id: &str,
+    ) -> Result<StopAcquisitionResponse, Status> {
+        let request = StopAcquisitionRequest {
+            session_id: session_id.to_string(),
+        };
+        let response = self.inner.clone().stop_acquisition(request).await?;
+        Ok(response.into_inner())
+    }
+
+    pub async fn pause_acquisition(
+        &self,
+        session_id: &str,
+    ) -> Result<PauseAcquisitionResponse, Status> {
+        let request = PauseAcquisitionRequest {
+            session_id: session_id.to_string(),
+        };
+        let response = self.inner.clone().pause_acquisition(request).await?;
+        Ok(response.into_inner())
+    }
+
+    pub async fn resume_acquisition(
+        &self,
+        session_id: &str,
+    ) -> Result<ResumeAcquisitionResponse, Status> {
+        let request = ResumeAcquisitionRequest {
+            session_id: session_id.to_string(),
+        };
+        let response = self.inner.clone().resume_acquisition(request).await?;
+        Ok(response.into_inner())
+    }
+
+    /// Starts a session that is waiting for its trigger
+    pub async fn trigger_start(&self, session_id: &str) -> Result<TriggerStartResponse, Status> {
+        let request = TriggerStartRequest {
+            session_id: session_id.to_string(),
+        };
+        let response = self.inner.clone().trigger_start(request).await?;
+        Ok(response.into_inner())
+    }
+
+    /// Readings of the named values; all values when `names` is empty
+    pub async fn get_instrument_values(
+        &self,
+        names: &[&str],
+    ) -> Result<Vec<InstrumentValue>, Status> {
+        let request = GetInstrumentValuesRequest {
+            names: names.iter().map(|name| name.to_string()).collect(),
+        };
+        let response = self.inner.clone().get_instrument_values(request).await?;
+        Ok(response.into_inner().values)
+    }
+
+    pub async fn set_instrument_values(
+        &self,
+        setpoints: impl IntoIterator<Item = (String, f64)>,
+    ) -> Result<Vec<InstrumentValue>, Status> {
+        let request = SetInstrumentValuesRequest {
+            setpoints: setpoints.into_iter().collect(),
+        };
+        let response = self.inner.clone().set_instrument_values(request).await?;
+        Ok(response.into_inner().values)
+    }
+
+    pub async fn set_instrument_mode(
+        &self,
+        mode: InstrumentMode,
+    ) -> Result<InstrumentModeResponse, Status> {
+        let request = SetInstrumentModeRequest { mode: mode.into() };
+        let response = self.inner.clone().set_instrument_mode(request).await?;
+        Ok(response.into_inner())
+    }
+
+    pub async fn get_instrument_mode(&self) -> Result<InstrumentModeResponse, Status> {
+        let response = self
+            .inner
+            .clone()
+            .get_instrument_mode(GetInstrumentModeRequest {})
+            .await?;
+        Ok(response.into_inner())
+    }
+
+    /// Replaces the fault plan; `None` clears it
+    pub async fn set_fault_plan(
+        &self,
+        plan: Option<FaultPlan>,
+    ) -> Result<Option<FaultPlan>, Status> {
+        let response = self
+            .inner
+            .clone()
+            .set_fault_plan(SetFaultPlanRequest { plan })
+            .await?;
+        Ok(response.into_inner().plan)
+    }
+
+    pub async fn get_fault_plan(&self) -> Result<Option<FaultPlan>, Status> {
+        let response = self
+            .inner
+            .clone()
+            .get_fault_plan(GetFaultPlanRequest {})
+            .await?;
+        Ok(response.into_inner().plan)
+    }
+
+    pub async fn stream_scans(
+        &self,
+        request: StreamScansRequest,
+    ) -> Result<Streaming<ScanMessage>, Status> {
+        let response = self.inner.clone().stream_scans(request).await?;
+        Ok(response.into_inner())
+    }
+
+    pub async fn stream_events(
+        &self,
+        request: StreamScansRequest,
+    ) -> Result<Streaming<StreamEvent>, Status> {
+        let response = self.inner.clone().stream_events(request).await?;
+        Ok(response.into_inner())
+    }
+
+    pub async fn stream_scan_batches(
+        &self,
+        request: StreamScanBatchesRequest,
+    ) -> Result<Streaming<ScanBatch>, Status> {
+        let response = self.inner.clone().stream_scan_batches(request).await?;
+        Ok(response.into_inner())
+    }
+
+    /// Status log records every `interval_seconds`; zero uses the server default
+    pub async fn stream_status_log(
+        &self,
+        interval_seconds: f64,
+    ) -> Result<Streaming<StatusLogRecord>, Status> {
+        let request = StreamStatusLogRequest { interval_seconds };
+        let response = self.inner.clone().stream_status_log(request).await?;
+        Ok(response.into_inner())
+    }
+
+    /// [`stream_scans`](Self::stream_scans), reconnecting per `policy`
+    pub fn resume_scans(
+        &self,
+        request: StreamScansRequest,
+        policy: ReconnectPolicy,
+    ) -> ResumingStream<ScanMessage> {
+        let client = self.clone();
+        resuming(
+            policy,
+            move || {
+                let (client, request) = (client.clone(), request);
+                async move { client.stream_scans(request).await }
+            },
+            |_, _| None,
+        )
+    }
+
+    /// [`stream_events`](Self::stream_events), reconnecting per `policy`. Scans of the
+    /// same session missed while reconnecting are reported by a `GapDetected` event
+    /// before the first scan after the reconnect; its `missed_events` counts scans.
+    pub fn resume_events(
+        &self,
+        request: StreamScansRequest,
+        policy: ReconnectPolicy,
+    ) -> ResumingStream<StreamEvent> {
+        let client = self.clone();
+        let mut gaps = GapTracker::default();
+        resuming(
+            policy,
+            move || {
+                let (client, request) = (client.clone(), request);
+                async move { client.stream_events(request).await }
+            },
+            move |event, reconnected| gaps.observe(event, reconnected),
+        )
+    }
+
+    /// [`stream_status_log`](Self::stream_status_log), reconnecting per `policy`
+    pub fn resume_status_log(
+        &self,
+        interval_seconds: f64,
+        policy: ReconnectPolicy,
+    ) -> ResumingStream<StatusLogRecord> {
+        let client = self.clone();
+        resuming(
+            policy,
+            move || {
+                let client = client.clone();
+                async move { client.stream_status_log(interval_seconds).await }
+            },
+            |_, _| None,
+        )
+    }
+}
+
+fn metadata_value(value: &str, what: &str) -> Result<MetadataValue<Ascii>> {
+    value
+        .parse()
+        .map_err(|_| anyhow::anyhow!("The {} is not valid in request metadata", what))
+}
+
+fn tls_config(options: &ClientOptions) -> Result<ClientTlsConfig> {
+    let mut tls = ClientTlsConfig::new();
+    if let Some(path) = &options.ca_cert {
+        let pem = std::fs::read(path)
+            .with_context(|| format!("Cannot read CA certificate {}", path.display()))?;
+        tls = tls.ca_certificate(Certificate::from_pem(pem));
+    }
+    match (&options.client_cert, &options.client_key) {
+        (Some(cert), Some(key)) => {
+            let cert_pem = std::fs::read(cert)
+                .with_context(|| format!("Cannot read client certificate {}", cert.display()))?;
+            let key_pem = std::fs::read(key)
+                .with_context(|| format!("Cannot read client key {}", key.display()))?;
+            tls = tls.identity(Identity::from_pem(cert_pem, key_pem));
+        }
+        (None, None) => {}
+        _ => bail!("A client certificate needs both the certificate and the key"),
+    }
+    Ok(tls)
+}
+
+/// Whether a stream that failed with `status` is worth reopening
+fn is_transient(status: &Status) -> bool {
+    matches!(
+        status.code(),
+        Code::Unavailable | Code::Unknown | Code::Internal | Code::Aborted | Code::Cancelled
+    )
+}
+
+/// Runs a stream on a task that reopens it per `policy`. `inject` sees every item, and
+/// whether it is the first since a reconnect, and may emit an item ahead of it.
+fn resuming<T, Open, Opening, Inject>(
+    policy: ReconnectPolicy,
+    mut open: Open,
+    mut inject: Inject,
+) -> ResumingStream<T>
+where
+    T: Send + 'static,
+    Open: FnMut() -> Opening + Send + 'static,
+    Opening: Future<Output = Result<Streaming<T>, Status>> + Send,
+    Inject: FnMut(&T, bool) -> Option<T> + Send + 'static,
+{
+    let (sender, receiver) = mpsc::channel(RESUME_BUFFER);
+    tokio::spawn(async move {
+        let mut failures = 0;
+        let mut reconnected = false;
+        loop {
+            let error = match open().await {
+                Ok(mut stream) => loop {
+                    match stream.message().await {
+                        Ok(Some(item)) => {
+                            failures = 0;
+                            let first = std::mem::take(&mut reconnected);
+                            if let Some(extra) = inject(&item, first) {
+                                if sender.send(Ok(extra)).await.is_err() {
+                                    return;
+                                }
+                            }
+                            if sender.send(Ok(item)).await.is_err() {
+                                return;
+                            }
+                        }
+                        Ok(None) => break None,
+                        Err(status) => break Some(status),
+                    }
+                },
+                Err(status) => Some(status),
+            };
+
+            // A stream the server ended cleanly just ends once the policy gives up
+            failures += 1;
+            let exhausted = policy.max_attempts.is_some_and(|max| failures > max);
+            if exhausted || error.as_ref().is_some_and(|status| !is_transient(status)) {
+                if let Some(status) = error {
+                    let _ = sender.send(Err(status)).await;
+                }
+                return;
+            }
+
+            let backoff = policy.backoff(failures);
+            let reason = error.map_or("ended by the server".to_string(), |status| {
+                status.message().to_string()
+            });
+            warn!(
+                "Stream interrupted ({}); reconnecting in {:?}",
+                reason, backoff
+            );
+            tokio::select! {
+                _ = tokio::time::sleep(backoff) => {}
+                _ = sender.closed() => return,
+            }
+            reconnected = true;
+        }
+    });
+    ReceiverStream::new(receiver)
+}
+
+/// Notices scans an event stream missed across a reconnect
+#[derive(Default)]
+struct GapTracker {
+    /// Session and number of the last scan seen
+    last_scan: Option<(String, i32)>,
+    /// A reconnect happened and no scan has arrived since
+    reconnected: bool,
+}
+
+impl GapTracker {
+    fn observe(&mut self, event: &StreamEvent, reconnected: bool) -> Option<StreamEvent> {
+        self.reconnected |= reconnected;
+        let Some(Event::Scan(scan)) = &event.event else {
+            return None;
+        };
+        let previous = self
+            .last_scan
+            .replace((scan.session_id.clone(), scan.scan_number));
+        if !std::mem::take(&mut self.reconnected) {
+            return None;
+        }
+
+        let (session_id, last) = previous?;
+        if session_id != scan.session_id || scan.scan_number <= last + 1 {
+            return None;
+        }
+        Some(StreamEvent {
+            session_id,
+            timestamp_ms: event.timestamp_ms,
+            event: Some(Event::GapDetected(GapDetected {
+                missed_events: i64::from(scan.scan_number - last - 1),
+                last_scan_number: last,
+            })),
+        })
+    }
+}
@@ -0,0 +1,560 @@
+//! Layered simulator configuration.
+//!
+//! Settings are resolved from, in increasing priority: built-in defaults, an optional
+//! TOML/YAML/JSON file, `LCMS_*` environment variables, and command line flags.
+//! Environment variables use `__` between sections, e.g. `LCMS_SERVER__PORT=31417` or
+//! `LCMS_SIMULATION__SCAN_RATE=50`. Unknown keys in any source are rejected.
+
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, bail, Context, Result};
+use clap::ValueEnum;
+use serde::de::{DeserializeOwned, IntoDeserializer};
+use serde::{Deserialize, Deserializer, Serialize};
+use tonic::codec::CompressionEncoding;
+
+use crate::faults::FaultPlan;
+use crate::power::PowerMode;
+use crate::profiles::InstrumentModel;
+use crate::proto::SimulationParameters;
+use crate::status_log::MIN_INTERVAL_SECONDS;
+use crate::telemetry::LogFormat;
+
+/// Prefix of environment variables read as configuration
+pub const ENV_PREFIX: &str = "LCMS";
+
+/// Environment variable naming the config file; not itself a setting
+pub const CONFIG_PATH_ENV: &str = "LCMS_CONFIG";
+
+/// Longest pump-down or warm-up a config may set (one day)
+const MAX_POWER_TRANSITION_SECONDS: f64 = 86_400.0;
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Settings {
+    pub server: ServerConfig,
+    pub logging: LoggingConfig,
+    pub instrument: InstrumentConfig,
+    /// Instruments hosted side by side; replaces `instrument` when non-empty
+    pub instruments: Vec<InstrumentConfig>,
+    pub power: PowerConfig,
+    pub simulation: SimulationConfig,
+    pub method: MethodConfig,
+    pub status_log: StatusLogConfig,
+    pub recording: RecordingConfig,
+    pub replay: ReplayConfig,
+    pub faults: FaultPlan,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub host: String,
+    pub port: u16,
+    /// Port for the Prometheus /metrics endpoint (0 disables it)
+    pub metrics_port: u16,
+    pub shutdown_grace_seconds: f64,
+    /// Response compression offered to clients that advertise it
+    #[serde(deserialize_with = "comma_separated")]
+    pub compression: Vec<Compression>,
+    pub tls: TlsConfig,
+    pub auth: AuthConfig,
+    pub web: WebConfig,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            host: "0.0.0.0".to_string(),
+            port: 31417,
+            metrics_port: 9100,
+            shutdown_grace_seconds: 5.0,
+            compression: vec![Compression::Zstd],
+            tls: TlsConfig::default(),
+            auth: AuthConfig::default(),
+            web: WebConfig::default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TlsConfig {
+    pub cert: Option<PathBuf>,
+    pub key: Option<PathBuf>,
+    pub client_ca: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    /// Accepted tokens as `<role>:<token>`
+    #[serde(deserialize_with = "comma_separated")]
+    pub tokens: Vec<String>,
+    pub token_file: Option<PathBuf>,
+}
+
+/// Access for browser clients
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WebConfig {
+    /// Accept gRPC-Web (and HTTP/1.1) on the gRPC port
+    pub grpc_web: bool,
+    /// Port for the REST/JSON gateway (0 disables it)
+    pub rest_port: u16,
+    /// Origins browsers may call from, for gRPC-Web and REST; `*` allows any
+    #[serde(deserialize_with = "comma_separated")]
+    pub allowed_origins: Vec<String>,
+}
+
+impl Default for WebConfig {
+    fn default() -> Self {
+        Self {
+            grpc_web: false,
+            rest_port: 0,
+            allowed_origins: vec!["*".to_string()],
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LoggingConfig {
+    /// Level or `RUST_LOG`-style filter directives
+    pub level: String,
+    pub format: LogFormat,
+    pub otlp_endpoint: Option<String>,
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        Self {
+            level: "info".to_string(),
+            format: LogFormat::Text,
+            otlp_endpoint: None,
+        }
+    }
+}
+
+/// Identity reported by `GetInstrumentInfo`; capabilities come from the profile
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct InstrumentConfig {
+    pub profile: InstrumentModel,
+    /// Defaults to "Simulated <model>"
+    pub name: Option<String>,
+    pub id: String,
+    /// Defaults to the instrument ID
+    pub serial_number: Option<String>,
+    /// Defaults to the profile's firmware version
+    pub firmware_version: Option<String>,
+    /// Hosts this many identical instruments with consecutively numbered IDs
+    pub count: usize,
+}
+
+impl Default for InstrumentConfig {
+    fn default() -> Self {
+        Self {
+            profile: InstrumentModel::default(),
+            name: None,
+            id: "SIM-001".to_string(),
+            serial_number: None,
+            firmware_version: None,
+            count: 1,
+        }
+    }
+}
+
+impl InstrumentConfig {
+    pub fn name(&self) -> String {
+        self.name
+            .clone()
+            .unwrap_or_else(|| format!("Simulated {}", self.profile.profile().model))
+    }
+
+    pub fn serial_number(&self) -> String {
+        self.serial_number
+            .clone()
+            .unwrap_or_else(|| self.id.clone())
+    }
+
+    /// The `count` instruments this entry stands for, each with a count of one
+    fn expand(&self) -> Vec<InstrumentConfig> {
+        (0..self.count)
+            .map(|offset| InstrumentConfig {
+                id: numbered_id(&self.id, offset),
+                serial_number: self
+                    .serial_number
+                    .as_deref()
+                    .map(|serial| numbered_id(serial, offset)),
+                count: 1,
+                ..self.clone()
+            })
+            .collect()
+    }
+}
+
+/// Numbers the replicas of an instrument: `SIM-001` is followed by `SIM-002`, `SIM-003`
+/// and so on, and an ID without trailing digits by `<id>-2`, `<id>-3`
+fn numbered_id(id: &str, offset: usize) -> String {
+    if offset == 0 {
+        return id.to_string();
+    }
+    let prefix = id.trim_end_matches(|c: char| c.is_ascii_digit());
+    let digits = &id[prefix.len()..];
+    match digits.parse::<u64>() {
+        Ok(number) => format!(
+            "{}{:0width$}",
+            prefix,
+            number + offset as u64,
+            width = digits.len()
+        ),
+        Err(_) => format!("{}-{}", id, offset + 1),
+    }
+}
+
+/// Power mode at startup and how long the instrument takes to become Ready
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PowerConfig {
+    /// Mode at startup; an instrument that starts On is Ready at once
+    pub mode: PowerMode,
+    /// Time to reach vacuum after leaving Off
+    pub pump_down_seconds: f64,
+    /// Time from switching On (with vacuum) to Ready
+    pub warm_up_seconds: f64,
+}
+
+impl Default for PowerConfig {
+    fn default() -> Self {
+        Self {
+            mode: PowerMode::On,
+            pump_down_seconds: 60.0,
+            warm_up_seconds: 10.0,
+        }
+    }
+}
+
+/// Simulation parameters used when `StartAcquisition` leaves them unset or non-positive
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SimulationConfig {
+    /// Total scans per second (MS1 + MS2)
+    pub scan_rate: f64,
+    pub ms2_per_ms1: i32,
+    pub min_mz: f64,
+    pub max_mz: f64,
+    pub ms1_peak_count: Option<i32>,
+    pub ms2_peak_count: Option<i32>,
+}
+
+impl Default for SimulationConfig {
+    fn default() -> Self {
+        Self {
+            scan_rate: 2.0,
+            ms2_per_ms1: 4,
+            min_mz: 200.0,
+            max_mz: 2000.0,
+            ms1_peak_count: None,
+            ms2_peak_count: None,
+        }
+    }
+}
+
+impl SimulationConfig {
+    /// Fills the parameters a request left unset or non-positive with these defaults
+    pub fn resolve(&self, params: Option<SimulationParameters>) -> SimulationParameters {
+        let params = params.unwrap_or_default();
+        SimulationParameters {
+            scan_rate: if params.scan_rate > 0.0 { params.scan_rate } else { self.scan_rate },
+            ms2_per_ms1: if params.ms2_per_ms1 > 0 {
+                params.ms2_per_ms1
+            } else {
+                self.ms2_per_ms1
+            },
+            min_mz: if params.min_mz > 0.0 { params.min_mz } else { self.min_mz },
+            max_mz: if params.max_mz > 0.0 { params.max_mz } else { self.max_mz },
+            ms1_peak_count: params
+                .ms1_peak_count
+                .filter(|v| *v > 0)
+                .or(self.ms1_peak_count),
+            ms2_peak_count: params
+                .ms2_peak_count
+                .filter(|v| *v > 0)
+                .or(self.ms2_peak_count),
+            ..params
+        }
+    }
+}
+
+/// Acquisition limits used when `StartAcquisition` does not set them
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MethodConfig {
+    pub max_scans: Option<i32>,
+    pub max_duration_seconds: Option<f64>,
+    /// How long a session started with `wait_for_trigger` waits; unset waits indefinitely
+    pub trigger_timeout_seconds: Option<f64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StatusLogConfig {
+    /// Seconds between records when `StreamStatusLog` does not set an interval
+    pub interval_seconds: f64,
+}
+
+impl Default for StatusLogConfig {
+    fn default() -> Self {
+        Self {
+            interval_seconds: 1.0,
+        }
+    }
+}
+
+/// Session recordings requested by `StartAcquisition`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RecordingConfig {
+    /// Directory recordings are written to; created when first needed
+    pub directory: PathBuf,
+    /// zlib-compress mzML binary arrays unless a request says otherwise
+    pub zlib_compression: bool,
+    /// zstd-compress stream log blocks unless a request says otherwise
+    pub zstd_compression: bool,
+}
+
+impl Default for RecordingConfig {
+    fn default() -> Self {
+        Self {
+            directory: PathBuf::from("recordings"),
+            zlib_compression: true,
+            zstd_compression: true,
+        }
+    }
+}
+
+/// Runs that `StartAcquisition` can replay
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ReplayConfig {
+    /// Directory replayed files are read from; requests cannot name files outside it
+    pub directory: PathBuf,
+}
+
+impl Default for ReplayConfig {
+    fn default() -> Self {
+        Self {
+            directory: PathBuf::from("replays"),
+        }
+    }
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    Gzip,
+    Zstd,
+}
+
+impl From<Compression> for CompressionEncoding {
+    fn from(value: Compression) -> Self {
+        match value {
+            Compression::Gzip => CompressionEncoding::Gzip,
+            Compression::Zstd => CompressionEncoding::Zstd,
+        }
+    }
+}
+
+impl Settings {
+    /// Every hosted instrument in order, with `count` expanded; the first is the default
+    pub fn instruments(&self) -> Vec<InstrumentConfig> {
+        let entries = if self.instruments.is_empty() {
+            std::slice::from_ref(&self.instrument)
+        } else {
+            &self.instruments[..]
+        };
+        entries.iter().flat_map(InstrumentConfig::expand).collect()
+    }
+
+    /// Merges defaults, the optional config file and `LCMS_*` environment variables
+    pub fn load(path: Option<&Path>) -> Result<Self> {
+        let mut builder = config::Config::builder();
+        if let Some(path) = path {
+            builder = builder.add_source(config::File::from(path).required(true));
+        }
+
+        let env = std::env::vars()
+            .filter(|(key, _)| key != CONFIG_PATH_ENV)
+            .collect();
+        builder = builder.add_source(
+            config::Environment::with_prefix(ENV_PREFIX)
+                .prefix_separator("_")
+                .separator("__")
+                .source(Some(env)),
+        );
+
+        let config = builder.build().context("Failed to read configuration")?;
+        serde_path_to_error::deserialize(config).map_err(|e| {
+            anyhow!(
+                "Invalid setting `{}`: {} (check the config file and {}_* environment variables)",
+                e.path(),
+                e.inner(),
+                ENV_PREFIX
+            )
+        })
+    }
+
+    /// Checks constraints that span several keys or sources
+    pub fn validate(&self) -> Result<()> {
+        let tls = &self.server.tls;
+        if tls.cert.is_some() != tls.key.is_some() {
+            bail!("server.tls.cert and server.tls.key must be set together");
+        }
+        if tls.client_ca.is_some() && tls.cert.is_none() {
+            bail!("server.tls.client_ca requires server.tls.cert and server.tls.key");
+        }
+        let grace = self.server.shutdown_grace_seconds;
+        if !grace.is_finite() || grace < 0.0 {
+            bail!("server.shutdown_grace_seconds must be finite and not negative");
+        }
+
+        if self
+            .method
+            .trigger_timeout_seconds
+            .is_some_and(|s| !s.is_finite() || s <= 0.0)
+        {
+            bail!("method.trigger_timeout_seconds must be positive");
+        }
+        if self.method.max_scans.is_some_and(|max| max <= 0) {
+            bail!("method.max_scans must be positive");
+        }
+        if self
+            .method
+            .max_duration_seconds
+            .is_some_and(|s| !s.is_finite() || s <= 0.0)
+        {
+            bail!("method.max_duration_seconds must be positive and finite");
+        }
+
+        let power = &self.power;
+        for (name, seconds) in [
+            ("pump_down_seconds", power.pump_down_seconds),
+            ("warm_up_seconds", power.warm_up_seconds),
+        ] {
+            if !seconds.is_finite() || seconds < 0.0 {
+                bail!("power.{} must not be negative", name);
+            }
+            if seconds > MAX_POWER_TRANSITION_SECONDS {
+                bail!("power.{} must be at most {}", name, MAX_POWER_TRANSITION_SECONDS);
+            }
+        }
+
+        let sim = &self.simulation;
+        if sim.scan_rate.is_nan() || sim.scan_rate <= 0.0 {
+            bail!("simulation.scan_rate must be positive");
+        }
+        if sim.ms2_per_ms1 < 0 {
+            bail!("simulation.ms2_per_ms1 must not be negative");
+        }
+        if sim.min_mz.is_nan() || sim.min_mz <= 0.0 || sim.min_mz >= sim.max_mz {
+            bail!("simulation.min_mz must be positive and below simulation.max_mz");
+        }
+        let interval = self.status_log.interval_seconds;
+        if !interval.is_finite() || interval < MIN_INTERVAL_SECONDS {
+            bail!(
+                "status_log.interval_seconds must be finite and at least {}",
+                MIN_INTERVAL_SECONDS
+            );
+        }
+        if let Err(e) = self.faults.validate() {
+            bail!("faults.{}", e);
+        }
+        if sim.ms1_peak_count.is_some_and(|v| v <= 0) || sim.ms2_peak_count.is_some_and(|v| v <= 0) {
+            bail!("simulation.ms1_peak_count and simulation.ms2_peak_count must be positive");
+        }
+        self.validate_instruments()?;
+
+        Ok(())
+    }
+
+    fn validate_instruments(&self) -> Result<()> {
+        let entries: Vec<_> = if self.instruments.is_empty() {
+            vec![("instrument".to_string(), &self.instrument)]
+        } else {
+            self.instruments
+                .iter()
+                .enumerate()
+                .map(|(index, instrument)| (format!("instruments[{}]", index), instrument))
+                .collect()
+        };
+
+        let defaults = self.simulation.resolve(None);
+        for (key, instrument) in entries {
+            if instrument.count == 0 {
+                bail!("{}.count must be at least 1", key);
+            }
+            // The ID travels in request metadata, which only carries visible ASCII
+            if instrument.id.is_empty() || !instrument.id.chars().all(|c| c.is_ascii_graphic()) {
+                bail!("{}.id must be non-empty printable ASCII without spaces", key);
+            }
+            if let Err(e) = instrument.profile.profile().scan_settings(&defaults) {
+                bail!(
+                    "[simulation] defaults do not fit {}.profile {}: {}",
+                    key,
+                    instrument.profile.name(),
+                    e
+                );
+            }
+        }
+
+        let mut ids = HashSet::new();
+        for instrument in self.instruments() {
+            if !ids.insert(instrument.id.clone()) {
+                bail!("Instrument ID {} is used more than once", instrument.id);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Serialized settings for the startup log, with token secrets masked
+    pub fn to_redacted_json(&self) -> String {
+        let mut settings = self.clone();
+        for spec in &mut settings.server.auth.tokens {
+            if let Some((role, _)) = spec.split_once(':') {
+                *spec = format!("{}:***", role);
+            }
+        }
+        serde_json::to_string(&settings).unwrap_or_default()
+    }
+}
+
+/// Accepts either a list or a comma-separated string, as environment variables provide
+fn comma_separated<'de, D, T>(deserializer: D) -> Result<Vec<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: DeserializeOwned,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum ListOrString {
+        List(Vec<String>),
+        String(String),
+    }
+
+    let items = match ListOrString::deserialize(deserializer)? {
+        ListOrString::List(items) => items,
+        ListOrString::String(value) => value
+            .split(',')
+            .map(str::trim)
+            .filter(|item| !item.is_empty())
+            .map(str::to_string)
+            .collect(),
+    };
+    items
+        .into_iter()
+        .map(|item| T::deserialize(item.into_deserializer()))
+        .collect()
+}
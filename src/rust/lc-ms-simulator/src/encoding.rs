@@ -0,0 +1,356 @@
+use tonic::Status;
+
+use crate::proto::{EncodedSpectrum, IntensityEncoding, MzEncoding, ScanMessage, SpectrumEncoding};
+
+/// Default quantization step for delta-encoded m/z (0.01 ppm at m/z 1000)
+const DEFAULT_MZ_DELTA_UNIT: f64 = 1e-5;
+
+/// Re-encodes spectrum arrays for a subscriber that negotiated a compact [`SpectrumEncoding`].
+#[derive(Debug, Clone, Copy)]
+pub struct SpectrumEncoder {
+    mz: MzEncoding,
+    intensity: IntensityEncoding,
+    mz_delta_unit: f64,
+}
+
+impl SpectrumEncoder {
+    /// Validates the requested encoding. Returns `None` when the default packed doubles
+    /// were requested, so the scan can be forwarded untouched.
+    pub fn from_request(encoding: Option<SpectrumEncoding>) -> Result<Option<Self>, Status> {
+        let Some(encoding) = encoding else {
+            return Ok(None);
+        };
+
+        let mz = MzEncoding::try_from(encoding.mz).map_err(|_| {
+            Status::invalid_argument(format!("Unknown m/z encoding {}", encoding.mz))
+        })?;
+        let intensity = IntensityEncoding::try_from(encoding.intensity).map_err(|_| {
+            Status::invalid_argument(format!("Unknown intensity encoding {}", encoding.intensity))
+        })?;
+
+        if mz == MzEncoding::Float64 && intensity == IntensityEncoding::Float64 {
+            return Ok(None);
+        }
+
+        let mz_delta_unit = if encoding.mz_delta_unit > 0.0 {
+            encoding.mz_delta_unit
+        } else {
+            DEFAULT_MZ_DELTA_UNIT
+        };
+
+        Ok(Some(Self {
+            mz,
+            intensity,
+            mz_delta_unit,
+        }))
+    }
+
+    /// Moves the arrays that use a compact encoding into `scan.encoded_spectrum`
+    pub fn encode(&self, scan: &mut ScanMessage) {
+        let mut encoded = EncodedSpectrum {
+            mz_encoding: self.mz as i32,
+            intensity_encoding: self.intensity as i32,
+            peak_count: scan.mz_values.len() as i32,
+            ..Default::default()
+        };
+
+        match self.mz {
+            MzEncoding::Float64 => {}
+            MzEncoding::Delta => {
+                encoded.mz_deltas = encode_delta(&scan.mz_values, self.mz_delta_unit);
+                encoded.mz_delta_unit = self.mz_delta_unit;
+                scan.mz_values = Vec::new();
+            }
+            MzEncoding::NumpressLinear => {
+                encoded.mz_numpress = numpress::encode_linear(&scan.mz_values);
+                scan.mz_values = Vec::new();
+            }
+        }
+
+        match self.intensity {
+            IntensityEncoding::Float64 => {}
+            IntensityEncoding::Float32 => {
+                encoded.intensity_float32 =
+                    scan.intensity_values.iter().map(|&v| v as f32).collect();
+                scan.intensity_values = Vec::new();
+            }
+            IntensityEncoding::NumpressSlof => {
+                encoded.intensity_numpress = numpress::encode_slof(&scan.intensity_values);
+                scan.intensity_values = Vec::new();
+            }
+        }
+
+        scan.encoded_spectrum = Some(encoded);
+    }
+}
+
+/// Quantizes values to multiples of `unit` and stores the first absolutely, the rest as differences
+fn encode_delta(values: &[f64], unit: f64) -> Vec<i64> {
+    let mut previous = 0i64;
+    values
+        .iter()
+        .map(|&v| {
+            let quantized = (v / unit).round() as i64;
+            let delta = quantized - previous;
+            previous = quantized;
+            delta
+        })
+        .collect()
+}
+
+/// MS-Numpress encoders, byte-compatible with the reference implementation
+/// (<https://github.com/ms-numpress/ms-numpress>).
+mod numpress {
+    /// Linear prediction encoding, suited to sorted m/z arrays
+    pub fn encode_linear(data: &[f64]) -> Vec<u8> {
+        let fixed_point = optimal_linear_fixed_point(data);
+        let mut result = Vec::with_capacity(16 + data.len() * 5);
+        result.extend_from_slice(&fixed_point.to_be_bytes());
+
+        if data.is_empty() {
+            return result;
+        }
+
+        let mut ints = [0i64; 3];
+        ints[1] = (data[0] * fixed_point + 0.5) as i64;
+        result.extend_from_slice(&(ints[1] as u32).to_le_bytes());
+
+        if data.len() == 1 {
+            return result;
+        }
+
+        ints[2] = (data[1] * fixed_point + 0.5) as i64;
+        result.extend_from_slice(&(ints[2] as u32).to_le_bytes());
+
+        let mut half_bytes = Vec::with_capacity(10);
+        for &value in &data[2..] {
+            ints[0] = ints[1];
+            ints[1] = ints[2];
+            ints[2] = (value * fixed_point + 0.5) as i64;
+
+            let extrapolated = ints[1] + (ints[1] - ints[0]);
+            encode_int((ints[2] - extrapolated) as i32, &mut half_bytes);
+
+            let pairs = half_bytes.len() / 2;
+            for pair in half_bytes.chunks_exact(2) {
+                result.push((pair[0] << 4) | (pair[1] & 0x0f));
+            }
+            half_bytes.drain(..pairs * 2);
+        }
+
+        if let Some(&last) = half_bytes.first() {
+            result.push(last << 4);
+        }
+
+        result
+    }
+
+    /// Short logged float encoding, suited to intensities (non-negative values)
+    pub fn encode_slof(data: &[f64]) -> Vec<u8> {
+        let fixed_point = optimal_slof_fixed_point(data);
+        let mut result = Vec::with_capacity(8 + data.len() * 2);
+        result.extend_from_slice(&fixed_point.to_be_bytes());
+
+        for &value in data {
+            let x = ((value.max(0.0) + 1.0).ln() * fixed_point + 0.5) as u16;
+            result.extend_from_slice(&x.to_le_bytes());
+        }
+
+        result
+    }
+
+    fn optimal_linear_fixed_point(data: &[f64]) -> f64 {
+        match data.len() {
+            0 => 0.0,
+            1 => (f64::from(u32::MAX) / data[0]).floor(),
+            _ => {
+                let mut max_double = data[0].max(data[1]);
+                for window in data.windows(3) {
+                    let extrapolated = window[1] + (window[1] - window[0]);
+                    let diff = window[2] - extrapolated;
+                    max_double = max_double.max((diff.abs() + 1.0).ceil());
+                }
+                (f64::from(i32::MAX) / max_double).floor()
+            }
+        }
+    }
+
+    fn optimal_slof_fixed_point(data: &[f64]) -> f64 {
+        if data.is_empty() {
+            return 0.0;
+        }
+
+        let max_double = data
+            .iter()
+            .map(|&v| (v.max(0.0) + 1.0).ln())
+            .fold(1.0f64, f64::max);
+        (f64::from(u16::MAX) / max_double).floor()
+    }
+
+    /// Appends the half-byte representation of `x`: a header nibble giving the number of
+    /// leading 0x0 (0-8) or 0xf (9-15, minus 8) nibbles, then the remaining nibbles,
+    /// least significant first.
+    fn encode_int(x: i32, half_bytes: &mut Vec<u8>) {
+        let x = x as u32;
+        let mask = 0xf000_0000u32;
+        let init = x & mask;
+
+        let (header, leading) = if init == 0 {
+            let leading = (0..8).find(|&i| x & (mask >> (4 * i)) != 0).unwrap_or(8);
+            (leading, leading)
+        } else if init == mask {
+            let leading = (0..8)
+                .find(|&i| x & (mask >> (4 * i)) != mask >> (4 * i))
+                .unwrap_or(7);
+            (leading + 8, leading)
+        } else {
+            (0, 0)
+        };
+
+        half_bytes.push(header as u8);
+        for i in 0..(8 - leading) {
+            half_bytes.push(((x >> (4 * i)) & 0x0f) as u8);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// MS-Numpress linear prediction decoder, after the reference implementation
+    fn decode_linear(data: &[u8]) -> Vec<f64> {
+        let fixed_point = f64::from_be_bytes(data[..8].try_into().unwrap());
+        let read_u32 =
+            |at: usize| i64::from(u32::from_le_bytes(data[at..at + 4].try_into().unwrap()));
+        let mut result = Vec::new();
+        if data.len() < 12 {
+            return result;
+        }
+
+        let mut ints = [0, read_u32(8), 0];
+        result.push(ints[1] as f64 / fixed_point);
+        if data.len() < 16 {
+            return result;
+        }
+        ints[2] = read_u32(12);
+        result.push(ints[2] as f64 / fixed_point);
+
+        let mut nibbles = data[16..]
+            .iter()
+            .flat_map(|&byte| [byte >> 4, byte & 0x0f])
+            .peekable();
+        while let Some(header) = nibbles.next() {
+            // A lone padding nibble ends the data
+            if header == 0 && nibbles.peek().is_none() {
+                break;
+            }
+            let (leading, fill) = if header <= 8 {
+                (u32::from(header), 0u32)
+            } else {
+                let leading = u32::from(header) - 8;
+                (leading, !(u32::MAX >> (4 * leading)))
+            };
+            let mut residual = fill;
+            for i in 0..(8 - leading) {
+                residual |= u32::from(nibbles.next().unwrap()) << (4 * i);
+            }
+
+            ints[0] = ints[1];
+            ints[1] = ints[2];
+            ints[2] = i64::from(residual as i32) + 2 * ints[1] - ints[0];
+            result.push(ints[2] as f64 / fixed_point);
+        }
+        result
+    }
+
+    /// MS-Numpress short logged float decoder
+    fn decode_slof(data: &[u8]) -> Vec<f64> {
+        let fixed_point = f64::from_be_bytes(data[..8].try_into().unwrap());
+        data[8..]
+            .chunks_exact(2)
+            .map(|x| (f64::from(u16::from_le_bytes([x[0], x[1]])) / fixed_point).exp() - 1.0)
+            .collect()
+    }
+
+    fn mz_values() -> Vec<f64> {
+        // Irregular spacing, so the prediction residuals take both signs
+        (0..500)
+            .map(|i| 150.0 + f64::from(i) * 3.7 + f64::from(i * i % 11) * 0.013)
+            .collect()
+    }
+
+    #[test]
+    fn numpress_linear_round_trip() {
+        let mz = mz_values();
+        let decoded = decode_linear(&numpress::encode_linear(&mz));
+        assert_eq!(decoded.len(), mz.len());
+        for (original, decoded) in mz.iter().zip(&decoded) {
+            assert!(
+                (original - decoded).abs() < 1e-6,
+                "{} decoded as {}",
+                original,
+                decoded
+            );
+        }
+    }
+
+    #[test]
+    fn numpress_linear_short_arrays() {
+        assert!(decode_linear(&numpress::encode_linear(&[])).is_empty());
+        for mz in [
+            &[445.12][..],
+            &[445.12, 446.13][..],
+            &[445.12, 446.13, 447.11][..],
+        ] {
+            let decoded = decode_linear(&numpress::encode_linear(mz));
+            assert_eq!(decoded.len(), mz.len());
+            for (original, decoded) in mz.iter().zip(&decoded) {
+                assert!(
+                    (original - decoded).abs() < 1e-6,
+                    "{} decoded as {}",
+                    original,
+                    decoded
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn numpress_slof_round_trip() {
+        let intensities = [0.0, 1.0, 12.5, 3.3e3, 4.1e5, 7.7e6, 1e8];
+        let decoded = decode_slof(&numpress::encode_slof(&intensities));
+        assert_eq!(decoded.len(), intensities.len());
+        assert_eq!(decoded[0], 0.0);
+        for (original, decoded) in intensities.iter().zip(&decoded).skip(1) {
+            // Half a quantization step of ln(1e8 + 1) / 65535, in ln(x + 1)
+            let relative = (original - decoded).abs() / (original + 1.0);
+            assert!(relative < 2e-4, "{} decoded as {}", original, decoded);
+        }
+    }
+
+    #[test]
+    fn encoder_moves_numpress_arrays() {
+        let encoder = SpectrumEncoder::from_request(Some(SpectrumEncoding {
+            mz: MzEncoding::NumpressLinear as i32,
+            intensity: IntensityEncoding::NumpressSlof as i32,
+            ..Default::default()
+        }))
+        .unwrap()
+        .unwrap();
+        let mz = mz_values();
+        let mut scan = ScanMessage {
+            intensity_values: vec![1e6; mz.len()],
+            mz_values: mz.clone(),
+            ..Default::default()
+        };
+
+        encoder.encode(&mut scan);
+
+        assert!(scan.mz_values.is_empty() && scan.intensity_values.is_empty());
+        let encoded = scan.encoded_spectrum.unwrap();
+        assert_eq!(encoded.peak_count, mz.len() as i32);
+        assert_eq!(decode_linear(&encoded.mz_numpress).len(), mz.len());
+        assert_eq!(decode_slof(&encoded.intensity_numpress).len(), mz.len());
+    }
+}
@@ -0,0 +1,454 @@
+//! Fault injection for exercising client error handling.
+//!
+//! A [`FaultPlan`] comes from the `[faults]` config section or the `SetFaultPlan` RPC.
+//! Triggers are evaluated against each session's scan count and run time, so every
+//! acquisition fails the same way until the plan is replaced. Status log excursions
+//! are timed from when the plan was set instead.
+
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Mutex;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::watch;
+use tokio_stream::Stream;
+use tonic::{Code, Status};
+
+use crate::malformed::{MalformedData, ScanCorruptor};
+use crate::network::NetworkImpairment;
+use crate::proto;
+use crate::proto::ScanMessage;
+use crate::status_log::StatusExcursion;
+
+const DEFAULT_FAULT_MESSAGE: &str = "Injected fault";
+const DEFAULT_ABORT_MESSAGE: &str = "Injected stream abort";
+const DEFAULT_REFUSAL_MESSAGE: &str = "Injected StartAcquisition refusal";
+
+/// Longest generation stall a plan may inject (one day)
+const MAX_STALL_SECONDS: f64 = 86_400.0;
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FaultPlan {
+    /// Move the session to Faulted
+    pub session_fault: Option<FaultTrigger>,
+    /// Reported in `StatusResponse.error_message` after a session fault
+    pub fault_message: String,
+    /// End open scan/event streams with an error status
+    pub stream_abort: Option<StreamAbort>,
+    /// Pause scan generation without changing state
+    pub stall: Option<GenerationStall>,
+    /// Reject `StartAcquisition` calls
+    pub refuse_start: Option<StartRefusal>,
+    /// Corrupt scan contents at random
+    pub malformed_data: Option<MalformedData>,
+    /// Network conditions for streams that do not request their own
+    pub network: Option<NetworkImpairment>,
+    /// Scripted changes to status log readings
+    pub status_excursions: Vec<StatusExcursion>,
+}
+
+/// When a fault fires within a session; with neither field set it fires immediately
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FaultTrigger {
+    pub at_scan: Option<i64>,
+    pub after_seconds: Option<f64>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StreamAbort {
+    pub trigger: FaultTrigger,
+    /// gRPC status code; 0 means UNAVAILABLE
+    pub status_code: i32,
+    pub message: String,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct GenerationStall {
+    pub trigger: FaultTrigger,
+    pub duration_seconds: f64,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StartRefusal {
+    /// gRPC status code; 0 answers `success = false` instead of failing the call
+    pub status_code: i32,
+    pub message: String,
+    /// Calls to refuse before the refusal clears itself; 0 refuses all
+    pub count: u32,
+}
+
+impl FaultTrigger {
+    fn is_due(&self, scans_generated: i64, elapsed: Duration) -> bool {
+        match (self.at_scan, self.after_seconds) {
+            (None, None) => true,
+            (at_scan, after_seconds) => {
+                at_scan.is_some_and(|n| scans_generated >= n)
+                    || after_seconds.is_some_and(|s| elapsed.as_secs_f64() >= s)
+            }
+        }
+    }
+
+    fn validate(&self, name: &str) -> Result<(), String> {
+        if self.at_scan.is_some_and(|n| n < 0) {
+            return Err(format!("{}.at_scan must not be negative", name));
+        }
+        if self.after_seconds.is_some_and(|s| !s.is_finite() || s < 0.0) {
+            return Err(format!("{}.after_seconds must be finite and not negative", name));
+        }
+        Ok(())
+    }
+}
+
+impl StreamAbort {
+    fn status(&self) -> Status {
+        let code = match self.status_code {
+            0 => Code::Unavailable,
+            code => Code::from_i32(code),
+        };
+        Status::new(code, or_default(&self.message, DEFAULT_ABORT_MESSAGE))
+    }
+}
+
+impl FaultPlan {
+    pub fn is_empty(&self) -> bool {
+        *self == FaultPlan::default()
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if let Some(trigger) = &self.session_fault {
+            trigger.validate("session_fault")?;
+        }
+        if let Some(abort) = &self.stream_abort {
+            abort.trigger.validate("stream_abort.trigger")?;
+            validate_code("stream_abort.status_code", abort.status_code)?;
+        }
+        if let Some(stall) = &self.stall {
+            stall.trigger.validate("stall.trigger")?;
+            if !stall.duration_seconds.is_finite() || stall.duration_seconds <= 0.0 {
+                return Err("stall.duration_seconds must be positive and finite".to_string());
+            }
+            if stall.duration_seconds > MAX_STALL_SECONDS {
+                return Err(format!(
+                    "stall.duration_seconds must be at most {}",
+                    MAX_STALL_SECONDS
+                ));
+            }
+        }
+        if let Some(refusal) = &self.refuse_start {
+            validate_code("refuse_start.status_code", refusal.status_code)?;
+        }
+        if let Some(malformed) = &self.malformed_data {
+            malformed.validate()?;
+        }
+        if let Some(network) = &self.network {
+            network.validate().map_err(|e| format!("network.{}", e))?;
+        }
+        for (index, excursion) in self.status_excursions.iter().enumerate() {
+            excursion
+                .validate()
+                .map_err(|e| format!("status_excursions[{}].{}", index, e))?;
+        }
+        Ok(())
+    }
+}
+
+fn validate_code(name: &str, code: i32) -> Result<(), String> {
+    if !(0..=16).contains(&code) {
+        return Err(format!("{} must be a gRPC status code (0-16), got {}", name, code));
+    }
+    Ok(())
+}
+
+fn or_default(message: &str, default: &str) -> String {
+    if message.is_empty() {
+        default.to_string()
+    } else {
+        message.to_string()
+    }
+}
+
+/// A fault that became due during an acquisition
+#[derive(Debug)]
+pub enum FaultAction {
+    /// Fault the session with this error message
+    Fault(String),
+    /// Stop generating scans for this long
+    Stall(Duration),
+}
+
+/// Holds the active plan and aborts subscriber streams on demand
+pub struct FaultInjector {
+    /// Plan and a generation counter that changes whenever the plan is replaced
+    plan: Mutex<(u64, FaultPlan)>,
+    /// When the plan was last replaced, which times status excursions
+    plan_set_at: Mutex<Instant>,
+    abort: watch::Sender<Option<Status>>,
+    network: watch::Sender<NetworkImpairment>,
+}
+
+impl FaultInjector {
+    pub fn new(plan: FaultPlan) -> Self {
+        Self {
+            network: watch::Sender::new(plan.network.unwrap_or_default()),
+            plan: Mutex::new((0, plan)),
+            plan_set_at: Mutex::new(Instant::now()),
+            abort: watch::Sender::new(None),
+        }
+    }
+
+    pub fn plan(&self) -> FaultPlan {
+        self.plan.lock().unwrap().1.clone()
+    }
+
+    pub fn set_plan(&self, plan: FaultPlan) {
+        let mut current = self.plan.lock().unwrap();
+        self.network.send_replace(plan.network.unwrap_or_default());
+        *current = (current.0 + 1, plan);
+        *self.plan_set_at.lock().unwrap() = Instant::now();
+    }
+
+    /// The plan's status excursions and the time since the plan was set
+    pub fn status_excursions(&self) -> (Vec<StatusExcursion>, Duration) {
+        let current = self.plan.lock().unwrap();
+        let elapsed = self.plan_set_at.lock().unwrap().elapsed();
+        (current.1.status_excursions.clone(), elapsed)
+    }
+
+    /// Follows the plan's network impairment
+    pub fn network(&self) -> watch::Receiver<NetworkImpairment> {
+        self.network.subscribe()
+    }
+
+    /// Applies a configured `StartAcquisition` refusal.
+    ///
+    /// Returns `Err` to fail the call, `Ok(Some(message))` to answer `success = false`,
+    /// or `Ok(None)` to let the acquisition start.
+    pub fn check_start(&self) -> Result<Option<String>, Status> {
+        let mut current = self.plan.lock().unwrap();
+        let Some(refusal) = current.1.refuse_start.as_mut() else {
+            return Ok(None);
+        };
+
+        let code = refusal.status_code;
+        let message = or_default(&refusal.message, DEFAULT_REFUSAL_MESSAGE);
+        match refusal.count {
+            0 => {}
+            1 => current.1.refuse_start = None,
+            _ => refusal.count -= 1,
+        }
+
+        if code == 0 {
+            Ok(Some(message))
+        } else {
+            Err(Status::new(Code::from_i32(code), message))
+        }
+    }
+
+    /// Starts tracking which faults have fired for a new session
+    pub fn session(&self) -> SessionFaults {
+        SessionFaults {
+            generation: None,
+            fault_fired: false,
+            abort_fired: false,
+            stall_fired: false,
+            corruptor: None,
+        }
+    }
+
+    /// Wraps a subscriber stream so it ends with the injected status when streams abort.
+    /// Only aborts injected after the stream was opened affect it.
+    pub fn abortable<S>(&self, inner: S) -> AbortOnFault<S> {
+        let mut abort = self.abort.subscribe();
+        AbortOnFault {
+            inner,
+            abort: Some(Box::pin(async move {
+                loop {
+                    if abort.changed().await.is_err() {
+                        return std::future::pending().await;
+                    }
+                    if let Some(status) = abort.borrow_and_update().clone() {
+                        return status;
+                    }
+                }
+            })),
+        }
+    }
+}
+
+/// Per-session record of fired faults; each fault fires at most once per session
+/// unless the plan is replaced.
+pub struct SessionFaults {
+    generation: Option<u64>,
+    fault_fired: bool,
+    abort_fired: bool,
+    stall_fired: bool,
+    /// Created on first use so a seeded plan replays the same defects
+    corruptor: Option<ScanCorruptor>,
+}
+
+impl SessionFaults {
+    /// Returns the next due action, aborting streams directly when that is due
+    pub fn check(
+        &mut self,
+        injector: &FaultInjector,
+        scans_generated: i64,
+        elapsed: Duration,
+    ) -> Option<FaultAction> {
+        let (generation, plan) = &*injector.plan.lock().unwrap();
+        self.sync(*generation);
+
+        if let Some(abort) = &plan.stream_abort {
+            if !self.abort_fired && abort.trigger.is_due(scans_generated, elapsed) {
+                self.abort_fired = true;
+                injector.abort.send_replace(Some(abort.status()));
+            }
+        }
+
+        if let Some(stall) = &plan.stall {
+            if !self.stall_fired && stall.trigger.is_due(scans_generated, elapsed) {
+                self.stall_fired = true;
+                return Some(FaultAction::Stall(
+                    Duration::try_from_secs_f64(stall.duration_seconds).unwrap_or(Duration::MAX),
+                ));
+            }
+        }
+
+        if let Some(trigger) = &plan.session_fault {
+            if !self.fault_fired && trigger.is_due(scans_generated, elapsed) {
+                self.fault_fired = true;
+                return Some(FaultAction::Fault(or_default(
+                    &plan.fault_message,
+                    DEFAULT_FAULT_MESSAGE,
+                )));
+            }
+        }
+
+        None
+    }
+
+    /// Applies the plan's malformed-data defects to a scan about to be published
+    pub fn corrupt(&mut self, injector: &FaultInjector, scan: &mut ScanMessage) {
+        let (generation, plan) = &*injector.plan.lock().unwrap();
+        self.sync(*generation);
+
+        if let Some(malformed) = &plan.malformed_data {
+            self.corruptor
+                .get_or_insert_with(|| ScanCorruptor::new(malformed.seed))
+                .corrupt(malformed, scan);
+        }
+    }
+
+    /// Re-arms every fault when the plan has been replaced
+    fn sync(&mut self, generation: u64) {
+        if self.generation != Some(generation) {
+            self.generation = Some(generation);
+            self.fault_fired = false;
+            self.abort_fired = false;
+            self.stall_fired = false;
+            self.corruptor = None;
+        }
+    }
+}
+
+/// Stream wrapper that ends with an injected error status
+pub struct AbortOnFault<S> {
+    inner: S,
+    /// Resolves with the abort status; `None` once the stream has ended
+    abort: Option<Pin<Box<dyn Future<Output = Status> + Send>>>,
+}
+
+impl<S, T> Stream for AbortOnFault<S>
+where
+    S: Stream<Item = Result<T, Status>> + Unpin,
+{
+    type Item = Result<T, Status>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let Some(abort) = self.abort.as_mut() else {
+            return Poll::Ready(None);
+        };
+        if let Poll::Ready(status) = abort.as_mut().poll(cx) {
+            self.abort = None;
+            return Poll::Ready(Some(Err(status)));
+        }
+
+        Pin::new(&mut self.inner).poll_next(cx)
+    }
+}
+
+impl From<proto::FaultTrigger> for FaultTrigger {
+    fn from(trigger: proto::FaultTrigger) -> Self {
+        Self {
+            at_scan: trigger.at_scan,
+            after_seconds: trigger.after_seconds,
+        }
+    }
+}
+
+impl From<FaultTrigger> for proto::FaultTrigger {
+    fn from(trigger: FaultTrigger) -> Self {
+        Self {
+            at_scan: trigger.at_scan,
+            after_seconds: trigger.after_seconds,
+        }
+    }
+}
+
+impl From<proto::FaultPlan> for FaultPlan {
+    fn from(plan: proto::FaultPlan) -> Self {
+        Self {
+            session_fault: plan.session_fault.map(Into::into),
+            fault_message: plan.fault_message,
+            stream_abort: plan.stream_abort.map(|abort| StreamAbort {
+                trigger: abort.trigger.unwrap_or_default().into(),
+                status_code: abort.status_code,
+                message: abort.message,
+            }),
+            stall: plan.stall.map(|stall| GenerationStall {
+                trigger: stall.trigger.unwrap_or_default().into(),
+                duration_seconds: stall.duration_seconds,
+            }),
+            refuse_start: plan.refuse_start.map(|refusal| StartRefusal {
+                status_code: refusal.status_code,
+                message: refusal.message,
+                count: refusal.count.max(0) as u32,
+            }),
+            malformed_data: plan.malformed_data.map(Into::into),
+            network: plan.network.map(Into::into),
+            status_excursions: plan.status_excursions.into_iter().map(Into::into).collect(),
+        }
+    }
+}
+
+impl From<FaultPlan> for proto::FaultPlan {
+    fn from(plan: FaultPlan) -> Self {
+        Self {
+            session_fault: plan.session_fault.map(Into::into),
+            fault_message: plan.fault_message,
+            stream_abort: plan.stream_abort.map(|abort| proto::StreamAbort {
+                trigger: Some(abort.trigger.into()),
+                status_code: abort.status_code,
+                message: abort.message,
+            }),
+            stall: plan.stall.map(|stall| proto::GenerationStall {
+                trigger: Some(stall.trigger.into()),
+                duration_seconds: stall.duration_seconds,
+            }),
+            refuse_start: plan.refuse_start.map(|refusal| proto::StartRefusal {
+                status_code: refusal.status_code,
+                message: refusal.message,
+                count: refusal.count as i32,
+            }),
+            malformed_data: plan.malformed_data.map(Into::into),
+            network: plan.network.map(Into::into),
+            status_excursions: plan.status_excursions.into_iter().map(Into::into).collect(),
+        }
+    }
+}
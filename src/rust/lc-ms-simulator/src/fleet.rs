@@ -0,0 +1,229 @@
+//! Several virtual instruments behind one gRPC service.
+//!
+//! Every instrument is a complete [`SimulatorServiceImpl`] with its own profile, power
+//! state, tune, fault plan and sessions. Requests pick one with the `instrument-id`
+//! metadata key; requests without it go to the first (default) instrument, so clients
+//! written for a single instrument keep working unchanged.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use tonic::{Request, Response, Status};
+
+use crate::auth::{authorize, Role};
+use crate::proto::simulator_service_server::SimulatorService;
+use crate::proto::*;
+use crate::service::SimulatorServiceImpl;
+
+/// Request metadata key naming the addressed instrument
+pub const INSTRUMENT_ID_METADATA: &str = "instrument-id";
+
+#[derive(Clone)]
+pub struct Fleet {
+    instruments: Arc<Vec<SimulatorServiceImpl>>,
+    by_id: Arc<HashMap<String, usize>>,
+}
+
+impl Fleet {
+    /// `instruments` must be non-empty with unique IDs; the first is the default
+    pub fn new(instruments: Vec<SimulatorServiceImpl>) -> Self {
+        assert!(
+            !instruments.is_empty(),
+            "a fleet needs at least one instrument"
+        );
+        let by_id = instruments
+            .iter()
+            .enumerate()
+            .map(|(index, instrument)| (instrument.instrument_id().to_string(), index))
+            .collect();
+        Self {
+            instruments: Arc::new(instruments),
+            by_id: Arc::new(by_id),
+        }
+    }
+
+    /// The instrument that serves requests without an `instrument-id`
+    pub fn default_instrument(&self) -> &SimulatorServiceImpl {
+        &self.instruments[0]
+    }
+
+    pub fn instruments(&self) -> &[SimulatorServiceImpl] {
+        &self.instruments
+    }
+
+    /// Shuts every instrument down concurrently, all against the same deadline
+    pub async fn shutdown(&self, deadline: tokio::time::Instant) {
+        let tasks: Vec<_> = self
+            .instruments
+            .iter()
+            .map(|instrument| {
+                let instrument = instrument.clone();
+                tokio::spawn(async move { instrument.shutdown(deadline).await })
+            })
+            .collect();
+        for task in tasks {
+            let _ = task.await;
+        }
+    }
+
+    /// Picks the instrument a request is addressed to
+    fn route<T>(&self, request: &Request<T>) -> Result<&SimulatorServiceImpl, Status> {
+        let Some(value) = request.metadata().get(INSTRUMENT_ID_METADATA) else {
+            return Ok(self.default_instrument());
+        };
+        let id = value.to_str().map_err(|_| {
+            Status::invalid_argument(format!(
+                "{} metadata is not valid ASCII",
+                INSTRUMENT_ID_METADATA
+            ))
+        })?;
+        self.by_id
+            .get(id)
+            .map(|&index| &self.instruments[index])
+            .ok_or_else(|| Status::not_found(format!("Unknown instrument '{}'", id)))
+    }
+}
+
+#[tonic::async_trait]
+impl SimulatorService for Fleet {
+    type StreamScansStream = <SimulatorServiceImpl as SimulatorService>::StreamScansStream;
+    type StreamEventsStream = <SimulatorServiceImpl as SimulatorService>::StreamEventsStream;
+    type StreamScanBatchesStream =
+        <SimulatorServiceImpl as SimulatorService>::StreamScanBatchesStream;
+    type StreamStatusLogStream = <SimulatorServiceImpl as SimulatorService>::StreamStatusLogStream;
+
+    async fn stream_scans(
+        &self,
+        request: Request<StreamScansRequest>,
+    ) -> Result<Response<Self::StreamScansStream>, Status> {
+        self.route(&request)?.stream_scans(request).await
+    }
+
+    async fn stream_events(
+        &self,
+        request: Request<StreamScansRequest>,
+    ) -> Result<Response<Self::StreamEventsStream>, Status> {
+        self.route(&request)?.stream_events(request).await
+    }
+
+    async fn stream_scan_batches(
+        &self,
+        request: Request<StreamScanBatchesRequest>,
+    ) -> Result<Response<Self::StreamScanBatchesStream>, Status> {
+        self.route(&request)?.stream_scan_batches(request).await
+    }
+
+    async fn get_status(
+        &self,
+        request: Request<GetStatusRequest>,
+    ) -> Result<Response<StatusResponse>, Status> {
+        self.route(&request)?.get_status(request).await
+    }
+
+    async fn start_acquisition(
+        &self,
+        request: Request<StartAcquisitionRequest>,
+    ) -> Result<Response<StartAcquisitionResponse>, Status> {
+        self.route(&request)?.start_acquisition(request).await
+    }
+
+    async fn stop_acquisition(
+        &self,
+        request: Request<StopAcquisitionRequest>,
+    ) -> Result<Response<StopAcquisitionResponse>, Status> {
+        self.route(&request)?.stop_acquisition(request).await
+    }
+
+    async fn pause_acquisition(
+        &self,
+        request: Request<PauseAcquisitionRequest>,
+    ) -> Result<Response<PauseAcquisitionResponse>, Status> {
+        self.route(&request)?.pause_acquisition(request).await
+    }
+
+    async fn resume_acquisition(
+        &self,
+        request: Request<ResumeAcquisitionRequest>,
+    ) -> Result<Response<ResumeAcquisitionResponse>, Status> {
+        self.route(&request)?.resume_acquisition(request).await
+    }
+
+    async fn trigger_start(
+        &self,
+        request: Request<TriggerStartRequest>,
+    ) -> Result<Response<TriggerStartResponse>, Status> {
+        self.route(&request)?.trigger_start(request).await
+    }
+
+    async fn list_instruments(
+        &self,
+        request: Request<ListInstrumentsRequest>,
+    ) -> Result<Response<ListInstrumentsResponse>, Status> {
+        authorize(&request, Role::Observer)?;
+
+        let mut instruments = Vec::with_capacity(self.instruments.len());
+        for (index, instrument) in self.instruments.iter().enumerate() {
+            instruments.push(InstrumentSummary {
+                is_default: index == 0,
+                ..instrument.summary().await
+            });
+        }
+        Ok(Response::new(ListInstrumentsResponse { instruments }))
+    }
+
+    async fn get_instrument_info(
+        &self,
+        request: Request<GetInstrumentInfoRequest>,
+    ) -> Result<Response<InstrumentInfoResponse>, Status> {
+        self.route(&request)?.get_instrument_info(request).await
+    }
+
+    async fn get_instrument_values(
+        &self,
+        request: Request<GetInstrumentValuesRequest>,
+    ) -> Result<Response<InstrumentValuesResponse>, Status> {
+        self.route(&request)?.get_instrument_values(request).await
+    }
+
+    async fn set_instrument_values(
+        &self,
+        request: Request<SetInstrumentValuesRequest>,
+    ) -> Result<Response<InstrumentValuesResponse>, Status> {
+        self.route(&request)?.set_instrument_values(request).await
+    }
+
+    async fn set_instrument_mode(
+        &self,
+        request: Request<SetInstrumentModeRequest>,
+    ) -> Result<Response<InstrumentModeResponse>, Status> {
+        self.route(&request)?.set_instrument_mode(request).await
+    }
+
+    async fn get_instrument_mode(
+        &self,
+        request: Request<GetInstrumentModeRequest>,
+    ) -> Result<Response<InstrumentModeResponse>, Status> {
+        self.route(&request)?.get_instrument_mode(request).await
+    }
+
+    async fn stream_status_log(
+        &self,
+        request: Request<StreamStatusLogRequest>,
+    ) -> Result<Response<Self::StreamStatusLogStream>, Status> {
+        self.route(&request)?.stream_status_log(request).await
+    }
+
+    async fn set_fault_plan(
+        &self,
+        request: Request<SetFaultPlanRequest>,
+    ) -> Result<Response<FaultPlanResponse>, Status> {
+        self.route(&request)?.set_fault_plan(request).await
+    }
+
+    async fn get_fault_plan(
+        &self,
+        request: Request<GetFaultPlanRequest>,
+    ) -> Result<Response<FaultPlanResponse>, Status> {
+        self.route(&request)?.get_fault_plan(request).await
+    }
+}
@@ -0,0 +1,386 @@
+//! Offline dataset generation.
+//!
+//! `lc-ms-simulator generate` runs an acquisition without the gRPC server: the same scan
+//! generator, instrument profile and tune defaults, with the scans scheduled on a
+//! simulated clock instead of waiting for wall-clock ticks, so it finishes as fast as
+//! the CPU allows. The scans go to an indexed mzML file, an MGF file with the MS2 scans
+//! and a tab-separated ground-truth table with what the simulator knows about each scan,
+//! such as the true precursor charge, which the other files leave to be inferred.
+//!
+//! The writers run on their own threads, fed through bounded queues, so generation and
+//! encoding overlap.
+
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, SyncSender};
+use std::sync::Arc;
+use std::thread;
+use std::time::Instant;
+
+use anyhow::{bail, Context, Result};
+use clap::Args;
+use tracing::info;
+
+use crate::config::Settings;
+use crate::mzml::{MzmlWriter, RunInfo};
+use crate::proto::{FragmentationType, ScanMessage, SimulationParameters};
+use crate::simulator::{current_timestamp_ms, AcquisitionCycle, ScanGenerator};
+use crate::tune::TuneState;
+
+/// Scans queued for each writer
+const QUEUE_CAPACITY: usize = 256;
+
+/// Scans between progress messages
+const PROGRESS_INTERVAL: i64 = 10_000;
+
+/// Generates a dataset without starting the server
+#[derive(Args, Debug)]
+pub struct GenerateArgs {
+    /// Directory the dataset is written to; created if missing
+    #[arg(short, long, default_value = ".")]
+    output: PathBuf,
+
+    /// Dataset file name, without extension; the files must not exist yet
+    #[arg(long, default_value = "dataset")]
+    name: String,
+
+    /// Number of scans [default: the method's max_scans]
+    #[arg(short = 'n', long)]
+    scans: Option<i32>,
+
+    /// Seconds of acquisition on the simulated clock [default: the method's
+    /// max_duration_seconds]
+    #[arg(long)]
+    duration: Option<f64>,
+
+    /// Scans per second on the simulated clock [default: the simulation's]
+    #[arg(long)]
+    scan_rate: Option<f64>,
+
+    /// MS2 scans per MS1 scan [default: the simulation's]
+    #[arg(long)]
+    ms2_per_ms1: Option<i32>,
+
+    /// Peaks per MS1 scan [default: the simulation's, or 500-2000]
+    #[arg(long)]
+    ms1_peaks: Option<i32>,
+
+    /// Peaks per MS2 scan [default: the simulation's, or 50-300]
+    #[arg(long)]
+    ms2_peaks: Option<i32>,
+
+    /// Random seed; the same seed and options generate the same scans [default: random]
+    #[arg(long)]
+    seed: Option<u64>,
+
+    /// Write the mzML binary arrays uncompressed, whatever the recording setting
+    #[arg(long)]
+    no_compression: bool,
+}
+
+/// Generates the dataset described by `args` for the default instrument
+pub fn run(settings: &Settings, args: GenerateArgs) -> Result<()> {
+    let instrument = settings.instruments().swap_remove(0);
+    let profile = instrument.profile.profile();
+    let params = settings.simulation.resolve(Some(SimulationParameters {
+        scan_rate: args.scan_rate.unwrap_or(0.0),
+        ms2_per_ms1: args.ms2_per_ms1.unwrap_or(0),
+        ms1_peak_count: args.ms1_peaks,
+        ms2_peak_count: args.ms2_peaks,
+        ..Default::default()
+    }));
+    let scan_settings = profile.scan_settings(&params).map_err(anyhow::Error::msg)?;
+
+    let max_scans = args.scans.or(settings.method.max_scans);
+    let max_duration = args.duration.or(settings.method.max_duration_seconds);
+    if max_scans.is_some_and(|scans| scans <= 0)
+        || max_duration.is_some_and(|seconds| seconds.is_nan() || seconds <= 0.0)
+    {
+        bail!("--scans and --duration must be positive");
+    }
+    if max_scans.is_none() && max_duration.is_none() {
+        bail!("Set --scans or --duration (or a method limit) to end the acquisition");
+    }
+
+    std::fs::create_dir_all(&args.output)
+        .with_context(|| format!("Cannot create {}", args.output.display()))?;
+    let path = |extension: &str| args.output.join(format!("{}.{}", args.name, extension));
+    let (mzml_path, mgf_path, truth_path) = (path("mzML"), path("mgf"), path("tsv"));
+    for path in [&mzml_path, &mgf_path, &truth_path] {
+        if path.exists() {
+            bail!("{} already exists", path.display());
+        }
+    }
+
+    let start_timestamp_ms = current_timestamp_ms();
+    let mzml = MzmlWriter::create(
+        &mzml_path,
+        RunInfo {
+            run_id: args.name.clone(),
+            model: instrument.profile,
+            serial_number: instrument.serial_number(),
+            start_timestamp_ms,
+            zlib: !args.no_compression && settings.recording.zlib_compression,
+        },
+    )
+    .with_context(|| format!("Cannot create {}", mzml_path.display()))?;
+    let mgf = create(&mgf_path)?;
+    let truth = create(&truth_path)?;
+
+    let mut generator = match args.seed {
+        Some(seed) => ScanGenerator::with_seed(scan_settings, seed),
+        None => ScanGenerator::new(scan_settings),
+    };
+    generator.set_conditions(TuneState::new(profile.tune_parameters).conditions());
+
+    info!(
+        "Generating {} as {}, ID {}: scan_rate={} scans/s, ms2_per_ms1={}, max_scans={:?}, \
+         max_duration={:?}",
+        args.output.join(&args.name).display(),
+        instrument.name(),
+        instrument.id,
+        params.scan_rate,
+        params.ms2_per_ms1,
+        max_scans,
+        max_duration
+    );
+    let started = Instant::now();
+
+    let (scans, ms2_count) = thread::scope(|scope| -> Result<(i64, i64)> {
+        let (mzml_sender, mzml_receiver) = mpsc::sync_channel::<Arc<ScanMessage>>(QUEUE_CAPACITY);
+        let mzml_writer = scope.spawn(move || -> io::Result<()> {
+            let mut mzml = mzml;
+            for scan in mzml_receiver {
+                mzml.write_spectrum(&scan)?;
+            }
+            mzml.finish().map(drop)
+        });
+
+        let (text_sender, text_receiver) = mpsc::sync_channel::<Arc<ScanMessage>>(QUEUE_CAPACITY);
+        let title = args.name.as_str();
+        let text_writer = scope.spawn(move || -> io::Result<i64> {
+            let mut mgf = mgf;
+            let mut truth = truth;
+            write_truth_header(&mut truth)?;
+            let mut precursor_scan = None;
+            let mut ms2_count = 0;
+            for scan in text_receiver {
+                if scan.ms_order == 1 {
+                    precursor_scan = Some(scan.scan_number);
+                } else {
+                    write_mgf_spectrum(&mut mgf, title, &scan)?;
+                    ms2_count += 1;
+                }
+                write_truth_row(&mut truth, &scan, precursor_scan)?;
+            }
+            mgf.flush()?;
+            truth.flush()?;
+            Ok(ms2_count)
+        });
+
+        let mut acquisition = Acquisition {
+            generator: &mut generator,
+            params: &params,
+            max_scans,
+            max_duration,
+            start_timestamp_ms,
+            outputs: [mzml_sender, text_sender],
+            scans: 0,
+        };
+        // A writer that failed has hung up; its result says why
+        let generated = acquisition.run();
+
+        drop(acquisition);
+        let written = mzml_writer
+            .join()
+            .expect("mzML writer panicked")
+            .with_context(|| format!("Cannot write {}", mzml_path.display()));
+        let ms2_count = text_writer
+            .join()
+            .expect("MGF writer panicked")
+            .with_context(|| {
+                format!(
+                    "Cannot write {} or {}",
+                    mgf_path.display(),
+                    truth_path.display()
+                )
+            });
+        written?;
+        Ok((generated, ms2_count?))
+    })?;
+
+    let elapsed = started.elapsed().as_secs_f64();
+    info!(
+        "Generated {} scans ({} MS2) in {:.1} s ({:.0} scans/s): {}, {}, {}",
+        scans,
+        ms2_count,
+        elapsed,
+        scans as f64 / elapsed.max(1e-9),
+        mzml_path.display(),
+        mgf_path.display(),
+        truth_path.display()
+    );
+    Ok(())
+}
+
+/// An acquisition on the simulated clock, which advances by one scan interval per scan
+struct Acquisition<'a> {
+    generator: &'a mut ScanGenerator,
+    params: &'a SimulationParameters,
+    max_scans: Option<i32>,
+    max_duration: Option<f64>,
+    start_timestamp_ms: i64,
+    outputs: [SyncSender<Arc<ScanMessage>>; 2],
+    scans: i64,
+}
+
+impl Acquisition<'_> {
+    /// Generates MS1/MS2 cycles until a limit is reached; returns the scan count
+    fn run(&mut self) -> i64 {
+        let mut cycle = AcquisitionCycle::new(self.params);
+        while !self.over() {
+            let scan = cycle.next_scan(self.generator);
+            if !self.emit(scan) {
+                break;
+            }
+        }
+        self.scans
+    }
+
+    /// Seconds on the simulated clock when the next scan is acquired
+    fn elapsed(&self) -> f64 {
+        self.scans as f64 / self.params.scan_rate
+    }
+
+    /// Whether the acquisition reached its scan or duration limit
+    fn over(&self) -> bool {
+        self.max_scans.is_some_and(|max| self.scans >= max as i64)
+            || self.max_duration.is_some_and(|max| self.elapsed() > max)
+    }
+
+    /// Stamps a scan's retention time and timestamp with the simulated clock and queues
+    /// it; `false` if a writer failed
+    fn emit(&mut self, mut scan: ScanMessage) -> bool {
+        let elapsed = self.elapsed();
+        scan.retention_time = elapsed / 60.0;
+        scan.timestamp_ms = self.start_timestamp_ms + (elapsed * 1000.0) as i64;
+        let scan = Arc::new(scan);
+        for output in &self.outputs {
+            if output.send(Arc::clone(&scan)).is_err() {
+                return false;
+            }
+        }
+        self.scans += 1;
+        if self.scans % PROGRESS_INTERVAL == 0 {
+            info!("{} scans generated", self.scans);
+        }
+        true
+    }
+}
+
+fn create(path: &Path) -> Result<BufWriter<File>> {
+    File::options()
+        .write(true)
+        .create_new(true)
+        .open(path)
+        .map(BufWriter::new)
+        .with_context(|| format!("Cannot create {}", path.display()))
+}
+
+/// One MS2 scan as an MGF query, titled `<name>.<scan>.<scan>.<charge>` as the
+/// Trans-Proteomic Pipeline names spectra
+fn write_mgf_spectrum(out: &mut impl Write, name: &str, scan: &ScanMessage) -> io::Result<()> {
+    writeln!(out, "BEGIN IONS")?;
+    writeln!(
+        out,
+        "TITLE={}.{}.{}.{}",
+        name,
+        scan.scan_number,
+        scan.scan_number,
+        scan.precursor_charge.unwrap_or(0)
+    )?;
+    match scan.precursor_intensity {
+        Some(intensity) => writeln!(
+            out,
+            "PEPMASS={} {}",
+            scan.precursor_mass.unwrap_or_default(),
+            intensity
+        )?,
+        None => writeln!(out, "PEPMASS={}", scan.precursor_mass.unwrap_or_default())?,
+    }
+    if let Some(charge) = scan.precursor_charge {
+        writeln!(out, "CHARGE={}+", charge)?;
+    }
+    writeln!(out, "RTINSECONDS={}", scan.retention_time * 60.0)?;
+    writeln!(out, "SCANS={}", scan.scan_number)?;
+    for (mz, intensity) in scan.mz_values.iter().zip(&scan.intensity_values) {
+        writeln!(out, "{} {}", mz, intensity)?;
+    }
+    writeln!(out, "END IONS")?;
+    writeln!(out)
+}
+
+const TRUTH_COLUMNS: &[&str] = &[
+    "scan_number",
+    "ms_order",
+    "retention_time_min",
+    "timestamp_ms",
+    "precursor_scan",
+    "precursor_mz",
+    "precursor_charge",
+    "precursor_intensity",
+    "isolation_width",
+    "collision_energy",
+    "fragmentation",
+    "analyzer",
+    "resolution",
+    "peak_count",
+    "base_peak_mz",
+    "base_peak_intensity",
+    "total_ion_current",
+];
+
+fn write_truth_header(out: &mut impl Write) -> io::Result<()> {
+    writeln!(out, "{}", TRUTH_COLUMNS.join("\t"))
+}
+
+/// One row of the ground-truth table; MS1 scans leave the precursor columns empty
+fn write_truth_row(
+    out: &mut impl Write,
+    scan: &ScanMessage,
+    precursor_scan: Option<i32>,
+) -> io::Result<()> {
+    let optional = |value: Option<String>| value.unwrap_or_default();
+    let msn = scan.ms_order > 1;
+    let fragmentation = match scan.fragmentation_type() {
+        FragmentationType::FragmentationUnknown => String::new(),
+        fragmentation => fragmentation
+            .as_str_name()
+            .trim_start_matches("FRAGMENTATION_")
+            .to_string(),
+    };
+    let row = [
+        scan.scan_number.to_string(),
+        scan.ms_order.to_string(),
+        scan.retention_time.to_string(),
+        scan.timestamp_ms.to_string(),
+        optional(precursor_scan.filter(|_| msn).map(|scan| scan.to_string())),
+        optional(scan.precursor_mass.map(|mz| mz.to_string())),
+        optional(scan.precursor_charge.map(|charge| charge.to_string())),
+        optional(
+            scan.precursor_intensity
+                .map(|intensity| intensity.to_string()),
+        ),
+        optional(scan.isolation_width.map(|width| width.to_string())),
+        optional(scan.collision_energy.map(|energy| energy.to_string())),
+        fragmentation,
+        scan.analyzer.clone(),
+        scan.resolution_at_mz200.to_string(),
+        scan.mz_values.len().to_string(),
+        scan.base_peak_mz.to_string(),
+        scan.base_peak_intensity.to_string(),
+        scan.total_ion_current.to_string(),
+    ];
+    writeln!(out, "{}", row.join("\t"))
+}
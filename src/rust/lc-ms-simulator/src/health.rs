@@ -0,0 +1,94 @@
+use std::time::Duration;
+
+use tonic::server::NamedService;
+use tonic_health::server::HealthReporter;
+use tonic_health::ServingStatus;
+use tracing::{info, warn};
+
+use crate::fleet::Fleet;
+use crate::proto::simulator_service_server::SimulatorServiceServer;
+use crate::proto::AcquisitionState;
+use crate::service::SimulatorServiceImpl;
+
+/// How often readiness is checked between acquisition state changes, since pumping
+/// down and warming up end by time passing
+const READINESS_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Keeps the standard `grpc.health.v1.Health` service in sync with the simulator.
+///
+/// An instrument reports SERVING while it can accept work: its power is Ready and it is
+/// not Faulted. Each instrument reports under `SimulatorService/<instrument id>`, and
+/// the overall server status (empty service name) and `SimulatorService` follow the
+/// default instrument.
+pub fn report_health(reporter: HealthReporter, fleet: &Fleet) {
+    for (index, instrument) in fleet.instruments().iter().enumerate() {
+        tokio::spawn(report_instrument_health(
+            reporter.clone(),
+            instrument.clone(),
+            index == 0,
+        ));
+    }
+}
+
+/// Service name under which an instrument reports its own status
+fn instrument_service_name(instrument_id: &str) -> String {
+    format!(
+        "{}/{}",
+        <SimulatorServiceServer<Fleet> as NamedService>::NAME,
+        instrument_id
+    )
+}
+
+async fn report_instrument_health(
+    mut reporter: HealthReporter,
+    instrument: SimulatorServiceImpl,
+    is_default: bool,
+) {
+    let service_name = instrument_service_name(instrument.instrument_id());
+    let mut state = instrument.subscribe_state();
+    let mut reported = None;
+
+    loop {
+        let readiness = instrument.readiness();
+        let (status, reason) = match *state.borrow_and_update() {
+            AcquisitionState::Faulted => (ServingStatus::NotServing, "simulator faulted"),
+            _ if !readiness.is_ready() => (ServingStatus::NotServing, "instrument not ready"),
+            _ => (ServingStatus::Serving, ""),
+        };
+
+        if reported != Some(status) {
+            reporter.set_service_status(&service_name, status).await;
+            if is_default {
+                reporter.set_service_status("", status).await;
+                if status == ServingStatus::Serving {
+                    reporter
+                        .set_serving::<SimulatorServiceServer<Fleet>>()
+                        .await;
+                } else {
+                    reporter
+                        .set_not_serving::<SimulatorServiceServer<Fleet>>()
+                        .await;
+                }
+            }
+            if status == ServingStatus::Serving {
+                info!("Health status of {}: SERVING", instrument.instrument_id());
+            } else {
+                warn!(
+                    "Health status of {}: NOT_SERVING ({})",
+                    instrument.instrument_id(),
+                    reason
+                );
+            }
+            reported = Some(status);
+        }
+
+        tokio::select! {
+            changed = state.changed() => {
+                if changed.is_err() {
+                    break;
+                }
+            }
+            _ = tokio::time::sleep(READINESS_POLL_INTERVAL) => {}
+        }
+    }
+}
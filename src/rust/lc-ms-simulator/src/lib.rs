@@ -0,0 +1,12 @@
+//! Protocol types and a Rust client for the LC-MS Orbitrap simulator.
+//!
+//! The server is the `lc-ms-simulator` binary; `lc-ms-client` is a command line front end
+//! to [`client::SimulatorClient`]. [`stream_log`] reads and writes stream logs, the
+//! recorded event streams that the server can replay.
+
+// tonic::Status is large by design; helpers returning it are idiomatic in gRPC services.
+#![allow(clippy::result_large_err)]
+
+pub mod client;
+pub mod proto;
+pub mod stream_log;
@@ -1,85 +1,503 @@
+// tonic::Status is large by design; helpers returning it are idiomatic in gRPC services.
+#![allow(clippy::result_large_err)]
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
 use anyhow::Result;
-use clap::Parser;
+use clap::{Parser, Subcommand};
+use lc_ms_simulator::{proto, stream_log};
+use tokio::net::TcpListener;
+use tokio::sync::watch;
+use tokio::time::Instant;
+use tonic::codec::CompressionEncoding;
+use tonic::service::interceptor::InterceptedService;
 use tonic::transport::Server;
-use tracing::{info, Level};
-use tracing_subscriber::FmtSubscriber;
+use tracing::{info, warn};
 
-mod proto;
+mod auth;
+mod batching;
+mod config;
+mod encoding;
+mod faults;
+mod fleet;
+mod generate;
+mod health;
+mod malformed;
+mod metrics;
+mod mzml;
+mod network;
+mod power;
+mod profiles;
+mod recording;
+mod replay;
+mod rest;
 mod service;
+mod shutdown;
 mod simulator;
+mod status_log;
+mod telemetry;
+mod tls;
+mod tune;
+mod web;
 
+use auth::AuthInterceptor;
+use config::{Compression, Settings};
+use fleet::Fleet;
+use generate::GenerateArgs;
+use metrics::MetricsRegistry;
+use power::PowerMode;
+use profiles::InstrumentModel;
 use service::SimulatorServiceImpl;
+use telemetry::LogFormat;
+use web::{Cors, GrpcWebLayer};
 
 /// LC-MS Orbitrap Simulator
 ///
 /// A gRPC server that simulates an Orbitrap mass spectrometer
 /// for development and testing of proteomics software.
+///
+/// Flags override the config file and LCMS_* environment variables.
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Args {
-    /// Port to listen on
-    #[arg(short, long, default_value_t = 31417)]
-    port: u16,
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// TOML, YAML or JSON config file
+    #[arg(short, long, env = config::CONFIG_PATH_ENV)]
+    config: Option<PathBuf>,
+
+    /// Port to listen on [default: 31417]
+    #[arg(short, long)]
+    port: Option<u16>,
+
+    /// Host address to bind to [default: 0.0.0.0]
+    #[arg(short = 'H', long)]
+    host: Option<String>,
+
+    /// Log filter: a level (trace, debug, info, warn, error) or RUST_LOG-style directives,
+    /// e.g. "info,lc_ms_simulator::service=debug" [default: info]
+    #[arg(short, long, env = "RUST_LOG")]
+    log_level: Option<String>,
+
+    /// Log output format [default: text]
+    #[arg(long, value_enum)]
+    log_format: Option<LogFormat>,
+
+    /// OTLP/gRPC endpoint for trace export, e.g. http://jaeger:4317 (disabled when unset)
+    #[arg(long, env = "OTEL_EXPORTER_OTLP_ENDPOINT")]
+    otlp_endpoint: Option<String>,
+
+    /// Instrument model to emulate; sets the capabilities reported and enforced
+    /// [default: exploris-480]
+    #[arg(long, value_enum)]
+    instrument_profile: Option<InstrumentModel>,
+
+    /// Instrument name to report [default: Simulated <model>]
+    #[arg(long)]
+    instrument_name: Option<String>,
+
+    /// Instrument ID to report [default: SIM-001]
+    #[arg(long)]
+    instrument_id: Option<String>,
+
+    /// Number of identical instruments to host, with IDs numbered from --instrument-id
+    /// [default: 1]
+    #[arg(long)]
+    instrument_count: Option<usize>,
+
+    /// Power mode at startup; only an instrument that starts on is ready at once
+    /// [default: on]
+    #[arg(long, value_enum)]
+    power_mode: Option<PowerMode>,
+
+    /// Seconds from switching on to ready [default: 10]
+    #[arg(long)]
+    warm_up_seconds: Option<f64>,
+
+    /// Directory session recordings are written to [default: recordings]
+    #[arg(long)]
+    recording_dir: Option<PathBuf>,
+
+    /// Directory replayed runs are read from [default: replays]
+    #[arg(long)]
+    replay_dir: Option<PathBuf>,
+
+    /// PEM certificate chain; serves gRPC over TLS together with --tls-key
+    #[arg(long)]
+    tls_cert: Option<PathBuf>,
+
+    /// PEM private key for --tls-cert
+    #[arg(long)]
+    tls_key: Option<PathBuf>,
+
+    /// PEM CA bundle for verifying client certificates (enables mutual TLS)
+    #[arg(long)]
+    tls_client_ca: Option<PathBuf>,
+
+    /// Accepted token as <role>:<token>, role is observer, controller or admin (repeatable).
+    /// Without tokens, authentication is disabled.
+    #[arg(long = "auth-token", value_name = "ROLE:TOKEN")]
+    auth_tokens: Vec<String>,
+
+    /// File with one <role>:<token> per line ('#' starts a comment)
+    #[arg(long)]
+    auth_token_file: Option<PathBuf>,
+
+    /// Response compression offered to clients that advertise it in grpc-accept-encoding.
+    /// gzip is opt-in because common clients (e.g. Grpc.Net.Client) advertise it by default.
+    /// [default: zstd]
+    #[arg(long, value_enum, value_delimiter = ',')]
+    compression: Vec<Compression>,
+
+    /// Port for the Prometheus /metrics endpoint (0 disables it) [default: 9100]
+    #[arg(long)]
+    metrics_port: Option<u16>,
+
+    /// Accept gRPC-Web from browser clients (and HTTP/1.1) on the gRPC port
+    #[arg(long)]
+    grpc_web: bool,
+
+    /// Port for the REST/JSON gateway (0 disables it) [default: 0]
+    #[arg(long)]
+    rest_port: Option<u16>,
+
+    /// Origin browsers may call gRPC-Web and REST from (repeatable, or comma-separated)
+    /// [default: *]
+    #[arg(long = "allowed-origin", value_delimiter = ',')]
+    allowed_origins: Vec<String>,
 
-    /// Host address to bind to
-    #[arg(short = 'H', long, default_value = "0.0.0.0")]
-    host: String,
+    /// Seconds to let a running acquisition stop and subscribers drain after SIGINT/SIGTERM
+    /// [default: 5]
+    #[arg(long)]
+    shutdown_grace_seconds: Option<f64>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Write an acquisition to mzML, MGF and a ground-truth table without serving it,
+    /// on a simulated clock. Server flags before the subcommand select the instrument
+    /// and defaults.
+    Generate(GenerateArgs),
+}
 
-    /// Log level (trace, debug, info, warn, error)
-    #[arg(short, long, default_value = "info")]
-    log_level: String,
+impl Args {
+    /// Overrides file and environment settings with the flags that were given
+    fn apply(self, settings: &mut Settings) {
+        let server = &mut settings.server;
+        if let Some(port) = self.port {
+            server.port = port;
+        }
+        if let Some(host) = self.host {
+            server.host = host;
+        }
+        if let Some(cert) = self.tls_cert {
+            server.tls.cert = Some(cert);
+        }
+        if let Some(key) = self.tls_key {
+            server.tls.key = Some(key);
+        }
+        if let Some(client_ca) = self.tls_client_ca {
+            server.tls.client_ca = Some(client_ca);
+        }
+        if !self.auth_tokens.is_empty() {
+            server.auth.tokens = self.auth_tokens;
+        }
+        if let Some(token_file) = self.auth_token_file {
+            server.auth.token_file = Some(token_file);
+        }
+        if !self.compression.is_empty() {
+            server.compression = self.compression;
+        }
+        if let Some(metrics_port) = self.metrics_port {
+            server.metrics_port = metrics_port;
+        }
+        if self.grpc_web {
+            server.web.grpc_web = true;
+        }
+        if let Some(rest_port) = self.rest_port {
+            server.web.rest_port = rest_port;
+        }
+        if !self.allowed_origins.is_empty() {
+            server.web.allowed_origins = self.allowed_origins;
+        }
+        if let Some(grace) = self.shutdown_grace_seconds {
+            server.shutdown_grace_seconds = grace;
+        }
 
-    /// Instrument name to report
-    #[arg(long, default_value = "Simulated Orbitrap Exploris 480")]
-    instrument_name: String,
+        let logging = &mut settings.logging;
+        if let Some(level) = self.log_level {
+            logging.level = level;
+        }
+        if let Some(format) = self.log_format {
+            logging.format = format;
+        }
+        if let Some(endpoint) = self.otlp_endpoint {
+            logging.otlp_endpoint = Some(endpoint);
+        }
 
-    /// Instrument ID to report
-    #[arg(long, default_value = "SIM-001")]
-    instrument_id: String,
+        if let Some(profile) = self.instrument_profile {
+            settings.instrument.profile = profile;
+        }
+        if let Some(name) = self.instrument_name {
+            settings.instrument.name = Some(name);
+        }
+        if let Some(id) = self.instrument_id {
+            settings.instrument.id = id;
+        }
+        if let Some(count) = self.instrument_count {
+            settings.instrument.count = count;
+        }
+        if let Some(mode) = self.power_mode {
+            settings.power.mode = mode;
+        }
+        if let Some(seconds) = self.warm_up_seconds {
+            settings.power.warm_up_seconds = seconds;
+        }
+        if let Some(directory) = self.recording_dir {
+            settings.recording.directory = directory;
+        }
+        if let Some(directory) = self.replay_dir {
+            settings.replay.directory = directory;
+        }
+    }
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    let args = Args::parse();
-
-    // Initialize logging
-    let log_level = match args.log_level.to_lowercase().as_str() {
-        "trace" => Level::TRACE,
-        "debug" => Level::DEBUG,
-        "info" => Level::INFO,
-        "warn" => Level::WARN,
-        "error" => Level::ERROR,
-        _ => Level::INFO,
-    };
+    let mut args = Args::parse();
+    let command = args.command.take();
+    let mut settings = Settings::load(args.config.as_deref())?;
+    args.apply(&mut settings);
+    settings.validate()?;
 
-    let subscriber = FmtSubscriber::builder()
-        .with_max_level(log_level)
-        .with_target(true)
-        .with_thread_ids(true)
-        .with_file(true)
-        .with_line_number(true)
-        .finish();
-
-    tracing::subscriber::set_global_default(subscriber)
-        .expect("Failed to set tracing subscriber");
-
-    // Create the simulator service
-    let service = SimulatorServiceImpl::new(
-        args.instrument_name.clone(),
-        args.instrument_id.clone(),
+    let instruments = settings.instruments();
+
+    // Initialize logging and trace export; the guard flushes pending spans on exit
+    let _telemetry = telemetry::init(
+        &settings.logging.level,
+        settings.logging.format,
+        settings.logging.otlp_endpoint.as_deref(),
+        &instruments[0].id,
+    )?;
+    if let Some(Command::Generate(generate)) = command {
+        return generate::run(&settings, generate);
+    }
+    info!("Effective configuration: {}", settings.to_redacted_json());
+    if !settings.faults.is_empty() {
+        warn!("Fault injection enabled: {:?}", settings.faults);
+    }
+
+    // Create one simulator per instrument; they share only the metrics registry
+    let metrics = Arc::new(MetricsRegistry::new(service::SCAN_QUEUE_CAPACITY));
+    let fleet = Fleet::new(
+        instruments
+            .iter()
+            .map(|instrument| {
+                SimulatorServiceImpl::new(
+                    instrument.clone(),
+                    settings.power.clone(),
+                    settings.simulation.clone(),
+                    settings.method.clone(),
+                    settings.status_log.clone(),
+                    settings.recording.clone(),
+                    settings.replay.clone(),
+                    settings.faults.clone(),
+                    metrics.instrument(&instrument.id),
+                )
+            })
+            .collect(),
     );
 
-    let addr = format!("{}:{}", args.host, args.port).parse()?;
+    let server = &settings.server;
+    let mut tokens = HashMap::new();
+    if let Some(path) = &server.auth.token_file {
+        tokens.extend(AuthInterceptor::load_token_file(path)?);
+    }
+    for spec in &server.auth.tokens {
+        let (token, role) = AuthInterceptor::parse_token_spec(spec)?;
+        tokens.insert(token, role);
+    }
+    let auth = AuthInterceptor::new(tokens);
+
+    // Standard health checking, driven by each instrument's power and acquisition state
+    let (health_reporter, health_service) = tonic_health::server::health_reporter();
+    health::report_health(health_reporter, &fleet);
+
+    // Server reflection so tools like grpcurl work without the proto file
+    let reflection_service = tonic_reflection::server::Builder::configure()
+        .register_encoded_file_descriptor_set(proto::FILE_DESCRIPTOR_SET)
+        .register_encoded_file_descriptor_set(tonic_health::pb::FILE_DESCRIPTOR_SET)
+        .build_v1()?;
+    let reflection_service_v1alpha = tonic_reflection::server::Builder::configure()
+        .register_encoded_file_descriptor_set(proto::FILE_DESCRIPTOR_SET)
+        .register_encoded_file_descriptor_set(tonic_health::pb::FILE_DESCRIPTOR_SET)
+        .build_v1alpha()?;
+
+    let shutdown_fleet = fleet.clone();
+    let rest_fleet = fleet.clone();
+
+    // Compressed requests are always accepted; responses are compressed only with the
+    // configured encodings, and only for clients that advertise them.
+    let mut simulator_service =
+        proto::simulator_service_server::SimulatorServiceServer::new(fleet)
+            .accept_compressed(CompressionEncoding::Gzip)
+            .accept_compressed(CompressionEncoding::Zstd);
+    for compression in &server.compression {
+        simulator_service = simulator_service.send_compressed((*compression).into());
+    }
+
+    let addr = format!("{}:{}", server.host, server.port).parse()?;
 
     info!("Starting LC-MS Simulator gRPC server");
-    info!("  Instrument: {}", args.instrument_name);
-    info!("  ID: {}", args.instrument_id);
+    for instrument in &instruments {
+        info!(
+            "  Instrument: {} ({}), ID {}",
+            instrument.name(),
+            instrument.profile.profile().model,
+            instrument.id
+        );
+    }
     info!("  Listening on: {}", addr);
+    if let Some(endpoint) = &settings.logging.otlp_endpoint {
+        info!("  Trace export: {}", endpoint);
+    }
+    info!(
+        "  Authentication: {}",
+        if auth.is_enabled() { "token" } else { "disabled" }
+    );
+
+    let cors = Arc::new(Cors::new(&server.web.allowed_origins));
+    if server.web.grpc_web {
+        info!("  gRPC-Web: enabled");
+    }
+
+    if server.metrics_port != 0 {
+        let metrics_addr: SocketAddr =
+            format!("{}:{}", server.host, server.metrics_port).parse()?;
+        info!("  Metrics: http://{}/metrics", metrics_addr);
+        tokio::spawn(async move {
+            if let Err(e) = metrics::serve(metrics, metrics_addr).await {
+                tracing::error!("Metrics endpoint failed: {}", e);
+            }
+        });
+    }
 
-    Server::builder()
-        .add_service(proto::simulator_service_server::SimulatorServiceServer::new(service))
-        .serve(addr)
-        .await?;
+    // On SIGINT/SIGTERM: stop the acquisition, let subscribers drain, then stop serving.
+    // The REST gateway stops accepting at once and finishes its requests in flight.
+    // Connections still open when the grace period ends are dropped.
+    let grace = Duration::from_secs_f64(server.shutdown_grace_seconds);
+    let (deadline_tx, deadline_rx) = watch::channel(None);
+    let shutdown_signal = async move {
+        shutdown::signal().await;
+        let deadline = Instant::now() + grace;
+        info!("Shutdown requested, draining for up to {:?}", grace);
+        deadline_tx.send_replace(Some(deadline));
+        shutdown_fleet.shutdown(deadline).await;
+    };
+
+    let tls = &server.tls;
+    let rest = if server.web.rest_port != 0 {
+        let rest_addr: SocketAddr = format!("{}:{}", server.host, server.web.rest_port).parse()?;
+        let acceptor = match (&tls.cert, &tls.key) {
+            (Some(cert), Some(key)) => {
+                info!("  REST gateway: https://{}/api/v1", rest_addr);
+                Some(tls::load_acceptor(cert, key, tls.client_ca.as_deref(), true)?)
+            }
+            _ => {
+                info!("  REST gateway: http://{}/api/v1", rest_addr);
+                None
+            }
+        };
+        let (auth, cors) = (auth.clone(), Arc::clone(&cors));
+        let shutdown_requested = shutdown_requested(deadline_rx.clone());
+        Some(tokio::spawn(async move {
+            if let Err(e) =
+                rest::serve(rest_fleet, auth, cors, rest_addr, acceptor, shutdown_requested).await
+            {
+                tracing::error!("REST gateway failed: {}", e);
+            }
+        }))
+    } else {
+        None
+    };
+
+    // Browsers speak gRPC-Web over HTTP/1.1; the layer passes plain gRPC through
+    let router = Server::builder()
+        .accept_http1(server.web.grpc_web)
+        .layer(GrpcWebLayer::new(server.web.grpc_web, cors))
+        .trace_fn(telemetry::grpc_span)
+        .add_service(health_service)
+        .add_service(reflection_service)
+        .add_service(reflection_service_v1alpha)
+        .add_service(InterceptedService::new(simulator_service, auth));
+
+    match (&tls.cert, &tls.key) {
+        (Some(cert), Some(key)) => {
+            let acceptor = tls::load_acceptor(cert, key, tls.client_ca.as_deref(), server.web.grpc_web)?;
+            info!(
+                "  Transport: TLS{}",
+                if tls.client_ca.is_some() { " (client certificates required)" } else { "" }
+            );
+
+            let listener = TcpListener::bind(addr).await?;
+            let incoming = tls::incoming(listener, acceptor);
+            serve_until_drained(
+                router.serve_with_incoming_shutdown(incoming, shutdown_signal),
+                grace_elapsed(deadline_rx.clone()),
+            )
+            .await?;
+        }
+        _ => {
+            info!("  Transport: plaintext");
+            serve_until_drained(
+                router.serve_with_shutdown(addr, shutdown_signal),
+                grace_elapsed(deadline_rx.clone()),
+            )
+            .await?;
+        }
+    }
+    if let Some(rest) = rest {
+        tokio::select! {
+            _ = rest => {}
+            _ = grace_elapsed(deadline_rx) => {
+                warn!("Shutdown grace period elapsed, closing remaining REST connections")
+            }
+        }
+    }
+
+    info!("Shutdown complete");
+    Ok(())
+}
+
+/// Resolves once a shutdown has been requested
+async fn shutdown_requested(mut deadline: watch::Receiver<Option<Instant>>) {
+    if deadline.wait_for(Option::is_some).await.is_err() {
+        std::future::pending().await
+    }
+}
+
+/// Resolves when the shutdown grace period runs out
+async fn grace_elapsed(mut deadline: watch::Receiver<Option<Instant>>) {
+    match deadline.wait_for(Option::is_some).await.map(|deadline| *deadline) {
+        Ok(Some(deadline)) => tokio::time::sleep_until(deadline).await,
+        _ => std::future::pending().await,
+    }
+}
 
+/// Runs the server until it stops on its own or the shutdown grace period runs out
+async fn serve_until_drained(
+    serve: impl Future<Output = Result<(), tonic::transport::Error>>,
+    grace_elapsed: impl Future<Output = ()>,
+) -> Result<()> {
+    tokio::select! {
+        result = serve => result?,
+        _ = grace_elapsed => warn!("Shutdown grace period elapsed, closing remaining connections"),
+    }
     Ok(())
 }
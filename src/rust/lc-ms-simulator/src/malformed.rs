@@ -0,0 +1,200 @@
+//! Malformed scan data for exercising client-side validation.
+//!
+//! Each defect kind has its own per-scan probability. Defects are applied to the
+//! published copy of a scan only, so precursor selection and session bookkeeping see
+//! the clean data, and every corrupted scan lists its defects in `trailer_extra`.
+
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
+
+use crate::proto::{self, ScanMessage};
+
+/// `trailer_extra` key listing the defects injected into a scan
+pub const DEFECTS_TRAILER_KEY: &str = "Injected Defects";
+
+/// Per-scan probabilities (0.0-1.0) of each kind of defect
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MalformedData {
+    pub nan_intensity: f64,
+    pub negative_intensity: f64,
+    pub unsorted_mz: f64,
+    pub duplicate_mz: f64,
+    pub mismatched_lengths: f64,
+    pub empty_scan: f64,
+    pub out_of_order_scan_number: f64,
+    pub repeated_scan_number: f64,
+    pub missing_precursor: f64,
+    pub non_monotonic_retention_time: f64,
+    /// Seed for reproducible defects; unset seeds from entropy
+    pub seed: Option<u64>,
+}
+
+impl MalformedData {
+    fn probabilities(&self) -> [(&'static str, f64); 10] {
+        [
+            ("nan_intensity", self.nan_intensity),
+            ("negative_intensity", self.negative_intensity),
+            ("unsorted_mz", self.unsorted_mz),
+            ("duplicate_mz", self.duplicate_mz),
+            ("mismatched_lengths", self.mismatched_lengths),
+            ("empty_scan", self.empty_scan),
+            ("out_of_order_scan_number", self.out_of_order_scan_number),
+            ("repeated_scan_number", self.repeated_scan_number),
+            ("missing_precursor", self.missing_precursor),
+            (
+                "non_monotonic_retention_time",
+                self.non_monotonic_retention_time,
+            ),
+        ]
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        for (name, probability) in self.probabilities() {
+            if !(0.0..=1.0).contains(&probability) {
+                return Err(format!(
+                    "malformed_data.{} must be a probability between 0 and 1",
+                    name
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Applies [`MalformedData`] defects to scans of one session
+pub struct ScanCorruptor {
+    /// Decides which defects hit; draws a fixed count per scan so a seed replays the
+    /// same defect sequence regardless of peak counts
+    selection: StdRng,
+    /// Picks the peaks and offsets a defect affects
+    random: StdRng,
+}
+
+impl ScanCorruptor {
+    pub fn new(seed: Option<u64>) -> Self {
+        let mut selection = match seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_entropy(),
+        };
+        let random = StdRng::seed_from_u64(selection.gen());
+        Self { selection, random }
+    }
+
+    /// Rolls for every defect kind and applies those that hit and fit the scan.
+    /// An emptied scan skips the peak-level defects.
+    pub fn corrupt(&mut self, config: &MalformedData, scan: &mut ScanMessage) {
+        let [nan_intensity, negative_intensity, unsorted_mz, duplicate_mz, mismatched_lengths, empty_scan, out_of_order_scan_number, repeated_scan_number, missing_precursor, non_monotonic_retention_time] =
+            config
+                .probabilities()
+                .map(|(_, probability)| self.roll(probability));
+        let mut applied = Vec::new();
+
+        if empty_scan {
+            scan.mz_values.clear();
+            scan.intensity_values.clear();
+            scan.base_peak_mz = 0.0;
+            scan.base_peak_intensity = 0.0;
+            scan.total_ion_current = 0.0;
+            applied.push("empty_scan");
+        }
+
+        let peaks = scan.mz_values.len().min(scan.intensity_values.len());
+        if nan_intensity && peaks > 0 {
+            for _ in 0..self.random.gen_range(1..=peaks.min(3)) {
+                let index = self.random.gen_range(0..peaks);
+                scan.intensity_values[index] = f64::NAN;
+            }
+            applied.push("nan_intensity");
+        }
+        if negative_intensity && peaks > 0 {
+            for _ in 0..self.random.gen_range(1..=peaks.min(3)) {
+                let index = self.random.gen_range(0..peaks);
+                scan.intensity_values[index] = -scan.intensity_values[index].abs().max(1.0);
+            }
+            applied.push("negative_intensity");
+        }
+        if unsorted_mz && peaks > 1 {
+            let index = self.random.gen_range(0..peaks - 1);
+            scan.mz_values.swap(index, index + 1);
+            scan.intensity_values.swap(index, index + 1);
+            applied.push("unsorted_mz");
+        }
+        if duplicate_mz && peaks > 0 {
+            let index = self.random.gen_range(0..peaks);
+            scan.mz_values.insert(index + 1, scan.mz_values[index]);
+            scan.intensity_values
+                .insert(index + 1, scan.intensity_values[index]);
+            applied.push("duplicate_mz");
+        }
+        if mismatched_lengths && !scan.intensity_values.is_empty() {
+            scan.intensity_values.pop();
+            applied.push("mismatched_lengths");
+        }
+
+        if out_of_order_scan_number && scan.scan_number > 2 {
+            scan.scan_number -= self.random.gen_range(2..=(scan.scan_number - 1).min(10));
+            applied.push("out_of_order_scan_number");
+        } else if repeated_scan_number && scan.scan_number > 1 {
+            scan.scan_number -= 1;
+            applied.push("repeated_scan_number");
+        }
+
+        if missing_precursor && scan.ms_order > 1 {
+            scan.precursor_mass = None;
+            scan.precursor_charge = None;
+            scan.precursor_intensity = None;
+            applied.push("missing_precursor");
+        }
+        if non_monotonic_retention_time {
+            scan.retention_time -= self.random.gen_range(0.01..0.5);
+            applied.push("non_monotonic_retention_time");
+        }
+
+        if !applied.is_empty() {
+            scan.trailer_extra
+                .insert(DEFECTS_TRAILER_KEY.to_string(), applied.join(","));
+        }
+    }
+
+    fn roll(&mut self, probability: f64) -> bool {
+        self.selection.gen_bool(probability.clamp(0.0, 1.0))
+    }
+}
+
+impl From<proto::MalformedData> for MalformedData {
+    fn from(data: proto::MalformedData) -> Self {
+        Self {
+            nan_intensity: data.nan_intensity,
+            negative_intensity: data.negative_intensity,
+            unsorted_mz: data.unsorted_mz,
+            duplicate_mz: data.duplicate_mz,
+            mismatched_lengths: data.mismatched_lengths,
+            empty_scan: data.empty_scan,
+            out_of_order_scan_number: data.out_of_order_scan_number,
+            repeated_scan_number: data.repeated_scan_number,
+            missing_precursor: data.missing_precursor,
+            non_monotonic_retention_time: data.non_monotonic_retention_time,
+            seed: data.seed,
+        }
+    }
+}
+
+impl From<MalformedData> for proto::MalformedData {
+    fn from(data: MalformedData) -> Self {
+        Self {
+            nan_intensity: data.nan_intensity,
+            negative_intensity: data.negative_intensity,
+            unsorted_mz: data.unsorted_mz,
+            duplicate_mz: data.duplicate_mz,
+            mismatched_lengths: data.mismatched_lengths,
+            empty_scan: data.empty_scan,
+            out_of_order_scan_number: data.out_of_order_scan_number,
+            repeated_scan_number: data.repeated_scan_number,
+            missing_precursor: data.missing_precursor,
+            non_monotonic_retention_time: data.non_monotonic_retention_time,
+            seed: data.seed,
+        }
+    }
+}
@@ -0,0 +1,407 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use axum::extract::State;
+use axum::http::header;
+use axum::response::IntoResponse;
+use axum::routing::get;
+use axum::Router;
+use prometheus_client::encoding::{text, EncodeLabelSet};
+use prometheus_client::metrics::counter::Counter;
+use prometheus_client::metrics::family::Family;
+use prometheus_client::metrics::gauge::Gauge;
+use prometheus_client::metrics::histogram::{exponential_buckets, Histogram};
+use prometheus_client::registry::Registry;
+use tokio::net::TcpListener;
+use tracing::info;
+
+use crate::proto::AcquisitionState;
+
+const ACQUISITION_STATES: [AcquisitionState; 7] = [
+    AcquisitionState::Idle,
+    AcquisitionState::Starting,
+    AcquisitionState::Acquiring,
+    AcquisitionState::Paused,
+    AcquisitionState::Stopping,
+    AcquisitionState::Completed,
+    AcquisitionState::Faulted,
+];
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct InstrumentLabels {
+    instrument_id: &'static str,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct MsOrderLabels {
+    instrument_id: &'static str,
+    ms_order: i32,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct RpcLabels {
+    instrument_id: &'static str,
+    rpc: &'static str,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct SubscriberLabels {
+    instrument_id: &'static str,
+    rpc: &'static str,
+    subscriber: u64,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct StateLabels {
+    instrument_id: &'static str,
+    state: String,
+}
+
+struct SubscriberState {
+    rpc: &'static str,
+    /// Events published before this subscriber joined
+    published_at_start: u64,
+    /// Events delivered or dropped so far
+    consumed: AtomicU64,
+}
+
+/// Metric families shared by all instruments; cloning shares the underlying metrics
+#[derive(Clone)]
+struct Families {
+    scans_generated: Family<MsOrderLabels, Counter>,
+    generation_seconds: Family<MsOrderLabels, Histogram>,
+    queue_depth: Family<InstrumentLabels, Gauge>,
+    active_subscribers: Family<RpcLabels, Gauge>,
+    subscriber_lag: Family<SubscriberLabels, Gauge>,
+    subscriber_dropped: Family<SubscriberLabels, Counter>,
+    dropped: Family<RpcLabels, Counter>,
+    acquisition_state: Family<StateLabels, Gauge>,
+    bytes_sent: Family<RpcLabels, Counter>,
+}
+
+/// Prometheus metrics for every hosted instrument, served at `/metrics`
+pub struct MetricsRegistry {
+    registry: Registry,
+    families: Families,
+    queue_capacity: u64,
+    instruments: Mutex<Vec<Arc<Metrics>>>,
+}
+
+impl MetricsRegistry {
+    /// `queue_capacity` is the broadcast channel capacity; per-subscriber lag never exceeds it.
+    pub fn new(queue_capacity: usize) -> Self {
+        let mut registry = Registry::with_prefix("orbitrap_simulator");
+
+        let scans_generated = Family::<MsOrderLabels, Counter>::default();
+        registry.register(
+            "scans_generated",
+            "Scans generated by the acquisition loop",
+            scans_generated.clone(),
+        );
+
+        let generation_seconds = Family::<MsOrderLabels, Histogram>::new_with_constructor(|| {
+            Histogram::new(exponential_buckets(1e-6, 2.0, 20))
+        });
+        registry.register(
+            "scan_generation_seconds",
+            "Time to generate a single scan",
+            generation_seconds.clone(),
+        );
+
+        let queue_depth = Family::<InstrumentLabels, Gauge>::default();
+        registry.register(
+            "broadcast_queue_depth",
+            "Events held in the broadcast queue for the slowest subscriber",
+            queue_depth.clone(),
+        );
+
+        let active_subscribers = Family::<RpcLabels, Gauge>::default();
+        registry.register(
+            "active_subscribers",
+            "Connected streaming subscribers",
+            active_subscribers.clone(),
+        );
+
+        let subscriber_lag = Family::<SubscriberLabels, Gauge>::default();
+        registry.register(
+            "subscriber_lag",
+            "Events published but not yet consumed by a subscriber",
+            subscriber_lag.clone(),
+        );
+
+        let subscriber_dropped = Family::<SubscriberLabels, Counter>::default();
+        registry.register(
+            "subscriber_dropped",
+            "Events dropped for a connected subscriber because it fell behind",
+            subscriber_dropped.clone(),
+        );
+
+        let dropped = Family::<RpcLabels, Counter>::default();
+        registry.register(
+            "dropped",
+            "Events dropped for lagging subscribers, including disconnected ones",
+            dropped.clone(),
+        );
+
+        let acquisition_state = Family::<StateLabels, Gauge>::default();
+        registry.register(
+            "acquisition_state",
+            "Current acquisition state (1 for the active state)",
+            acquisition_state.clone(),
+        );
+
+        let bytes_sent = Family::<RpcLabels, Counter>::default();
+        registry.register(
+            "bytes_sent",
+            "Protobuf-encoded bytes sent to subscribers, before transport compression",
+            bytes_sent.clone(),
+        );
+
+        Self {
+            registry,
+            families: Families {
+                scans_generated,
+                generation_seconds,
+                queue_depth,
+                active_subscribers,
+                subscriber_lag,
+                subscriber_dropped,
+                dropped,
+                acquisition_state,
+                bytes_sent,
+            },
+            queue_capacity: queue_capacity as u64,
+            instruments: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Creates the metrics of one instrument, labelled with its ID
+    pub fn instrument(&self, instrument_id: &str) -> Arc<Metrics> {
+        // Instruments live as long as the process, so the label can be static
+        let instrument_id: &'static str = Box::leak(instrument_id.to_string().into_boxed_str());
+        let metrics = Arc::new(Metrics {
+            families: self.families.clone(),
+            instrument_id,
+            queue_capacity: self.queue_capacity,
+            events_published: AtomicU64::new(0),
+            next_subscriber_id: AtomicU64::new(1),
+            subscribers: Mutex::new(HashMap::new()),
+        });
+        metrics.set_acquisition_state(AcquisitionState::Idle);
+
+        self.instruments
+            .lock()
+            .expect("instrument registry poisoned")
+            .push(Arc::clone(&metrics));
+        metrics
+    }
+
+    /// Renders all metrics in the OpenMetrics text format
+    pub fn encode(&self) -> String {
+        for metrics in self
+            .instruments
+            .lock()
+            .expect("instrument registry poisoned")
+            .iter()
+        {
+            metrics.refresh_subscriber_lag();
+        }
+
+        let mut body = String::new();
+        text::encode(&mut body, &self.registry).expect("writing to a String cannot fail");
+        body
+    }
+}
+
+/// Metrics of one instrument
+pub struct Metrics {
+    families: Families,
+    instrument_id: &'static str,
+    queue_capacity: u64,
+    events_published: AtomicU64,
+    next_subscriber_id: AtomicU64,
+    subscribers: Mutex<HashMap<u64, Arc<SubscriberState>>>,
+}
+
+impl Metrics {
+    pub fn record_scan_generated(&self, ms_order: i32, elapsed: Duration) {
+        let labels = MsOrderLabels {
+            instrument_id: self.instrument_id,
+            ms_order,
+        };
+        self.families.scans_generated.get_or_create(&labels).inc();
+        self.families
+            .generation_seconds
+            .get_or_create(&labels)
+            .observe(elapsed.as_secs_f64());
+    }
+
+    pub fn record_event_published(&self) {
+        self.events_published.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn set_acquisition_state(&self, current: AcquisitionState) {
+        for state in ACQUISITION_STATES {
+            let labels = StateLabels {
+                instrument_id: self.instrument_id,
+                state: format!("{:?}", state).to_lowercase(),
+            };
+            self.families
+                .acquisition_state
+                .get_or_create(&labels)
+                .set(i64::from(state == current));
+        }
+    }
+
+    /// Registers a streaming subscriber; its metrics are removed when the guard is dropped
+    pub fn subscribe(self: &Arc<Self>, rpc: &'static str) -> SubscriberGuard {
+        let id = self.next_subscriber_id.fetch_add(1, Ordering::Relaxed);
+        let state = Arc::new(SubscriberState {
+            rpc,
+            published_at_start: self.events_published.load(Ordering::Relaxed),
+            consumed: AtomicU64::new(0),
+        });
+
+        self.subscribers
+            .lock()
+            .expect("subscriber registry poisoned")
+            .insert(id, Arc::clone(&state));
+        self.families
+            .active_subscribers
+            .get_or_create(&self.rpc_labels(rpc))
+            .inc();
+
+        SubscriberGuard {
+            metrics: Arc::clone(self),
+            id,
+            state,
+        }
+    }
+
+    fn rpc_labels(&self, rpc: &'static str) -> RpcLabels {
+        RpcLabels {
+            instrument_id: self.instrument_id,
+            rpc,
+        }
+    }
+
+    /// Lag is derived at scrape time so stalled subscribers are visible too
+    fn refresh_subscriber_lag(&self) {
+        let published = self.events_published.load(Ordering::Relaxed);
+        let subscribers = self
+            .subscribers
+            .lock()
+            .expect("subscriber registry poisoned");
+
+        let mut max_lag = 0;
+        for (&id, state) in subscribers.iter() {
+            let lag = published
+                .saturating_sub(state.published_at_start)
+                .saturating_sub(state.consumed.load(Ordering::Relaxed))
+                .min(self.queue_capacity);
+            max_lag = max_lag.max(lag);
+
+            self.families
+                .subscriber_lag
+                .get_or_create(&SubscriberLabels {
+                    instrument_id: self.instrument_id,
+                    rpc: state.rpc,
+                    subscriber: id,
+                })
+                .set(lag as i64);
+        }
+
+        self.families
+            .queue_depth
+            .get_or_create(&InstrumentLabels {
+                instrument_id: self.instrument_id,
+            })
+            .set(max_lag as i64);
+    }
+}
+
+/// Per-subscriber metrics handle held by a streaming response
+pub struct SubscriberGuard {
+    metrics: Arc<Metrics>,
+    id: u64,
+    state: Arc<SubscriberState>,
+}
+
+impl SubscriberGuard {
+    /// Records one event consumed by the subscriber; `bytes` is zero if it was filtered out
+    pub fn delivered(&self, bytes: usize) {
+        self.state.consumed.fetch_add(1, Ordering::Relaxed);
+        if bytes > 0 {
+            self.metrics
+                .families
+                .bytes_sent
+                .get_or_create(&self.metrics.rpc_labels(self.state.rpc))
+                .inc_by(bytes as u64);
+        }
+    }
+
+    /// Records events skipped because the subscriber fell behind
+    pub fn dropped(&self, count: u64) {
+        self.state.consumed.fetch_add(count, Ordering::Relaxed);
+        self.metrics
+            .families
+            .subscriber_dropped
+            .get_or_create(&self.labels())
+            .inc_by(count);
+        self.metrics
+            .families
+            .dropped
+            .get_or_create(&self.metrics.rpc_labels(self.state.rpc))
+            .inc_by(count);
+    }
+
+    fn labels(&self) -> SubscriberLabels {
+        SubscriberLabels {
+            instrument_id: self.metrics.instrument_id,
+            rpc: self.state.rpc,
+            subscriber: self.id,
+        }
+    }
+}
+
+impl Drop for SubscriberGuard {
+    fn drop(&mut self) {
+        let labels = self.labels();
+        self.metrics.families.subscriber_lag.remove(&labels);
+        self.metrics.families.subscriber_dropped.remove(&labels);
+        self.metrics
+            .families
+            .active_subscribers
+            .get_or_create(&self.metrics.rpc_labels(self.state.rpc))
+            .dec();
+
+        if let Ok(mut subscribers) = self.metrics.subscribers.lock() {
+            subscribers.remove(&self.id);
+        }
+    }
+}
+
+/// Serves `GET /metrics` until the process exits
+pub async fn serve(metrics: Arc<MetricsRegistry>, addr: SocketAddr) -> anyhow::Result<()> {
+    let app = Router::new()
+        .route("/metrics", get(render))
+        .with_state(metrics);
+
+    let listener = TcpListener::bind(addr).await?;
+    info!("Metrics endpoint listening on http://{}/metrics", addr);
+    axum::serve(listener, app).await?;
+    Ok(())
+}
+
+async fn render(State(metrics): State<Arc<MetricsRegistry>>) -> impl IntoResponse {
+    (
+        [(
+            header::CONTENT_TYPE,
+            "application/openmetrics-text; version=1.0.0; charset=utf-8",
+        )],
+        metrics.encode(),
+    )
+}
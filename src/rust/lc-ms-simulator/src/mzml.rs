@@ -0,0 +1,1640 @@
+//! mzML output and input.
+//!
+//! [`MzmlWriter`] writes scans as they are acquired. Spectra go to a `<file>.part` spool
+//! first, because the header needs the final spectrum count; [`MzmlWriter::finish`] then
+//! assembles the `indexedmzML` file with the spectrum offset index and the SHA-1
+//! checksum that readers use to seek and to verify the file.
+//!
+//! [`MzmlReader`] streams the spectra of an mzML or indexedmzML file back as scans, one
+//! spectrum in memory at a time, so runs of any size can be replayed.
+
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use flate2::read::ZlibDecoder;
+use flate2::write::ZlibEncoder;
+use quick_xml::events::{BytesStart, Event};
+use quick_xml::Reader;
+use ring::digest;
+
+use crate::profiles::{Analyzer, InstrumentModel};
+use crate::proto::{FragmentationType, Polarity, ScanMessage};
+
+/// Spectrum ID prefix of Thermo raw files, which the scan number completes
+const NATIVE_ID_PREFIX: &str = "controllerType=0 controllerNumber=1 scan=";
+
+const SOFTWARE_ID: &str = "lc_ms_simulator";
+const DATA_PROCESSING_ID: &str = "simulation";
+
+/// A unit for cvParam values
+struct Unit {
+    cv: &'static str,
+    accession: &'static str,
+    name: &'static str,
+}
+
+const MZ: Unit = Unit {
+    cv: "MS",
+    accession: "MS:1000040",
+    name: "m/z",
+};
+const DETECTOR_COUNTS: Unit = Unit {
+    cv: "MS",
+    accession: "MS:1000131",
+    name: "number of detector counts",
+};
+const MINUTE: Unit = Unit {
+    cv: "UO",
+    accession: "UO:0000031",
+    name: "minute",
+};
+const ELECTRONVOLT: Unit = Unit {
+    cv: "UO",
+    accession: "UO:0000266",
+    name: "electronvolt",
+};
+
+/// Time unit accessions of `scan start time`, with their length in minutes
+const TIME_UNITS: &[(&str, f64)] = &[
+    ("UO:0000031", 1.0),
+    ("MS:1000038", 1.0),
+    ("UO:0000010", 1.0 / 60.0),
+    ("UO:0000028", 1.0 / 60_000.0),
+];
+
+/// Array compressions other than zlib, which the reader cannot decode
+const UNSUPPORTED_COMPRESSIONS: &[(&str, &str)] = &[
+    ("MS:1002312", "MS-Numpress linear prediction"),
+    ("MS:1002313", "MS-Numpress positive integer"),
+    ("MS:1002314", "MS-Numpress short logged float"),
+    (
+        "MS:1002746",
+        "MS-Numpress linear prediction followed by zlib",
+    ),
+    (
+        "MS:1002747",
+        "MS-Numpress positive integer followed by zlib",
+    ),
+    (
+        "MS:1002748",
+        "MS-Numpress short logged float followed by zlib",
+    ),
+];
+
+/// The acquisition a file records
+pub struct RunInfo {
+    /// mzML `run` ID, e.g. the session ID; made a valid XML ID
+    pub run_id: String,
+    pub model: InstrumentModel,
+    pub serial_number: String,
+    /// Wall-clock start of the run
+    pub start_timestamp_ms: i64,
+    /// zlib-compress the binary arrays
+    pub zlib: bool,
+}
+
+pub struct MzmlWriter {
+    path: PathBuf,
+    spool_path: PathBuf,
+    spool: BufWriter<File>,
+    spool_len: u64,
+    run: RunInfo,
+    /// Spectrum ID and offset within the spool, in file order
+    offsets: Vec<(String, u64)>,
+    /// Scan numbers written so far, to keep IDs unique when a scan number repeats
+    scan_numbers: HashSet<i32>,
+    /// Precursor spectrum of the following MS2 scans
+    last_ms1_id: Option<String>,
+    has_ms1: bool,
+    has_msn: bool,
+    buffer: Vec<u8>,
+}
+
+impl MzmlWriter {
+    /// Starts a file at `path`, which must not exist yet
+    pub fn create(path: &Path, run: RunInfo) -> io::Result<Self> {
+        if path.exists() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} already exists", path.display()),
+            ));
+        }
+        let mut spool_path = path.as_os_str().to_owned();
+        spool_path.push(".part");
+        let spool_path = PathBuf::from(spool_path);
+        let spool = File::options()
+            .write(true)
+            .create_new(true)
+            .open(&spool_path)?;
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            spool_path,
+            spool: BufWriter::new(spool),
+            spool_len: 0,
+            run,
+            offsets: Vec::new(),
+            scan_numbers: HashSet::new(),
+            last_ms1_id: None,
+            has_ms1: false,
+            has_msn: false,
+            buffer: Vec::new(),
+        })
+    }
+
+    pub fn spectrum_count(&self) -> usize {
+        self.offsets.len()
+    }
+
+    pub fn write_spectrum(&mut self, scan: &ScanMessage) -> io::Result<()> {
+        let index = self.offsets.len();
+        // A repeated scan number, e.g. an injected defect, also gets the spectrum index
+        let id = if self.scan_numbers.insert(scan.scan_number) {
+            format!("{}{}", NATIVE_ID_PREFIX, scan.scan_number)
+        } else {
+            format!("{}{} index={}", NATIVE_ID_PREFIX, scan.scan_number, index)
+        };
+        let configuration = self
+            .configuration(&scan.analyzer)
+            .filter(|&configuration| configuration != self.default_configuration());
+        let mut buffer = std::mem::take(&mut self.buffer);
+        buffer.clear();
+        let out = &mut buffer;
+
+        writeln!(
+            out,
+            "      <spectrum index=\"{}\" id=\"{}\" defaultArrayLength=\"{}\">",
+            index,
+            id,
+            scan.mz_values.len()
+        )?;
+        cv_param(out, 8, "MS:1000511", "ms level", scan.ms_order)?;
+        if scan.ms_order <= 1 {
+            self.has_ms1 = true;
+            cv_param(out, 8, "MS:1000579", "MS1 spectrum", "")?;
+        } else {
+            self.has_msn = true;
+            cv_param(out, 8, "MS:1000580", "MSn spectrum", "")?;
+        }
+        match scan.polarity() {
+            Polarity::Positive => cv_param(out, 8, "MS:1000130", "positive scan", "")?,
+            Polarity::Negative => cv_param(out, 8, "MS:1000129", "negative scan", "")?,
+            Polarity::Unknown => {}
+        }
+        cv_param(out, 8, "MS:1000127", "centroid spectrum", "")?;
+        cv_unit(
+            out,
+            8,
+            "MS:1000504",
+            "base peak m/z",
+            scan.base_peak_mz,
+            &MZ,
+        )?;
+        cv_unit(
+            out,
+            8,
+            "MS:1000505",
+            "base peak intensity",
+            scan.base_peak_intensity,
+            &DETECTOR_COUNTS,
+        )?;
+        cv_param(
+            out,
+            8,
+            "MS:1000285",
+            "total ion current",
+            scan.total_ion_current,
+        )?;
+        let mz_range = scan.mz_values.iter().copied().fold(
+            None,
+            |range: Option<(f64, f64)>, mz| match range {
+                Some((low, high)) => Some((low.min(mz), high.max(mz))),
+                None => Some((mz, mz)),
+            },
+        );
+        if let Some((low, high)) = mz_range {
+            cv_unit(out, 8, "MS:1000528", "lowest observed m/z", low, &MZ)?;
+            cv_unit(out, 8, "MS:1000527", "highest observed m/z", high, &MZ)?;
+        }
+
+        writeln!(out, "        <scanList count=\"1\">")?;
+        cv_param(out, 10, "MS:1000795", "no combination", "")?;
+        match configuration {
+            Some(configuration) => writeln!(
+                out,
+                "          <scan instrumentConfigurationRef=\"{}\">",
+                configuration
+            )?,
+            None => writeln!(out, "          <scan>")?,
+        }
+        cv_unit(
+            out,
+            12,
+            "MS:1000016",
+            "scan start time",
+            scan.retention_time,
+            &MINUTE,
+        )?;
+        writeln!(out, "          </scan>")?;
+        writeln!(out, "        </scanList>")?;
+
+        if scan.ms_order > 1 {
+            if let Some(precursor_mz) = scan.precursor_mass {
+                write_precursor(out, scan, precursor_mz, self.last_ms1_id.as_deref())?;
+            }
+        } else {
+            self.last_ms1_id = Some(id.clone());
+        }
+
+        writeln!(out, "        <binaryDataArrayList count=\"2\">")?;
+        let default_length = scan.mz_values.len();
+        write_array(
+            out,
+            &scan.mz_values,
+            default_length,
+            self.run.zlib,
+            ("MS:1000514", "m/z array", &MZ),
+        )?;
+        write_array(
+            out,
+            &scan.intensity_values,
+            default_length,
+            self.run.zlib,
+            ("MS:1000515", "intensity array", &DETECTOR_COUNTS),
+        )?;
+        writeln!(out, "        </binaryDataArrayList>")?;
+        writeln!(out, "      </spectrum>")?;
+
+        self.spool.write_all(&buffer)?;
+        // Offsets point at the tag itself, past the indentation
+        let indent = buffer.iter().take_while(|&&b| b == b' ').count() as u64;
+        self.offsets.push((id, self.spool_len + indent));
+        self.spool_len += buffer.len() as u64;
+        self.buffer = buffer;
+        Ok(())
+    }
+
+    /// Assembles the indexed file from the spool and removes the spool
+    pub fn finish(mut self) -> io::Result<PathBuf> {
+        self.spool.flush()?;
+        let spool = File::open(&self.spool_path)?;
+        let file = File::options()
+            .write(true)
+            .create_new(true)
+            .open(&self.path)?;
+        let mut out = Sha1Writer::new(BufWriter::new(file));
+
+        let mut header = Vec::new();
+        self.write_header(&mut header)?;
+        out.write_all(&header)?;
+        io::copy(&mut BufReader::new(spool), &mut out)?;
+        let spectra_start = header.len() as u64;
+
+        write!(out, "    </spectrumList>\n  </run>\n</mzML>\n")?;
+        let index_offset = out.written;
+        writeln!(out, "<indexList count=\"1\">")?;
+        writeln!(out, "  <index name=\"spectrum\">")?;
+        for (id, offset) in &self.offsets {
+            writeln!(
+                out,
+                "    <offset idRef=\"{}\">{}</offset>",
+                id,
+                spectra_start + offset
+            )?;
+        }
+        writeln!(out, "  </index>")?;
+        writeln!(out, "</indexList>")?;
+        writeln!(out, "<indexListOffset>{}</indexListOffset>", index_offset)?;
+        // The checksum covers the file up to and including this opening tag
+        write!(out, "<fileChecksum>")?;
+        let (mut file, checksum) = out.finish();
+        writeln!(file, "{}</fileChecksum>", checksum)?;
+        writeln!(file, "</indexedmzML>")?;
+        file.flush()?;
+
+        fs::remove_file(&self.spool_path)?;
+        Ok(self.path)
+    }
+
+    /// Everything before the first spectrum
+    fn write_header(&self, out: &mut Vec<u8>) -> io::Result<()> {
+        let run = &self.run;
+        let profile = run.model.profile();
+        let (model_accession, model_name) = model_term(run.model);
+
+        writeln!(out, "<?xml version=\"1.0\" encoding=\"utf-8\"?>")?;
+        writeln!(
+            out,
+            "<indexedmzML xmlns=\"http://psi.hupo.org/ms/mzml\" \
+             xmlns:xsi=\"http://www.w3.org/2001/XMLSchema-instance\" \
+             xsi:schemaLocation=\"http://psi.hupo.org/ms/mzml \
+             http://psidev.info/files/ms/mzML/xsd/mzML1.1.2_idx.xsd\">"
+        )?;
+        writeln!(
+            out,
+            "<mzML xmlns=\"http://psi.hupo.org/ms/mzml\" \
+             xmlns:xsi=\"http://www.w3.org/2001/XMLSchema-instance\" \
+             xsi:schemaLocation=\"http://psi.hupo.org/ms/mzml \
+             http://psidev.info/files/ms/mzML/xsd/mzML1.1.0.xsd\" id=\"{}\" version=\"1.1.0\">",
+            xml_id(&run.run_id)
+        )?;
+        writeln!(out, "  <cvList count=\"2\">")?;
+        writeln!(
+            out,
+            "    <cv id=\"MS\" fullName=\"Proteomics Standards Initiative Mass Spectrometry \
+             Ontology\" version=\"4.1.0\" \
+             URI=\"https://raw.githubusercontent.com/HUPO-PSI/psi-ms-CV/master/psi-ms.obo\"/>"
+        )?;
+        writeln!(
+            out,
+            "    <cv id=\"UO\" fullName=\"Unit Ontology\" version=\"09:04:2014\" \
+             URI=\"https://raw.githubusercontent.com/bio-ontology-research-group/\
+             unit-ontology/master/unit.obo\"/>"
+        )?;
+        writeln!(out, "  </cvList>")?;
+
+        writeln!(out, "  <fileDescription>")?;
+        writeln!(out, "    <fileContent>")?;
+        if self.has_ms1 {
+            cv_param(out, 6, "MS:1000579", "MS1 spectrum", "")?;
+        }
+        if self.has_msn {
+            cv_param(out, 6, "MS:1000580", "MSn spectrum", "")?;
+        }
+        writeln!(out, "    </fileContent>")?;
+        writeln!(out, "  </fileDescription>")?;
+
+        writeln!(out, "  <softwareList count=\"1\">")?;
+        writeln!(
+            out,
+            "    <software id=\"{}\" version=\"{}\">",
+            SOFTWARE_ID,
+            env!("CARGO_PKG_VERSION")
+        )?;
+        cv_param(
+            out,
+            6,
+            "MS:1000799",
+            "custom unreleased software tool",
+            "lc-ms-simulator",
+        )?;
+        writeln!(out, "    </software>")?;
+        writeln!(out, "  </softwareList>")?;
+
+        writeln!(
+            out,
+            "  <instrumentConfigurationList count=\"{}\">",
+            profile.analyzers.len()
+        )?;
+        for &analyzer in profile.analyzers {
+            let (analyzer_accession, analyzer_name, detector_accession, detector_name) =
+                analyzer_terms(analyzer);
+            writeln!(
+                out,
+                "    <instrumentConfiguration id=\"{}\">",
+                configuration_id(analyzer)
+            )?;
+            cv_param(out, 6, model_accession, model_name, "")?;
+            cv_param(
+                out,
+                6,
+                "MS:1000529",
+                "instrument serial number",
+                escape(&run.serial_number),
+            )?;
+            writeln!(out, "      <componentList count=\"3\">")?;
+            writeln!(out, "        <source order=\"1\">")?;
+            cv_param(out, 10, "MS:1000073", "electrospray ionization", "")?;
+            writeln!(out, "        </source>")?;
+            writeln!(out, "        <analyzer order=\"2\">")?;
+            cv_param(out, 10, analyzer_accession, analyzer_name, "")?;
+            writeln!(out, "        </analyzer>")?;
+            writeln!(out, "        <detector order=\"3\">")?;
+            cv_param(out, 10, detector_accession, detector_name, "")?;
+            writeln!(out, "        </detector>")?;
+            writeln!(out, "      </componentList>")?;
+            writeln!(out, "      <softwareRef ref=\"{}\"/>", SOFTWARE_ID)?;
+            writeln!(out, "    </instrumentConfiguration>")?;
+        }
+        writeln!(out, "  </instrumentConfigurationList>")?;
+
+        writeln!(out, "  <dataProcessingList count=\"1\">")?;
+        writeln!(out, "    <dataProcessing id=\"{}\">", DATA_PROCESSING_ID)?;
+        writeln!(
+            out,
+            "      <processingMethod order=\"0\" softwareRef=\"{}\">",
+            SOFTWARE_ID
+        )?;
+        cv_param(out, 8, "MS:1000544", "Conversion to mzML", "")?;
+        writeln!(out, "      </processingMethod>")?;
+        writeln!(out, "    </dataProcessing>")?;
+        writeln!(out, "  </dataProcessingList>")?;
+
+        writeln!(
+            out,
+            "  <run id=\"{}\" defaultInstrumentConfigurationRef=\"{}\" startTimeStamp=\"{}\">",
+            xml_id(&run.run_id),
+            self.default_configuration(),
+            iso8601(run.start_timestamp_ms)
+        )?;
+        writeln!(
+            out,
+            "    <spectrumList count=\"{}\" defaultDataProcessingRef=\"{}\">",
+            self.offsets.len(),
+            DATA_PROCESSING_ID
+        )?;
+        Ok(())
+    }
+
+    fn default_configuration(&self) -> &'static str {
+        configuration_id(self.run.model.profile().analyzers[0])
+    }
+
+    /// The configuration of the analyzer a scan names, if the instrument has it
+    fn configuration(&self, analyzer: &str) -> Option<&'static str> {
+        self.run
+            .model
+            .profile()
+            .analyzers
+            .iter()
+            .find(|candidate| candidate.name() == analyzer)
+            .map(|&analyzer| configuration_id(analyzer))
+    }
+}
+
+fn write_precursor(
+    out: &mut Vec<u8>,
+    scan: &ScanMessage,
+    precursor_mz: f64,
+    precursor_id: Option<&str>,
+) -> io::Result<()> {
+    writeln!(out, "        <precursorList count=\"1\">")?;
+    match precursor_id {
+        Some(id) => writeln!(out, "          <precursor spectrumRef=\"{}\">", id)?,
+        None => writeln!(out, "          <precursor>")?,
+    }
+    if let Some(width) = scan.isolation_width {
+        writeln!(out, "            <isolationWindow>")?;
+        cv_unit(
+            out,
+            14,
+            "MS:1000827",
+            "isolation window target m/z",
+            precursor_mz,
+            &MZ,
+        )?;
+        cv_unit(
+            out,
+            14,
+            "MS:1000828",
+            "isolation window lower offset",
+            width / 2.0,
+            &MZ,
+        )?;
+        cv_unit(
+            out,
+            14,
+            "MS:1000829",
+            "isolation window upper offset",
+            width / 2.0,
+            &MZ,
+        )?;
+        writeln!(out, "            </isolationWindow>")?;
+    }
+    writeln!(out, "            <selectedIonList count=\"1\">")?;
+    writeln!(out, "              <selectedIon>")?;
+    cv_unit(out, 16, "MS:1000744", "selected ion m/z", precursor_mz, &MZ)?;
+    if let Some(charge) = scan.precursor_charge {
+        cv_param(out, 16, "MS:1000041", "charge state", charge)?;
+    }
+    if let Some(intensity) = scan.precursor_intensity {
+        cv_unit(
+            out,
+            16,
+            "MS:1000042",
+            "peak intensity",
+            intensity,
+            &DETECTOR_COUNTS,
+        )?;
+    }
+    writeln!(out, "              </selectedIon>")?;
+    writeln!(out, "            </selectedIonList>")?;
+    writeln!(out, "            <activation>")?;
+    let (accession, name) = activation_term(scan.fragmentation_type());
+    cv_param(out, 14, accession, name, "")?;
+    if let Some(energy) = scan.collision_energy {
+        cv_unit(
+            out,
+            14,
+            "MS:1000045",
+            "collision energy",
+            energy,
+            &ELECTRONVOLT,
+        )?;
+    }
+    writeln!(out, "            </activation>")?;
+    writeln!(out, "          </precursor>")?;
+    writeln!(out, "        </precursorList>")?;
+    Ok(())
+}
+
+/// A 64-bit float array, base64-encoded and optionally zlib-compressed
+fn write_array(
+    out: &mut Vec<u8>,
+    values: &[f64],
+    default_length: usize,
+    zlib: bool,
+    (accession, name, unit): (&str, &str, &Unit),
+) -> io::Result<()> {
+    let mut bytes = Vec::with_capacity(values.len() * 8);
+    for value in values {
+        bytes.extend_from_slice(&value.to_le_bytes());
+    }
+    if zlib {
+        let mut encoder = ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(&bytes)?;
+        bytes = encoder.finish()?;
+    }
+    let encoded = STANDARD.encode(&bytes);
+
+    // Only arrays whose length differs from the spectrum's state their own
+    if values.len() == default_length {
+        writeln!(
+            out,
+            "          <binaryDataArray encodedLength=\"{}\">",
+            encoded.len()
+        )?;
+    } else {
+        writeln!(
+            out,
+            "          <binaryDataArray arrayLength=\"{}\" encodedLength=\"{}\">",
+            values.len(),
+            encoded.len()
+        )?;
+    }
+    cv_param(out, 12, "MS:1000523", "64-bit float", "")?;
+    if zlib {
+        cv_param(out, 12, "MS:1000574", "zlib compression", "")?;
+    } else {
+        cv_param(out, 12, "MS:1000576", "no compression", "")?;
+    }
+    cv_unit(out, 12, accession, name, "", unit)?;
+    writeln!(out, "            <binary>{}</binary>", encoded)?;
+    writeln!(out, "          </binaryDataArray>")?;
+    Ok(())
+}
+
+fn cv_param(
+    out: &mut impl Write,
+    indent: usize,
+    accession: &str,
+    name: &str,
+    value: impl std::fmt::Display,
+) -> io::Result<()> {
+    writeln!(
+        out,
+        "{:indent$}<cvParam cvRef=\"{}\" accession=\"{}\" name=\"{}\" value=\"{}\"/>",
+        "",
+        cv_ref(accession),
+        accession,
+        name,
+        value,
+        indent = indent
+    )
+}
+
+fn cv_unit(
+    out: &mut impl Write,
+    indent: usize,
+    accession: &str,
+    name: &str,
+    value: impl std::fmt::Display,
+    unit: &Unit,
+) -> io::Result<()> {
+    writeln!(
+        out,
+        "{:indent$}<cvParam cvRef=\"{}\" accession=\"{}\" name=\"{}\" value=\"{}\" \
+         unitCvRef=\"{}\" unitAccession=\"{}\" unitName=\"{}\"/>",
+        "",
+        cv_ref(accession),
+        accession,
+        name,
+        value,
+        unit.cv,
+        unit.accession,
+        unit.name,
+        indent = indent
+    )
+}
+
+/// The CV an accession belongs to, from its prefix
+fn cv_ref(accession: &str) -> &str {
+    accession.split(':').next().unwrap_or("MS")
+}
+
+fn configuration_id(analyzer: Analyzer) -> &'static str {
+    match analyzer {
+        Analyzer::Orbitrap => "IC_Orbitrap",
+        Analyzer::IonTrap => "IC_IonTrap",
+        Analyzer::Astral => "IC_Astral",
+    }
+}
+
+/// PSI-MS instrument model term
+fn model_term(model: InstrumentModel) -> (&'static str, &'static str) {
+    match model {
+        InstrumentModel::QExactiveHf => ("MS:1002523", "Q Exactive HF"),
+        InstrumentModel::Exploris240 => ("MS:1003094", "Orbitrap Exploris 240"),
+        InstrumentModel::Exploris480 => ("MS:1003028", "Orbitrap Exploris 480"),
+        InstrumentModel::FusionLumos => ("MS:1002732", "Orbitrap Fusion Lumos"),
+        InstrumentModel::Eclipse => ("MS:1003029", "Orbitrap Eclipse"),
+        InstrumentModel::Astral => ("MS:1003378", "Orbitrap Astral"),
+        InstrumentModel::Generic => ("MS:1000483", "Thermo Fisher Scientific instrument model"),
+    }
+}
+
+/// Analyzer and detector terms
+fn analyzer_terms(analyzer: Analyzer) -> (&'static str, &'static str, &'static str, &'static str) {
+    match analyzer {
+        Analyzer::Orbitrap => ("MS:1000484", "orbitrap", "MS:1000624", "inductive detector"),
+        Analyzer::IonTrap => (
+            "MS:1000291",
+            "linear ion trap",
+            "MS:1000253",
+            "electron multiplier",
+        ),
+        Analyzer::Astral => (
+            "MS:1000084",
+            "time-of-flight",
+            "MS:1000253",
+            "electron multiplier",
+        ),
+    }
+}
+
+/// Dissociation method term
+fn activation_term(fragmentation: FragmentationType) -> (&'static str, &'static str) {
+    match fragmentation {
+        FragmentationType::FragmentationHcd => {
+            ("MS:1000422", "beam-type collision-induced dissociation")
+        }
+        FragmentationType::FragmentationCid => ("MS:1000133", "collision-induced dissociation"),
+        FragmentationType::FragmentationEtd => ("MS:1000598", "electron transfer dissociation"),
+        FragmentationType::FragmentationEthcd => (
+            "MS:1002631",
+            "electron transfer/higher-energy collision dissociation",
+        ),
+        FragmentationType::FragmentationUvpd => ("MS:1003246", "ultraviolet photodissociation"),
+        FragmentationType::FragmentationUnknown => ("MS:1000044", "dissociation method"),
+    }
+}
+
+/// Escapes text for an XML attribute or element
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// A valid `xs:ID`: a name that starts with a letter or underscore
+fn xml_id(text: &str) -> String {
+    let mut id: String = text
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.') {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    if !id.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_') {
+        id.insert(0, '_');
+    }
+    id
+}
+
+/// UTC date and time as `YYYY-MM-DDThh:mm:ssZ`
+fn iso8601(timestamp_ms: i64) -> String {
+    let seconds = timestamp_ms.div_euclid(1000);
+    let (days, second_of_day) = (seconds.div_euclid(86_400), seconds.rem_euclid(86_400));
+
+    // Civil date from days since 1970-01-01 (Howard Hinnant's algorithm)
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        second_of_day / 3600,
+        second_of_day / 60 % 60,
+        second_of_day % 60
+    )
+}
+
+/// Counts and hashes what passes through, for the index offsets and checksum
+struct Sha1Writer<W> {
+    inner: W,
+    context: digest::Context,
+    written: u64,
+}
+
+impl<W: Write> Sha1Writer<W> {
+    fn new(inner: W) -> Self {
+        Self {
+            inner,
+            context: digest::Context::new(&digest::SHA1_FOR_LEGACY_USE_ONLY),
+            written: 0,
+        }
+    }
+
+    /// The inner writer and the lowercase hex digest of everything written
+    fn finish(self) -> (W, String) {
+        let digest = self.context.finish();
+        let hex = digest
+            .as_ref()
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+        (self.inner, hex)
+    }
+}
+
+impl<W: Write> Write for Sha1Writer<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.context.update(&buf[..written]);
+        self.written += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Reads the spectra of an mzML or indexedmzML file in file order
+pub struct MzmlReader<R> {
+    reader: Reader<R>,
+    buffer: Vec<u8>,
+    /// Scopes of the open elements, innermost last
+    scopes: Vec<Scope>,
+    /// `referenceableParamGroup` contents by ID
+    param_groups: HashMap<String, Vec<CvParam>>,
+    /// The group being read
+    param_group: Option<(String, Vec<CvParam>)>,
+    /// Analyzer of each `instrumentConfiguration` that names a known one
+    configurations: HashMap<String, Analyzer>,
+    /// The configuration being read
+    configuration: Option<String>,
+    default_configuration: Option<String>,
+    spectrum: Option<SpectrumBuilder>,
+    /// Whether an `mzML` element was seen, to tell other XML files apart
+    is_mzml: bool,
+}
+
+/// Where a cvParam applies
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Scope {
+    Other,
+    ParamGroup,
+    Configuration,
+    Analyzer,
+    Spectrum,
+    Scan,
+    Precursor,
+    IsolationWindow,
+    SelectedIon,
+    Activation,
+    /// A second or later precursor, which scans have no fields for
+    IgnoredPrecursor,
+    BinaryDataArray,
+    Binary,
+}
+
+struct CvParam {
+    accession: String,
+    value: String,
+    unit_accession: Option<String>,
+}
+
+impl CvParam {
+    fn number(&self) -> io::Result<f64> {
+        self.value.trim().parse().map_err(|_| {
+            invalid_data(format!(
+                "{} has the non-numeric value {:?}",
+                self.accession, self.value
+            ))
+        })
+    }
+}
+
+/// A spectrum being read
+#[derive(Default)]
+struct SpectrumBuilder {
+    scan: ScanMessage,
+    id: String,
+    default_length: usize,
+    configuration: Option<String>,
+    isolation_target: Option<f64>,
+    isolation_lower: Option<f64>,
+    isolation_upper: Option<f64>,
+    has_precursor: bool,
+    activations: Vec<FragmentationType>,
+    supplemental_activation: bool,
+    has_base_peak: bool,
+    has_total_ion_current: bool,
+    array: Option<ArrayBuilder>,
+}
+
+#[derive(Default)]
+struct ArrayBuilder {
+    length: Option<usize>,
+    kind: Option<ArrayKind>,
+    number: Option<NumberType>,
+    zlib: bool,
+    unsupported_compression: Option<&'static str>,
+    base64: Vec<u8>,
+}
+
+#[derive(Clone, Copy)]
+enum ArrayKind {
+    Mz,
+    Intensity,
+}
+
+#[derive(Clone, Copy)]
+enum NumberType {
+    Float32,
+    Float64,
+    Int32,
+    Int64,
+}
+
+impl MzmlReader<BufReader<File>> {
+    pub fn open(path: &Path) -> io::Result<Self> {
+        Ok(Self::new(BufReader::new(File::open(path)?)))
+    }
+}
+
+impl<R: BufRead> MzmlReader<R> {
+    pub fn new(source: R) -> Self {
+        let mut reader = Reader::from_reader(source);
+        reader.config_mut().trim_text(true);
+        Self {
+            reader,
+            buffer: Vec::new(),
+            scopes: Vec::new(),
+            param_groups: HashMap::new(),
+            param_group: None,
+            configurations: HashMap::new(),
+            configuration: None,
+            default_configuration: None,
+            spectrum: None,
+            is_mzml: false,
+        }
+    }
+
+    /// The next spectrum as a scan, or `None` after the last one
+    pub fn next_scan(&mut self) -> io::Result<Option<ScanMessage>> {
+        let mut buffer = std::mem::take(&mut self.buffer);
+        let result = self.read_scan(&mut buffer);
+        self.buffer = buffer;
+        result
+    }
+
+    fn read_scan(&mut self, buffer: &mut Vec<u8>) -> io::Result<Option<ScanMessage>> {
+        loop {
+            buffer.clear();
+            let scan = match self.reader.read_event_into(buffer).map_err(invalid_data)? {
+                Event::Start(element) => {
+                    self.start(&element)?;
+                    None
+                }
+                Event::Empty(element) => {
+                    self.start(&element)?;
+                    self.end()?
+                }
+                Event::End(_) => self.end()?,
+                Event::Text(text) => {
+                    if self.scopes.last() == Some(&Scope::Binary) {
+                        if let Some(array) = self.array() {
+                            array.base64.extend_from_slice(&text);
+                        }
+                    }
+                    None
+                }
+                Event::Eof if !self.is_mzml => {
+                    return Err(invalid_data("Not an mzML file"));
+                }
+                Event::Eof => {
+                    return match &self.spectrum {
+                        Some(spectrum) => Err(invalid_data(format!(
+                            "File ends inside spectrum {}",
+                            spectrum.id
+                        ))),
+                        None => Ok(None),
+                    };
+                }
+                _ => None,
+            };
+            if scan.is_some() {
+                return Ok(scan);
+            }
+        }
+    }
+
+    fn start(&mut self, element: &BytesStart) -> io::Result<()> {
+        let parent = self.scopes.last().copied().unwrap_or(Scope::Other);
+        let scope = match (element.local_name().as_ref(), parent) {
+            (b"mzML", _) => {
+                self.is_mzml = true;
+                Scope::Other
+            }
+            (b"referenceableParamGroup", _) => {
+                self.param_group = Some((required_attribute(element, b"id")?, Vec::new()));
+                Scope::ParamGroup
+            }
+            (b"instrumentConfiguration", _) => {
+                self.configuration = Some(required_attribute(element, b"id")?);
+                Scope::Configuration
+            }
+            (b"analyzer", Scope::Configuration) => Scope::Analyzer,
+            (b"run", _) => {
+                self.default_configuration =
+                    attribute(element, b"defaultInstrumentConfigurationRef")?;
+                Scope::Other
+            }
+            (b"spectrum", _) => {
+                self.spectrum = Some(SpectrumBuilder::new(element)?);
+                Scope::Spectrum
+            }
+            (b"chromatogram", _) => Scope::Other,
+            (b"scan", Scope::Spectrum) => {
+                if let Some(configuration) = attribute(element, b"instrumentConfigurationRef")? {
+                    if let Some(spectrum) = &mut self.spectrum {
+                        spectrum.configuration = Some(configuration);
+                    }
+                }
+                Scope::Scan
+            }
+            (b"precursor", Scope::Spectrum) => {
+                let first = self
+                    .spectrum
+                    .as_mut()
+                    .is_some_and(|spectrum| !std::mem::replace(&mut spectrum.has_precursor, true));
+                if first {
+                    Scope::Precursor
+                } else {
+                    Scope::IgnoredPrecursor
+                }
+            }
+            (b"isolationWindow", Scope::Precursor) => Scope::IsolationWindow,
+            (b"selectedIon", Scope::Precursor) => Scope::SelectedIon,
+            (b"activation", Scope::Precursor) => Scope::Activation,
+            (b"binaryDataArray", Scope::Spectrum) => {
+                let length = attribute(element, b"arrayLength")?
+                    .map(|length| parse_count(&length))
+                    .transpose()?;
+                if let Some(spectrum) = &mut self.spectrum {
+                    spectrum.array = Some(ArrayBuilder {
+                        length,
+                        ..Default::default()
+                    });
+                }
+                Scope::BinaryDataArray
+            }
+            (b"binary", Scope::BinaryDataArray) => Scope::Binary,
+            (b"cvParam", _) => {
+                let param = CvParam {
+                    accession: required_attribute(element, b"accession")?,
+                    value: attribute(element, b"value")?.unwrap_or_default(),
+                    unit_accession: attribute(element, b"unitAccession")?,
+                };
+                self.apply(parent, param)?;
+                parent
+            }
+            (b"referenceableParamGroupRef", _) => {
+                let id = required_attribute(element, b"ref")?;
+                let params = self.param_groups.remove(&id).ok_or_else(|| {
+                    invalid_data(format!("Unknown referenceableParamGroup {}", id))
+                })?;
+                let applied = params
+                    .iter()
+                    .try_for_each(|param| self.apply_param(parent, param));
+                self.param_groups.insert(id, params);
+                applied?;
+                parent
+            }
+            // Lists and other wrappers take the scope they are in
+            _ => parent,
+        };
+        self.scopes.push(scope);
+        Ok(())
+    }
+
+    /// Closes the innermost element; returns the scan a closed spectrum completes
+    fn end(&mut self) -> io::Result<Option<ScanMessage>> {
+        let scope = self
+            .scopes
+            .pop()
+            .ok_or_else(|| invalid_data("Unbalanced closing tag"))?;
+        let parent = self.scopes.last().copied().unwrap_or(Scope::Other);
+        if scope == parent {
+            return Ok(None);
+        }
+        match scope {
+            Scope::ParamGroup => {
+                if let Some((id, params)) = self.param_group.take() {
+                    self.param_groups.insert(id, params);
+                }
+            }
+            Scope::Configuration => self.configuration = None,
+            Scope::BinaryDataArray => {
+                if let Some(spectrum) = &mut self.spectrum {
+                    spectrum.finish_array()?;
+                }
+            }
+            Scope::Spectrum => {
+                if let Some(spectrum) = self.spectrum.take() {
+                    let configuration = spectrum
+                        .configuration
+                        .as_ref()
+                        .or(self.default_configuration.as_ref());
+                    let analyzer = configuration.and_then(|id| self.configurations.get(id));
+                    return spectrum.finish(analyzer.copied()).map(Some);
+                }
+            }
+            _ => {}
+        }
+        Ok(None)
+    }
+
+    fn apply(&mut self, scope: Scope, param: CvParam) -> io::Result<()> {
+        match (scope, &mut self.param_group) {
+            (Scope::ParamGroup, Some((_, params))) => {
+                params.push(param);
+                Ok(())
+            }
+            _ => self.apply_param(scope, &param),
+        }
+    }
+
+    fn apply_param(&mut self, scope: Scope, param: &CvParam) -> io::Result<()> {
+        if scope == Scope::Analyzer {
+            if let (Some(configuration), Some(analyzer)) =
+                (&self.configuration, analyzer_from_term(&param.accession))
+            {
+                // The last analyzer of a configuration is the one that measures
+                self.configurations.insert(configuration.clone(), analyzer);
+            }
+            return Ok(());
+        }
+        match &mut self.spectrum {
+            Some(spectrum) => spectrum.apply(scope, param),
+            None => Ok(()),
+        }
+    }
+
+    fn array(&mut self) -> Option<&mut ArrayBuilder> {
+        self.spectrum
+            .as_mut()
+            .and_then(|spectrum| spectrum.array.as_mut())
+    }
+}
+
+impl SpectrumBuilder {
+    fn new(element: &BytesStart) -> io::Result<Self> {
+        let id = required_attribute(element, b"id")?;
+        let index = attribute(element, b"index")?
+            .map(|index| parse_count(&index))
+            .transpose()?;
+        let default_length = parse_count(&required_attribute(element, b"defaultArrayLength")?)?;
+
+        // Native IDs carry the scan number; other files number spectra by position
+        let scan_number = id
+            .split_whitespace()
+            .find_map(|part| part.strip_prefix("scan="))
+            .and_then(|number| number.parse().ok())
+            .or_else(|| index.and_then(|index| i32::try_from(index + 1).ok()))
+            .unwrap_or(0);
+
+        Ok(Self {
+            scan: ScanMessage {
+                scan_number,
+                ms_order: 1,
+                ..Default::default()
+            },
+            id,
+            default_length,
+            ..Default::default()
+        })
+    }
+
+    fn apply(&mut self, scope: Scope, param: &CvParam) -> io::Result<()> {
+        let scan = &mut self.scan;
+        match (scope, param.accession.as_str()) {
+            (Scope::Spectrum, "MS:1000511") => {
+                scan.ms_order = param.number()? as i32;
+            }
+            (Scope::Spectrum, "MS:1000130") => scan.set_polarity(Polarity::Positive),
+            (Scope::Spectrum, "MS:1000129") => scan.set_polarity(Polarity::Negative),
+            (Scope::Spectrum, "MS:1000504") => {
+                scan.base_peak_mz = param.number()?;
+                self.has_base_peak = true;
+            }
+            (Scope::Spectrum, "MS:1000505") => scan.base_peak_intensity = param.number()?,
+            (Scope::Spectrum, "MS:1000285") => {
+                scan.total_ion_current = param.number()?;
+                self.has_total_ion_current = true;
+            }
+            (Scope::Scan, "MS:1000016") => {
+                let unit = param.unit_accession.as_deref().unwrap_or("UO:0000031");
+                let minutes = TIME_UNITS
+                    .iter()
+                    .find(|(accession, _)| *accession == unit)
+                    .map(|(_, minutes)| *minutes)
+                    .ok_or_else(|| {
+                        invalid_data(format!("Unknown scan start time unit {}", unit))
+                    })?;
+                scan.retention_time = param.number()? * minutes;
+            }
+            (Scope::IsolationWindow, "MS:1000827") => {
+                self.isolation_target = Some(param.number()?);
+            }
+            (Scope::IsolationWindow, "MS:1000828") => {
+                self.isolation_lower = Some(param.number()?);
+            }
+            (Scope::IsolationWindow, "MS:1000829") => {
+                self.isolation_upper = Some(param.number()?);
+            }
+            (Scope::SelectedIon, "MS:1000744") => scan.precursor_mass = Some(param.number()?),
+            (Scope::SelectedIon, "MS:1000041") => {
+                scan.precursor_charge = Some(param.number()? as i32);
+            }
+            (Scope::SelectedIon, "MS:1000042") => {
+                scan.precursor_intensity = Some(param.number()?);
+            }
+            (Scope::Activation, "MS:1000045") => scan.collision_energy = Some(param.number()?),
+            // Supplemental activation of ETD, as in EThcD
+            (Scope::Activation, "MS:1002678") => self.supplemental_activation = true,
+            (Scope::Activation, accession) => {
+                if let Some(fragmentation) = fragmentation_from_term(accession) {
+                    self.activations.push(fragmentation);
+                }
+            }
+            (Scope::BinaryDataArray, accession) => {
+                if let Some(array) = &mut self.array {
+                    array.apply(accession);
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Decodes the array just read into the scan
+    fn finish_array(&mut self) -> io::Result<()> {
+        let Some(array) = self.array.take() else {
+            return Ok(());
+        };
+        let Some(kind) = array.kind else {
+            // Other arrays, such as noise or charge, have no scan field
+            return Ok(());
+        };
+        let values = array
+            .decode(self.default_length)
+            .map_err(|e| invalid_data(format!("Spectrum {}: {}", self.id, e)))?;
+        match kind {
+            ArrayKind::Mz => self.scan.mz_values = values,
+            ArrayKind::Intensity => self.scan.intensity_values = values,
+        }
+        Ok(())
+    }
+
+    fn finish(mut self, analyzer: Option<Analyzer>) -> io::Result<ScanMessage> {
+        let scan = &mut self.scan;
+        if scan.mz_values.len() != scan.intensity_values.len() {
+            return Err(invalid_data(format!(
+                "Spectrum {} has {} m/z values but {} intensities",
+                self.id,
+                scan.mz_values.len(),
+                scan.intensity_values.len()
+            )));
+        }
+        if let Some(analyzer) = analyzer {
+            scan.analyzer = analyzer.name().to_string();
+        }
+
+        // Files without the summary terms get them from the arrays
+        if !self.has_base_peak {
+            let base_peak = scan
+                .mz_values
+                .iter()
+                .zip(&scan.intensity_values)
+                .max_by(|a, b| a.1.total_cmp(b.1));
+            if let Some((&mz, &intensity)) = base_peak {
+                scan.base_peak_mz = mz;
+                scan.base_peak_intensity = intensity;
+            }
+        }
+        if !self.has_total_ion_current {
+            scan.total_ion_current = scan.intensity_values.iter().sum();
+        }
+
+        if scan.ms_order > 1 {
+            if scan.precursor_mass.is_none() {
+                scan.precursor_mass = self.isolation_target;
+            }
+            if let (Some(lower), Some(upper)) = (self.isolation_lower, self.isolation_upper) {
+                scan.isolation_width = Some(lower + upper);
+            }
+            let etd = self
+                .activations
+                .contains(&FragmentationType::FragmentationEtd);
+            let beam_type = self
+                .activations
+                .contains(&FragmentationType::FragmentationHcd);
+            let fragmentation = if etd && (beam_type || self.supplemental_activation) {
+                FragmentationType::FragmentationEthcd
+            } else {
+                self.activations
+                    .first()
+                    .copied()
+                    .unwrap_or(FragmentationType::FragmentationUnknown)
+            };
+            scan.set_fragmentation_type(fragmentation);
+        } else {
+            // Precursors only describe MSn scans
+            scan.precursor_mass = None;
+            scan.precursor_charge = None;
+            scan.precursor_intensity = None;
+            scan.collision_energy = None;
+        }
+        Ok(self.scan)
+    }
+}
+
+impl ArrayBuilder {
+    fn apply(&mut self, accession: &str) {
+        match accession {
+            "MS:1000514" => self.kind = Some(ArrayKind::Mz),
+            "MS:1000515" => self.kind = Some(ArrayKind::Intensity),
+            "MS:1000521" => self.number = Some(NumberType::Float32),
+            "MS:1000523" => self.number = Some(NumberType::Float64),
+            "MS:1000519" => self.number = Some(NumberType::Int32),
+            "MS:1000522" => self.number = Some(NumberType::Int64),
+            "MS:1000574" => self.zlib = true,
+            "MS:1000576" => self.zlib = false,
+            accession => {
+                if let Some((_, name)) = UNSUPPORTED_COMPRESSIONS
+                    .iter()
+                    .find(|(unsupported, _)| *unsupported == accession)
+                {
+                    self.unsupported_compression = Some(name);
+                }
+            }
+        }
+    }
+
+    fn decode(self, default_length: usize) -> io::Result<Vec<f64>> {
+        if let Some(compression) = self.unsupported_compression {
+            return Err(invalid_data(format!(
+                "{} compression is not supported",
+                compression
+            )));
+        }
+        let number = self
+            .number
+            .ok_or_else(|| invalid_data("Binary array without a number type"))?;
+
+        let mut base64 = self.base64;
+        base64.retain(|byte| !byte.is_ascii_whitespace());
+        let mut bytes = STANDARD.decode(&base64).map_err(invalid_data)?;
+        if self.zlib {
+            let mut inflated = Vec::with_capacity(bytes.len() * 4);
+            ZlibDecoder::new(bytes.as_slice()).read_to_end(&mut inflated)?;
+            bytes = inflated;
+        }
+
+        let values: Vec<f64> = match number {
+            NumberType::Float32 => bytes
+                .chunks_exact(4)
+                .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]) as f64)
+                .collect(),
+            NumberType::Float64 => bytes
+                .chunks_exact(8)
+                .map(|b| f64::from_le_bytes(b.try_into().expect("8-byte chunk")))
+                .collect(),
+            NumberType::Int32 => bytes
+                .chunks_exact(4)
+                .map(|b| i32::from_le_bytes([b[0], b[1], b[2], b[3]]) as f64)
+                .collect(),
+            NumberType::Int64 => bytes
+                .chunks_exact(8)
+                .map(|b| i64::from_le_bytes(b.try_into().expect("8-byte chunk")) as f64)
+                .collect(),
+        };
+        let expected = self.length.unwrap_or(default_length);
+        if values.len() != expected {
+            return Err(invalid_data(format!(
+                "Binary array has {} values, expected {}",
+                values.len(),
+                expected
+            )));
+        }
+        Ok(values)
+    }
+}
+
+fn attribute(element: &BytesStart, name: &[u8]) -> io::Result<Option<String>> {
+    match element.try_get_attribute(name).map_err(invalid_data)? {
+        Some(attribute) => Ok(Some(
+            attribute
+                .unescape_value()
+                .map_err(invalid_data)?
+                .into_owned(),
+        )),
+        None => Ok(None),
+    }
+}
+
+fn required_attribute(element: &BytesStart, name: &[u8]) -> io::Result<String> {
+    attribute(element, name)?.ok_or_else(|| {
+        invalid_data(format!(
+            "<{}> without {}",
+            String::from_utf8_lossy(element.local_name().as_ref()),
+            String::from_utf8_lossy(name)
+        ))
+    })
+}
+
+fn parse_count(text: &str) -> io::Result<usize> {
+    text.trim()
+        .parse()
+        .map_err(|_| invalid_data(format!("{:?} is not a count", text)))
+}
+
+fn invalid_data(error: impl Into<Box<dyn std::error::Error + Send + Sync>>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, error)
+}
+
+/// The analyzer a PSI-MS mass analyzer term describes, if the simulator has it
+fn analyzer_from_term(accession: &str) -> Option<Analyzer> {
+    match accession {
+        "MS:1000484" => Some(Analyzer::Orbitrap),
+        // Ion traps: generic, quadrupole, linear, axial and radial ejection
+        "MS:1000264" | "MS:1000082" | "MS:1000291" | "MS:1000078" | "MS:1000083" => {
+            Some(Analyzer::IonTrap)
+        }
+        "MS:1000084" => Some(Analyzer::Astral),
+        _ => None,
+    }
+}
+
+/// The fragmentation a dissociation method term describes
+fn fragmentation_from_term(accession: &str) -> Option<FragmentationType> {
+    // Higher energy beam-type CID, as older converters name HCD
+    if accession == "MS:1002481" {
+        return Some(FragmentationType::FragmentationHcd);
+    }
+    [
+        FragmentationType::FragmentationCid,
+        FragmentationType::FragmentationHcd,
+        FragmentationType::FragmentationEtd,
+        FragmentationType::FragmentationEthcd,
+        FragmentationType::FragmentationUvpd,
+    ]
+    .into_iter()
+    .find(|&fragmentation| activation_term(fragmentation).0 == accession)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::malformed::{MalformedData, ScanCorruptor};
+
+    fn temp_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "lc-ms-simulator-{}-{}.mzML",
+            std::process::id(),
+            name
+        ));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    fn run(zlib: bool) -> RunInfo {
+        RunInfo {
+            run_id: "session-1".to_string(),
+            model: InstrumentModel::FusionLumos,
+            serial_number: "SIM-001".to_string(),
+            start_timestamp_ms: 1_700_000_000_000,
+            zlib,
+        }
+    }
+
+    fn scans() -> Vec<ScanMessage> {
+        let ms1 = ScanMessage {
+            scan_number: 1,
+            ms_order: 1,
+            retention_time: 12.345678,
+            mz_values: vec![400.123456789, 523.2771, 1099.999],
+            intensity_values: vec![1.5e6, 3.25e7, 0.0],
+            base_peak_mz: 523.2771,
+            base_peak_intensity: 3.25e7,
+            total_ion_current: 3.4e7,
+            analyzer: "Orbitrap".to_string(),
+            polarity: Polarity::Positive as i32,
+            ..Default::default()
+        };
+        let ms2 =
+            |scan_number: i32, analyzer: &str, fragmentation: FragmentationType| ScanMessage {
+                scan_number,
+                ms_order: 2,
+                retention_time: 12.35 + f64::from(scan_number) * 0.001,
+                mz_values: vec![147.1128, 262.1397, 523.2771],
+                intensity_values: vec![2.0e4, 8.5e4, 1.0e3],
+                base_peak_mz: 262.1397,
+                base_peak_intensity: 8.5e4,
+                total_ion_current: 1.06e5,
+                precursor_mass: Some(523.2771),
+                precursor_charge: Some(2),
+                precursor_intensity: Some(3.25e7),
+                isolation_width: Some(1.6),
+                collision_energy: Some(30.0),
+                fragmentation_type: fragmentation as i32,
+                analyzer: analyzer.to_string(),
+                polarity: Polarity::Positive as i32,
+                ..Default::default()
+            };
+        vec![
+            ms1,
+            ms2(2, "Orbitrap", FragmentationType::FragmentationHcd),
+            ms2(3, "IonTrap", FragmentationType::FragmentationCid),
+        ]
+    }
+
+    fn write(path: &Path, zlib: bool) -> Vec<ScanMessage> {
+        let scans = scans();
+        let mut writer = MzmlWriter::create(path, run(zlib)).unwrap();
+        for scan in &scans {
+            writer.write_spectrum(scan).unwrap();
+        }
+        assert_eq!(writer.spectrum_count(), scans.len());
+        writer.finish().unwrap();
+        scans
+    }
+
+    fn round_trip(zlib: bool) {
+        let path = temp_path(if zlib { "zlib" } else { "plain" });
+        let written = write(&path, zlib);
+
+        let mut reader = MzmlReader::open(&path).unwrap();
+        let mut read = Vec::new();
+        while let Some(scan) = reader.next_scan().unwrap() {
+            read.push(scan);
+        }
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(read, written);
+    }
+
+    #[test]
+    fn round_trip_uncompressed() {
+        round_trip(false);
+    }
+
+    #[test]
+    fn round_trip_zlib() {
+        round_trip(true);
+    }
+
+    #[test]
+    fn index_points_at_spectra() {
+        let path = temp_path("index");
+        let written = write(&path, true);
+        let file = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let between = |text: &str, start: &str, end: &str| -> String {
+            let from = text.find(start).unwrap() + start.len();
+            text[from..from + text[from..].find(end).unwrap()].to_string()
+        };
+        let index_offset: usize = between(&file, "<indexListOffset>", "<").parse().unwrap();
+        assert!(file[index_offset..].starts_with("<indexList count=\"1\">"));
+
+        let offsets: Vec<_> = file[index_offset..]
+            .match_indices("<offset idRef=\"")
+            .map(|(at, _)| {
+                let entry = &file[index_offset + at..];
+                let id = between(entry, "idRef=\"", "\"");
+                let offset: usize = between(entry, ">", "<").parse().unwrap();
+                (id, offset)
+            })
+            .collect();
+        assert_eq!(offsets.len(), written.len());
+        for ((id, offset), scan) in offsets.iter().zip(&written) {
+            assert_eq!(*id, format!("{}{}", NATIVE_ID_PREFIX, scan.scan_number));
+            assert!(file[*offset..].starts_with("<spectrum "));
+            assert_eq!(between(&file[*offset..], "id=\"", "\""), *id);
+        }
+
+        // The checksum covers everything up to its own opening tag
+        let checksum_start = file.find("<fileChecksum>").unwrap() + "<fileChecksum>".len();
+        let digest = digest::digest(
+            &digest::SHA1_FOR_LEGACY_USE_ONLY,
+            &file.as_bytes()[..checksum_start],
+        );
+        let expected: String = digest
+            .as_ref()
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+        assert_eq!(between(&file, "<fileChecksum>", "<"), expected);
+    }
+
+    #[test]
+    fn repeated_scan_numbers_get_unique_ids() {
+        let path = temp_path("repeated");
+        let defects = MalformedData {
+            repeated_scan_number: 1.0,
+            seed: Some(7),
+            ..Default::default()
+        };
+        let mut corruptor = ScanCorruptor::new(defects.seed);
+        let mut written = scans();
+        for scan in &mut written {
+            corruptor.corrupt(&defects, scan);
+        }
+        let scan_numbers: Vec<_> = written.iter().map(|scan| scan.scan_number).collect();
+        assert_eq!(scan_numbers, [1, 1, 2]);
+
+        let mut writer = MzmlWriter::create(&path, run(false)).unwrap();
+        for scan in &written {
+            writer.write_spectrum(scan).unwrap();
+        }
+        writer.finish().unwrap();
+        let file = fs::read_to_string(&path).unwrap();
+
+        let mut reader = MzmlReader::open(&path).unwrap();
+        let mut read = Vec::new();
+        while let Some(scan) = reader.next_scan().unwrap() {
+            read.push(scan.scan_number);
+        }
+        fs::remove_file(&path).unwrap();
+        assert_eq!(read, scan_numbers);
+
+        let ids: Vec<_> = file
+            .match_indices("<spectrum index=")
+            .map(|(at, _)| {
+                let from = at + file[at..].find("id=\"").unwrap() + "id=\"".len();
+                &file[from..from + file[from..].find('"').unwrap()]
+            })
+            .collect();
+        assert_eq!(
+            ids,
+            [
+                format!("{}1", NATIVE_ID_PREFIX),
+                format!("{}1 index=1", NATIVE_ID_PREFIX),
+                format!("{}2", NATIVE_ID_PREFIX),
+            ]
+        );
+    }
+
+    #[test]
+    fn refuses_to_overwrite() {
+        let path = temp_path("exists");
+        fs::write(&path, "").unwrap();
+        let result = MzmlWriter::create(&path, run(false));
+        fs::remove_file(&path).unwrap();
+        assert_eq!(result.err().unwrap().kind(), io::ErrorKind::AlreadyExists);
+    }
+}
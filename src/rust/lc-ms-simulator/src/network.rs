@@ -0,0 +1,308 @@
+//! Emulated network conditions on subscriber streams.
+//!
+//! Each stream gets a shaping task between the broadcast channel and the gRPC response
+//! that adds latency, jitter, a bandwidth cap, periodic stalls and random resets, so a
+//! client on localhost sees roughly what it would over a congested lab network. The
+//! global impairment comes from the fault plan and follows `SetFaultPlan` changes; a
+//! subscriber can replace it for its own stream with `StreamScansRequest.impairment`.
+
+use std::collections::VecDeque;
+use std::time::Duration;
+
+use prost::Message;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use rand_distr::{Distribution, Exp};
+use serde::{Deserialize, Serialize};
+use tokio::sync::{mpsc, watch};
+use tokio::time::Instant;
+use tokio_stream::wrappers::ReceiverStream;
+use tokio_stream::{Stream, StreamExt};
+use tonic::Status;
+
+use crate::proto;
+
+/// Messages held back by the shaper before it stops reading; beyond this the
+/// subscriber lags on the broadcast channel like any slow client
+const QUEUE_CAPACITY: usize = 1024;
+
+const RESET_MESSAGE: &str = "Injected stream reset";
+
+/// Upper bound on `latency_ms` and `jitter_ms`
+const MAX_DELAY_MS: f64 = 60_000.0;
+
+/// Slowest link a nonzero `bandwidth_kbps` may describe
+const MIN_BANDWIDTH_KBPS: f64 = 1.0;
+
+/// Upper bound on the stall and reset periods (one day)
+const MAX_PERIOD_SECONDS: f64 = 86_400.0;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NetworkImpairment {
+    pub latency_ms: f64,
+    /// Random extra delay of up to this much per message
+    pub jitter_ms: f64,
+    /// Link capacity in kilobits per second (0 = unlimited)
+    pub bandwidth_kbps: f64,
+    pub stall_interval_seconds: f64,
+    pub stall_duration_seconds: f64,
+    /// Mean time between resets that end the stream with UNAVAILABLE (0 = never)
+    pub mean_seconds_between_resets: f64,
+}
+
+impl NetworkImpairment {
+    /// Validates a subscriber's own impairment. Returns `None` when the request leaves
+    /// it unset, so the stream follows the fault plan.
+    pub fn from_request(
+        impairment: Option<proto::NetworkImpairment>,
+    ) -> Result<Option<Self>, Status> {
+        let Some(impairment) = impairment else {
+            return Ok(None);
+        };
+
+        let impairment = Self::from(impairment);
+        impairment
+            .validate()
+            .map_err(|e| Status::invalid_argument(format!("impairment.{}", e)))?;
+        Ok(Some(impairment))
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        for (name, value) in [
+            ("latency_ms", self.latency_ms),
+            ("jitter_ms", self.jitter_ms),
+            ("bandwidth_kbps", self.bandwidth_kbps),
+            ("stall_interval_seconds", self.stall_interval_seconds),
+            ("stall_duration_seconds", self.stall_duration_seconds),
+            (
+                "mean_seconds_between_resets",
+                self.mean_seconds_between_resets,
+            ),
+        ] {
+            if !value.is_finite() || value < 0.0 {
+                return Err(format!("{} must not be negative", name));
+            }
+        }
+        for (name, value) in [
+            ("latency_ms", self.latency_ms),
+            ("jitter_ms", self.jitter_ms),
+        ] {
+            if value > MAX_DELAY_MS {
+                return Err(format!("{} must be at most {}", name, MAX_DELAY_MS));
+            }
+        }
+        if self.bandwidth_kbps > 0.0 && self.bandwidth_kbps < MIN_BANDWIDTH_KBPS {
+            return Err(format!(
+                "bandwidth_kbps must be 0 (unlimited) or at least {}",
+                MIN_BANDWIDTH_KBPS
+            ));
+        }
+        for (name, value) in [
+            ("stall_interval_seconds", self.stall_interval_seconds),
+            ("stall_duration_seconds", self.stall_duration_seconds),
+            (
+                "mean_seconds_between_resets",
+                self.mean_seconds_between_resets,
+            ),
+        ] {
+            if value > MAX_PERIOD_SECONDS {
+                return Err(format!("{} must be at most {}", name, MAX_PERIOD_SECONDS));
+            }
+        }
+        if self.stall_duration_seconds > 0.0
+            && self.stall_duration_seconds >= self.stall_interval_seconds
+        {
+            return Err(
+                "stall_duration_seconds must be shorter than stall_interval_seconds".to_string(),
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Delivers `inner` through a shaping task. `local` fixes the impairment for this
+/// stream; without it the stream follows `global`.
+pub fn impair<S, T>(
+    inner: S,
+    local: Option<NetworkImpairment>,
+    global: watch::Receiver<NetworkImpairment>,
+) -> ReceiverStream<Result<T, Status>>
+where
+    S: Stream<Item = Result<T, Status>> + Send + Unpin + 'static,
+    T: Message + Send + 'static,
+{
+    let (sender, receiver) = mpsc::channel(1);
+    tokio::spawn(Shaper::new(local, global).run(inner, sender));
+    ReceiverStream::new(receiver)
+}
+
+struct Shaper {
+    local: Option<NetworkImpairment>,
+    global: watch::Receiver<NetworkImpairment>,
+    random: StdRng,
+    opened: Instant,
+    /// When the link finishes transmitting the previous message
+    link_free_at: Instant,
+    /// Delivery time of the previous message; later messages never overtake it
+    last_due: Instant,
+    reset_at: Option<Instant>,
+}
+
+impl Shaper {
+    fn new(local: Option<NetworkImpairment>, global: watch::Receiver<NetworkImpairment>) -> Self {
+        let now = Instant::now();
+        let mut shaper = Self {
+            local,
+            global,
+            random: StdRng::from_entropy(),
+            opened: now,
+            link_free_at: now,
+            last_due: now,
+            reset_at: None,
+        };
+        shaper.schedule_reset();
+        shaper
+    }
+
+    fn config(&self) -> NetworkImpairment {
+        self.local.unwrap_or_else(|| *self.global.borrow())
+    }
+
+    fn schedule_reset(&mut self) {
+        let mean = self.config().mean_seconds_between_resets;
+        self.reset_at = match Exp::new(1.0 / mean) {
+            // A draw too far out to represent never comes due
+            Ok(exp) if mean > 0.0 => after(Instant::now(), exp.sample(&mut self.random)).ok(),
+            _ => None,
+        };
+    }
+
+    /// Works out when a message that arrived at `arrival` reaches the subscriber
+    fn due(&mut self, arrival: Instant, bytes: usize) -> Result<Instant, Status> {
+        let config = self.config();
+
+        let mut sent = arrival.max(self.link_free_at);
+        if config.bandwidth_kbps > 0.0 {
+            sent = after(sent, bytes as f64 * 8.0 / (config.bandwidth_kbps * 1000.0))?;
+        }
+        self.link_free_at = sent;
+
+        let mut delay = config.latency_ms;
+        if config.jitter_ms > 0.0 {
+            delay += self.random.gen_range(0.0..config.jitter_ms);
+        }
+        let mut due = after(sent, delay / 1000.0)?.max(self.last_due);
+
+        if config.stall_interval_seconds > 0.0 && config.stall_duration_seconds > 0.0 {
+            let elapsed = due.duration_since(self.opened).as_secs_f64();
+            let stall_start =
+                (elapsed / config.stall_interval_seconds).floor() * config.stall_interval_seconds;
+            if stall_start > 0.0 && elapsed < stall_start + config.stall_duration_seconds {
+                due = after(self.opened, stall_start + config.stall_duration_seconds)?;
+            }
+        }
+
+        self.last_due = due;
+        Ok(due)
+    }
+
+    async fn run<S, T>(mut self, mut inner: S, sender: mpsc::Sender<Result<T, Status>>)
+    where
+        S: Stream<Item = Result<T, Status>> + Unpin,
+        T: Message,
+    {
+        let mut queue: VecDeque<(Instant, Result<T, Status>)> = VecDeque::new();
+        let mut next_due = None;
+        let mut inner_done = false;
+
+        loop {
+            if inner_done && queue.is_empty() {
+                return;
+            }
+            if next_due.is_none() {
+                if let Some((arrival, item)) = queue.front() {
+                    let bytes = item.as_ref().map_or(0, T::encoded_len);
+                    match self.due(*arrival, bytes) {
+                        Ok(due) => next_due = Some(due),
+                        Err(status) => {
+                            let _ = sender.send(Err(status)).await;
+                            return;
+                        }
+                    }
+                }
+            }
+
+            tokio::select! {
+                biased;
+                _ = sender.closed() => return,
+                _ = sleep_until(self.reset_at) => {
+                    let _ = sender.send(Err(Status::unavailable(RESET_MESSAGE))).await;
+                    return;
+                }
+                _ = sleep_until(next_due) => {
+                    next_due = None;
+                    if let Some((_, item)) = queue.pop_front() {
+                        if sender.send(item).await.is_err() {
+                            return;
+                        }
+                    }
+                }
+                item = inner.next(), if !inner_done && queue.len() < QUEUE_CAPACITY => {
+                    match item {
+                        Some(item) => queue.push_back((Instant::now(), item)),
+                        None => inner_done = true,
+                    }
+                }
+                Ok(()) = self.global.changed(), if self.local.is_none() => self.schedule_reset(),
+            }
+        }
+    }
+}
+
+/// `seconds` after `instant`, or an error when that is not a representable time
+fn after(instant: Instant, seconds: f64) -> Result<Instant, Status> {
+    Duration::try_from_secs_f64(seconds)
+        .ok()
+        .and_then(|delay| instant.checked_add(delay))
+        .ok_or_else(|| {
+            Status::internal(format!(
+                "Impaired delivery time out of range ({}s)",
+                seconds
+            ))
+        })
+}
+
+/// Sleeps until `deadline`, or forever without one
+async fn sleep_until(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => tokio::time::sleep_until(deadline).await,
+        None => std::future::pending().await,
+    }
+}
+
+impl From<proto::NetworkImpairment> for NetworkImpairment {
+    fn from(impairment: proto::NetworkImpairment) -> Self {
+        Self {
+            latency_ms: impairment.latency_ms,
+            jitter_ms: impairment.jitter_ms,
+            bandwidth_kbps: impairment.bandwidth_kbps,
+            stall_interval_seconds: impairment.stall_interval_seconds,
+            stall_duration_seconds: impairment.stall_duration_seconds,
+            mean_seconds_between_resets: impairment.mean_seconds_between_resets,
+        }
+    }
+}
+
+impl From<NetworkImpairment> for proto::NetworkImpairment {
+    fn from(impairment: NetworkImpairment) -> Self {
+        Self {
+            latency_ms: impairment.latency_ms,
+            jitter_ms: impairment.jitter_ms,
+            bandwidth_kbps: impairment.bandwidth_kbps,
+            stall_interval_seconds: impairment.stall_interval_seconds,
+            stall_duration_seconds: impairment.stall_duration_seconds,
+            mean_seconds_between_resets: impairment.mean_seconds_between_resets,
+        }
+    }
+}
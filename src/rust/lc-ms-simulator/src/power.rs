@@ -0,0 +1,155 @@
+//! Instrument power mode and readiness.
+//!
+//! The instrument is Off (vented), in Standby (under vacuum, electronics idle) or On.
+//! Leaving Off pumps the instrument down, and switching On warms it up, so it reports
+//! Not Ready for a while before it becomes Ready. Acquisitions only start when Ready.
+
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+
+use crate::proto::{self, InstrumentState};
+
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PowerMode {
+    Off,
+    Standby,
+    #[default]
+    On,
+}
+
+impl PowerMode {
+    /// Converts a requested mode; `None` for an unknown or unset value
+    pub fn from_proto(mode: i32) -> Option<Self> {
+        match proto::InstrumentMode::try_from(mode).ok()? {
+            proto::InstrumentMode::Unknown => None,
+            proto::InstrumentMode::Off => Some(Self::Off),
+            proto::InstrumentMode::Standby => Some(Self::Standby),
+            proto::InstrumentMode::On => Some(Self::On),
+        }
+    }
+}
+
+impl From<PowerMode> for proto::InstrumentMode {
+    fn from(mode: PowerMode) -> Self {
+        match mode {
+            PowerMode::Off => Self::Off,
+            PowerMode::Standby => Self::Standby,
+            PowerMode::On => Self::On,
+        }
+    }
+}
+
+/// Readiness at one point in time
+pub struct Readiness {
+    pub mode: PowerMode,
+    pub state: InstrumentState,
+    /// Why the instrument is not ready; empty when Ready, Off or in Standby
+    pub reason: &'static str,
+    pub seconds_until_ready: f64,
+}
+
+impl Readiness {
+    pub fn is_ready(&self) -> bool {
+        self.state == InstrumentState::Ready
+    }
+
+    pub fn to_response(&self) -> proto::InstrumentModeResponse {
+        proto::InstrumentModeResponse {
+            mode: proto::InstrumentMode::from(self.mode) as i32,
+            state: self.state as i32,
+            not_ready_reason: self.reason.to_string(),
+            seconds_until_ready: self.seconds_until_ready,
+        }
+    }
+}
+
+struct PowerState {
+    mode: PowerMode,
+    /// When the vacuum is good; `None` while vented
+    vacuum_ready_at: Option<Instant>,
+    /// When an instrument switched On finishes warming up
+    warm_at: Option<Instant>,
+}
+
+pub struct Power {
+    state: Mutex<PowerState>,
+    pump_down: Duration,
+    warm_up: Duration,
+}
+
+impl Power {
+    /// Starts in `mode` as if the instrument had been there for a long time, so On is
+    /// immediately Ready
+    pub fn new(mode: PowerMode, pump_down_seconds: f64, warm_up_seconds: f64) -> Self {
+        let now = Instant::now();
+        Self {
+            state: Mutex::new(PowerState {
+                mode,
+                vacuum_ready_at: (mode != PowerMode::Off).then_some(now),
+                warm_at: (mode == PowerMode::On).then_some(now),
+            }),
+            pump_down: Duration::from_secs_f64(pump_down_seconds),
+            warm_up: Duration::from_secs_f64(warm_up_seconds),
+        }
+    }
+
+    /// Changes mode; returns the previous mode
+    pub fn set_mode(&self, mode: PowerMode) -> PowerMode {
+        let mut state = self.state.lock().unwrap();
+        let previous = state.mode;
+        let now = Instant::now();
+
+        match mode {
+            PowerMode::Off => {
+                state.vacuum_ready_at = None;
+                state.warm_at = None;
+            }
+            PowerMode::Standby | PowerMode::On => {
+                let vacuum_ready_at = *state.vacuum_ready_at.get_or_insert(now + self.pump_down);
+                if mode == PowerMode::Standby {
+                    state.warm_at = None;
+                } else if previous != PowerMode::On {
+                    // Electronics only stabilize once the vacuum is good
+                    state.warm_at = Some(vacuum_ready_at.max(now) + self.warm_up);
+                }
+            }
+        }
+        state.mode = mode;
+        previous
+    }
+
+    pub fn readiness(&self) -> Readiness {
+        let state = self.state.lock().unwrap();
+        let now = Instant::now();
+        let remaining = |at: Option<Instant>| {
+            at.map_or(0.0, |at| at.saturating_duration_since(now).as_secs_f64())
+        };
+
+        let (instrument_state, reason) = match state.mode {
+            PowerMode::Off => (InstrumentState::Off, ""),
+            PowerMode::Standby => (InstrumentState::Standby, ""),
+            PowerMode::On if remaining(state.vacuum_ready_at) > 0.0 => {
+                (InstrumentState::NotReady, "Pumping down")
+            }
+            PowerMode::On if remaining(state.warm_at) > 0.0 => {
+                (InstrumentState::NotReady, "Warming up")
+            }
+            PowerMode::On => (InstrumentState::Ready, ""),
+        };
+
+        Readiness {
+            mode: state.mode,
+            state: instrument_state,
+            reason,
+            seconds_until_ready: if state.mode == PowerMode::On {
+                remaining(state.warm_at)
+            } else {
+                0.0
+            },
+        }
+    }
+}
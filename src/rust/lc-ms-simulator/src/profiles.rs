@@ -0,0 +1,337 @@
+//! Instrument model profiles.
+//!
+//! A profile describes what a real instrument can do: its Orbitrap resolution settings,
+//! maximum scan speed, analyzers, fragmentation types and m/z limits. The selected
+//! profile is reported by `GetInstrumentInfo`, and `StartAcquisition` requests that ask
+//! for more than it supports are rejected.
+
+use std::cmp::Ordering;
+
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+
+use crate::proto::{FragmentationType, SimulationParameters};
+use crate::tune::{TuneParameter, OPTAMAX_TUNE, Q_EXACTIVE_TUNE};
+
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum InstrumentModel {
+    QExactiveHf,
+    #[value(name = "exploris-240")]
+    #[serde(rename = "exploris-240")]
+    Exploris240,
+    #[default]
+    #[value(name = "exploris-480")]
+    #[serde(rename = "exploris-480")]
+    Exploris480,
+    FusionLumos,
+    Eclipse,
+    Astral,
+    /// No capability limits, for load testing beyond real instrument speeds
+    Generic,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Analyzer {
+    Orbitrap,
+    IonTrap,
+    Astral,
+}
+
+impl Analyzer {
+    pub fn name(self) -> &'static str {
+        match self {
+            Analyzer::Orbitrap => "Orbitrap",
+            Analyzer::IonTrap => "IonTrap",
+            Analyzer::Astral => "Astral",
+        }
+    }
+
+    /// Resolution at m/z 200 and mass accuracy (ppm) reported for MS2 scans
+    fn ms2_performance(self) -> (f64, f64) {
+        match self {
+            Analyzer::Orbitrap => (30000.0, 5.0),
+            Analyzer::IonTrap => (2000.0, 200.0),
+            Analyzer::Astral => (80000.0, 3.0),
+        }
+    }
+}
+
+pub struct InstrumentProfile {
+    pub model: &'static str,
+    pub firmware_version: &'static str,
+    /// Selectable Orbitrap resolutions at m/z 200
+    pub resolutions: &'static [f64],
+    /// MS1 resolution when a request does not choose one
+    pub default_resolution: f64,
+    /// Total scans per second (MS1 + MS2); `None` means unlimited
+    pub max_scan_rate: Option<f64>,
+    pub analyzers: &'static [Analyzer],
+    /// Analyzer used for MS2 scans when a request does not choose one
+    pub default_ms2_analyzer: Analyzer,
+    /// The first entry is used when a request does not choose one
+    pub fragmentation_types: &'static [FragmentationType],
+    pub min_mz: f64,
+    pub max_mz: f64,
+    /// Ion source values served by `GetInstrumentValues`/`SetInstrumentValues`
+    pub tune_parameters: &'static [TuneParameter],
+}
+
+/// Per-session generation settings, checked against the instrument profile
+#[derive(Debug, Clone, Copy)]
+pub struct ScanSettings {
+    pub ms1_resolution: f64,
+    pub ms2_analyzer: Analyzer,
+    pub ms2_resolution: f64,
+    pub ms2_mass_accuracy_ppm: f64,
+    pub fragmentation_type: FragmentationType,
+}
+
+impl Default for ScanSettings {
+    fn default() -> Self {
+        InstrumentModel::default().profile().default_scan_settings()
+    }
+}
+
+const ORBITRAP_ONLY: &[Analyzer] = &[Analyzer::Orbitrap];
+const TRIBRID: &[Analyzer] = &[Analyzer::Orbitrap, Analyzer::IonTrap];
+const HCD_ONLY: &[FragmentationType] = &[FragmentationType::FragmentationHcd];
+const TRIBRID_FRAGMENTATION: &[FragmentationType] = &[
+    FragmentationType::FragmentationHcd,
+    FragmentationType::FragmentationCid,
+    FragmentationType::FragmentationEtd,
+    FragmentationType::FragmentationEthcd,
+    FragmentationType::FragmentationUvpd,
+];
+
+static Q_EXACTIVE_HF: InstrumentProfile = InstrumentProfile {
+    model: "Q Exactive HF",
+    firmware_version: "2.11",
+    resolutions: &[15000.0, 30000.0, 45000.0, 60000.0, 120000.0, 240000.0],
+    default_resolution: 120000.0,
+    max_scan_rate: Some(18.0),
+    analyzers: ORBITRAP_ONLY,
+    default_ms2_analyzer: Analyzer::Orbitrap,
+    fragmentation_types: HCD_ONLY,
+    min_mz: 50.0,
+    max_mz: 6000.0,
+    tune_parameters: Q_EXACTIVE_TUNE,
+};
+
+static EXPLORIS_240: InstrumentProfile = InstrumentProfile {
+    model: "Orbitrap Exploris 240",
+    firmware_version: "4.2",
+    resolutions: &[15000.0, 30000.0, 60000.0, 120000.0, 240000.0],
+    default_resolution: 120000.0,
+    max_scan_rate: Some(22.0),
+    analyzers: ORBITRAP_ONLY,
+    default_ms2_analyzer: Analyzer::Orbitrap,
+    fragmentation_types: HCD_ONLY,
+    min_mz: 40.0,
+    max_mz: 6000.0,
+    tune_parameters: OPTAMAX_TUNE,
+};
+
+static EXPLORIS_480: InstrumentProfile = InstrumentProfile {
+    model: "Orbitrap Exploris 480",
+    firmware_version: "4.2",
+    resolutions: &[
+        7500.0, 15000.0, 30000.0, 45000.0, 60000.0, 120000.0, 240000.0, 480000.0,
+    ],
+    default_resolution: 120000.0,
+    max_scan_rate: Some(40.0),
+    analyzers: ORBITRAP_ONLY,
+    default_ms2_analyzer: Analyzer::Orbitrap,
+    fragmentation_types: HCD_ONLY,
+    min_mz: 40.0,
+    max_mz: 6000.0,
+    tune_parameters: OPTAMAX_TUNE,
+};
+
+static FUSION_LUMOS: InstrumentProfile = InstrumentProfile {
+    model: "Orbitrap Fusion Lumos",
+    firmware_version: "3.5",
+    resolutions: &[
+        7500.0, 15000.0, 30000.0, 50000.0, 60000.0, 120000.0, 240000.0, 500000.0,
+    ],
+    default_resolution: 120000.0,
+    max_scan_rate: Some(40.0),
+    analyzers: TRIBRID,
+    default_ms2_analyzer: Analyzer::Orbitrap,
+    fragmentation_types: TRIBRID_FRAGMENTATION,
+    min_mz: 50.0,
+    max_mz: 6000.0,
+    tune_parameters: OPTAMAX_TUNE,
+};
+
+static ECLIPSE: InstrumentProfile = InstrumentProfile {
+    model: "Orbitrap Eclipse",
+    firmware_version: "4.0",
+    resolutions: &[
+        7500.0, 15000.0, 30000.0, 50000.0, 60000.0, 120000.0, 240000.0, 500000.0,
+    ],
+    default_resolution: 120000.0,
+    max_scan_rate: Some(45.0),
+    analyzers: TRIBRID,
+    default_ms2_analyzer: Analyzer::Orbitrap,
+    fragmentation_types: TRIBRID_FRAGMENTATION,
+    min_mz: 50.0,
+    max_mz: 8000.0,
+    tune_parameters: OPTAMAX_TUNE,
+};
+
+static ASTRAL: InstrumentProfile = InstrumentProfile {
+    model: "Orbitrap Astral",
+    firmware_version: "1.1",
+    resolutions: &[
+        7500.0, 15000.0, 30000.0, 45000.0, 60000.0, 120000.0, 240000.0, 480000.0,
+    ],
+    default_resolution: 240000.0,
+    max_scan_rate: Some(200.0),
+    analyzers: &[Analyzer::Orbitrap, Analyzer::Astral],
+    default_ms2_analyzer: Analyzer::Astral,
+    fragmentation_types: HCD_ONLY,
+    min_mz: 40.0,
+    max_mz: 8000.0,
+    tune_parameters: OPTAMAX_TUNE,
+};
+
+static GENERIC: InstrumentProfile = InstrumentProfile {
+    model: "Simulated Orbitrap",
+    firmware_version: "1.0.0",
+    resolutions: &[
+        7500.0, 15000.0, 30000.0, 45000.0, 50000.0, 60000.0, 120000.0, 240000.0, 480000.0,
+        500000.0, 1000000.0,
+    ],
+    default_resolution: 120000.0,
+    max_scan_rate: None,
+    analyzers: &[Analyzer::Orbitrap, Analyzer::IonTrap, Analyzer::Astral],
+    default_ms2_analyzer: Analyzer::Orbitrap,
+    fragmentation_types: TRIBRID_FRAGMENTATION,
+    min_mz: 10.0,
+    max_mz: 10000.0,
+    tune_parameters: OPTAMAX_TUNE,
+};
+
+impl InstrumentModel {
+    pub fn profile(self) -> &'static InstrumentProfile {
+        match self {
+            InstrumentModel::QExactiveHf => &Q_EXACTIVE_HF,
+            InstrumentModel::Exploris240 => &EXPLORIS_240,
+            InstrumentModel::Exploris480 => &EXPLORIS_480,
+            InstrumentModel::FusionLumos => &FUSION_LUMOS,
+            InstrumentModel::Eclipse => &ECLIPSE,
+            InstrumentModel::Astral => &ASTRAL,
+            InstrumentModel::Generic => &GENERIC,
+        }
+    }
+
+    /// Profile name as used in configuration
+    pub fn name(self) -> String {
+        self.to_possible_value()
+            .map(|value| value.get_name().to_string())
+            .unwrap_or_default()
+    }
+}
+
+impl InstrumentProfile {
+    pub fn max_resolution(&self) -> f64 {
+        self.resolutions.iter().copied().fold(0.0, f64::max)
+    }
+
+    pub fn default_scan_settings(&self) -> ScanSettings {
+        let (ms2_resolution, ms2_mass_accuracy_ppm) = self.default_ms2_analyzer.ms2_performance();
+        ScanSettings {
+            ms1_resolution: self.default_resolution,
+            ms2_analyzer: self.default_ms2_analyzer,
+            ms2_resolution,
+            ms2_mass_accuracy_ppm,
+            fragmentation_type: self.fragmentation_types[0],
+        }
+    }
+
+    /// Checks resolved simulation parameters against the instrument's capabilities and
+    /// picks the profile defaults for choices the request leaves open
+    pub fn scan_settings(&self, params: &SimulationParameters) -> Result<ScanSettings, String> {
+        if let Some(max) = self.max_scan_rate {
+            if params.scan_rate > max {
+                return Err(format!(
+                    "scan_rate {} exceeds the {} maximum of {} scans/s",
+                    params.scan_rate, self.model, max
+                ));
+            }
+        }
+        if params.min_mz.partial_cmp(&params.max_mz) != Some(Ordering::Less) {
+            return Err(format!("m/z range {}-{} is empty", params.min_mz, params.max_mz));
+        }
+        if params.min_mz < self.min_mz || params.max_mz > self.max_mz {
+            return Err(format!(
+                "m/z range {}-{} is outside the {} range of {}-{}",
+                params.min_mz, params.max_mz, self.model, self.min_mz, self.max_mz
+            ));
+        }
+
+        let ms1_resolution = if params.resolution > 0.0 {
+            if !self.resolutions.contains(&params.resolution) {
+                return Err(format!(
+                    "resolution {} is not available on the {} (choose one of {:?})",
+                    params.resolution, self.model, self.resolutions
+                ));
+            }
+            params.resolution
+        } else {
+            self.default_resolution
+        };
+
+        let fragmentation_type = match FragmentationType::try_from(params.fragmentation_type) {
+            Ok(FragmentationType::FragmentationUnknown) => self.fragmentation_types[0],
+            Ok(fragmentation) if self.fragmentation_types.contains(&fragmentation) => fragmentation,
+            Ok(fragmentation) => {
+                return Err(format!(
+                    "fragmentation type {} is not available on the {}",
+                    fragmentation.as_str_name(),
+                    self.model
+                ))
+            }
+            Err(_) => {
+                return Err(format!(
+                    "Unknown fragmentation type {}",
+                    params.fragmentation_type
+                ))
+            }
+        };
+
+        let ms2_analyzer = if params.ms2_analyzer.is_empty() {
+            self.default_ms2_analyzer
+        } else {
+            *self
+                .analyzers
+                .iter()
+                .find(|analyzer| analyzer.name().eq_ignore_ascii_case(&params.ms2_analyzer))
+                .ok_or_else(|| {
+                    format!(
+                        "analyzer '{}' is not available on the {} (choose one of {})",
+                        params.ms2_analyzer,
+                        self.model,
+                        self.analyzer_names().join(", ")
+                    )
+                })?
+        };
+        let (ms2_resolution, ms2_mass_accuracy_ppm) = ms2_analyzer.ms2_performance();
+
+        Ok(ScanSettings {
+            ms1_resolution,
+            ms2_analyzer,
+            ms2_resolution,
+            ms2_mass_accuracy_ppm,
+            fragmentation_type,
+        })
+    }
+
+    pub fn analyzer_names(&self) -> Vec<String> {
+        self.analyzers
+            .iter()
+            .map(|analyzer| analyzer.name().to_string())
+            .collect()
+    }
+}
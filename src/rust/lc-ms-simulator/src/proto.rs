@@ -1,3 +1,111 @@
+#![allow(clippy::enum_variant_names, clippy::large_enum_variant)]
+
 // Include the generated protobuf code
 // This file will be generated by tonic-build during compilation
 tonic::include_proto!("orbitrap.simulator.v1");
+
+/// Encoded file descriptor set used by the gRPC reflection service
+pub const FILE_DESCRIPTOR_SET: &[u8] = tonic::include_file_descriptor_set!("simulator_descriptor");
+
+/// Serde adapters for enum fields, which prost stores as `i32`. JSON carries the proto
+/// value name, e.g. `"ACQUISITION_STATE_IDLE"`; the number is accepted as input too.
+pub mod json {
+    use std::marker::PhantomData;
+
+    use serde::ser::SerializeSeq;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    /// A generated proto enumeration
+    pub trait ProtoEnum: TryFrom<i32> + Into<i32> {
+        fn name(self) -> &'static str;
+        fn from_name(name: &str) -> Option<Self>;
+    }
+
+    macro_rules! proto_enums {
+        ($($enumeration:ident),*) => {
+            $(
+                impl ProtoEnum for super::$enumeration {
+                    fn name(self) -> &'static str {
+                        self.as_str_name()
+                    }
+
+                    fn from_name(name: &str) -> Option<Self> {
+                        Self::from_str_name(name)
+                    }
+                }
+            )*
+        };
+    }
+
+    proto_enums!(
+        MzEncoding,
+        IntensityEncoding,
+        Polarity,
+        FragmentationType,
+        AcquisitionState,
+        InstrumentMode,
+        InstrumentState,
+        ReplayPacing,
+        RecordingFormat,
+        StreamLogCompression
+    );
+
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum NameOrNumber {
+        Name(String),
+        Number(i32),
+    }
+
+    impl NameOrNumber {
+        fn value<E: ProtoEnum, Error: serde::de::Error>(self) -> Result<i32, Error> {
+            match self {
+                NameOrNumber::Number(value) => Ok(value),
+                NameOrNumber::Name(name) => E::from_name(&name)
+                    .map(Into::into)
+                    .ok_or_else(|| Error::custom(format!("unknown enum value '{}'", name))),
+            }
+        }
+    }
+
+    /// `#[serde(with)]` adapter for a singular enum field
+    pub struct EnumName<E>(PhantomData<E>);
+
+    impl<E: ProtoEnum> EnumName<E> {
+        pub fn serialize<S: Serializer>(value: &i32, serializer: S) -> Result<S::Ok, S::Error> {
+            match E::try_from(*value) {
+                Ok(known) => serializer.serialize_str(known.name()),
+                Err(_) => serializer.serialize_i32(*value),
+            }
+        }
+
+        pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<i32, D::Error> {
+            NameOrNumber::deserialize(deserializer)?.value::<E, _>()
+        }
+    }
+
+    /// `#[serde(with)]` adapter for a repeated enum field
+    pub struct EnumNames<E>(PhantomData<E>);
+
+    impl<E: ProtoEnum> EnumNames<E> {
+        pub fn serialize<S: Serializer>(values: &[i32], serializer: S) -> Result<S::Ok, S::Error> {
+            let mut seq = serializer.serialize_seq(Some(values.len()))?;
+            for &value in values {
+                match E::try_from(value) {
+                    Ok(known) => seq.serialize_element(known.name())?,
+                    Err(_) => seq.serialize_element(&value)?,
+                }
+            }
+            seq.end()
+        }
+
+        pub fn deserialize<'de, D: Deserializer<'de>>(
+            deserializer: D,
+        ) -> Result<Vec<i32>, D::Error> {
+            Vec::<NameOrNumber>::deserialize(deserializer)?
+                .into_iter()
+                .map(|value| value.value::<E, D::Error>())
+                .collect()
+        }
+    }
+}
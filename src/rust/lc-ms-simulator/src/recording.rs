@@ -0,0 +1,205 @@
+//! Session recordings on the simulator host.
+//!
+//! A `StartAcquisition` with `recording` set writes the session to a file in the
+//! recording directory: an indexed mzML file, as an instrument writes its raw file, or a
+//! stream log with every event of the session and when it was published, for exact
+//! replay. Scans are recorded exactly as they were published, injected data faults
+//! included. A blocking task does the writing, so disk I/O never stalls the acquisition
+//! loop, and the file is finalized when the session ends, whether it was stopped,
+//! completed or faulted.
+
+use std::fs::File;
+use std::io::{self, BufWriter};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tonic::Status;
+use tracing::{info, warn};
+
+use crate::config::{InstrumentConfig, RecordingConfig};
+use crate::mzml::{MzmlWriter, RunInfo};
+use crate::proto::stream_event::Event;
+use crate::proto::{
+    RecordingFormat, RecordingOptions, StreamEvent, StreamLogCompression, StreamLogHeader,
+};
+use crate::simulator::current_timestamp_ms;
+use crate::stream_log::{self, StreamLogWriter};
+
+/// Events queued for the writer; a full queue slows the acquisition rather than losing
+/// events
+const QUEUE_CAPACITY: usize = 1024;
+
+/// Longest accepted `RecordingOptions.name`
+const MAX_NAME_LENGTH: usize = 200;
+
+pub struct Recorder {
+    path: PathBuf,
+    sender: mpsc::Sender<(Duration, StreamEvent)>,
+    writer: JoinHandle<io::Result<String>>,
+    /// When the first event was recorded, which stream log times count from
+    origin: Option<Instant>,
+}
+
+impl Recorder {
+    /// Starts recording a session; fails if the file name is taken
+    pub fn start(
+        config: &RecordingConfig,
+        options: &RecordingOptions,
+        instrument: &InstrumentConfig,
+        session_id: &str,
+    ) -> Result<Self, Status> {
+        let name = if options.name.is_empty() {
+            // Instrument IDs may contain characters that file names cannot
+            let id: String = instrument
+                .id
+                .chars()
+                .map(|c| if is_name_char(c) { c } else { '_' })
+                .collect();
+            format!("{}_{}", id, session_id)
+        } else {
+            validate_name(&options.name)?;
+            options.name.clone()
+        };
+
+        std::fs::create_dir_all(&config.directory).map_err(|e| {
+            Status::internal(format!(
+                "Cannot create recording directory {}: {}",
+                config.directory.display(),
+                e
+            ))
+        })?;
+        let start_timestamp_ms = current_timestamp_ms();
+        let (path, writer) = match options.format() {
+            RecordingFormat::Mzml => {
+                let path = config.directory.join(format!("{}.mzML", name));
+                let run = RunInfo {
+                    run_id: session_id.to_string(),
+                    model: instrument.profile,
+                    serial_number: instrument.serial_number(),
+                    start_timestamp_ms,
+                    zlib: options.zlib_compression.unwrap_or(config.zlib_compression),
+                };
+                let writer = MzmlWriter::create(&path, run).map(Writer::Mzml);
+                (path, writer)
+            }
+            RecordingFormat::StreamLog => {
+                let path = config
+                    .directory
+                    .join(format!("{}.{}", name, stream_log::EXTENSION));
+                let compression = if options.zstd_compression.unwrap_or(config.zstd_compression) {
+                    StreamLogCompression::Zstd
+                } else {
+                    StreamLogCompression::None
+                };
+                let header = StreamLogHeader {
+                    instrument_id: instrument.id.clone(),
+                    session_id: session_id.to_string(),
+                    start_timestamp_ms,
+                    compression: compression as i32,
+                    writer: format!("lc-ms-simulator {}", env!("CARGO_PKG_VERSION")),
+                    ..Default::default()
+                };
+                let writer = StreamLogWriter::create(&path, header).map(Writer::StreamLog);
+                (path, writer)
+            }
+        };
+        let mut writer = writer.map_err(|e| match e.kind() {
+            io::ErrorKind::AlreadyExists => {
+                Status::already_exists(format!("Recording {} already exists", path.display()))
+            }
+            _ => Status::internal(format!("Cannot create recording {}: {}", path.display(), e)),
+        })?;
+
+        let (sender, mut receiver) = mpsc::channel::<(Duration, StreamEvent)>(QUEUE_CAPACITY);
+        let writer = tokio::task::spawn_blocking(move || {
+            while let Some((elapsed, event)) = receiver.blocking_recv() {
+                writer.write(elapsed, &event)?;
+            }
+            writer.finish()
+        });
+
+        info!("Recording session to {}", path.display());
+        Ok(Self {
+            path,
+            sender,
+            writer,
+            origin: None,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Queues an event, waiting while the writer catches up. mzML recordings keep only
+    /// the scans.
+    pub async fn record(&mut self, event: &StreamEvent) {
+        let elapsed = self.origin.get_or_insert_with(Instant::now).elapsed();
+        // A writer that failed has stopped receiving; finish reports why
+        let _ = self.sender.send((elapsed, event.clone())).await;
+    }
+
+    /// Writes the queued events and finalizes the file
+    pub async fn finish(self) {
+        drop(self.sender);
+        match self.writer.await {
+            Ok(Ok(summary)) => info!("Recorded {} to {}", summary, self.path.display()),
+            Ok(Err(e)) => warn!("Recording to {} failed: {}", self.path.display(), e),
+            Err(e) => warn!("Recording to {} failed: {}", self.path.display(), e),
+        }
+    }
+}
+
+enum Writer {
+    Mzml(MzmlWriter),
+    StreamLog(StreamLogWriter<BufWriter<File>>),
+}
+
+impl Writer {
+    fn write(&mut self, elapsed: Duration, event: &StreamEvent) -> io::Result<()> {
+        match self {
+            Writer::Mzml(writer) => match &event.event {
+                Some(Event::Scan(scan)) => writer.write_spectrum(scan),
+                _ => Ok(()),
+            },
+            Writer::StreamLog(writer) => writer.write(elapsed, event),
+        }
+    }
+
+    /// Finalizes the file; returns what it holds
+    fn finish(self) -> io::Result<String> {
+        match self {
+            Writer::Mzml(writer) => {
+                let spectra = writer.spectrum_count();
+                writer.finish()?;
+                Ok(format!("{} spectra", spectra))
+            }
+            Writer::StreamLog(writer) => {
+                let events = writer.record_count();
+                writer.finish()?;
+                Ok(format!("{} events", events))
+            }
+        }
+    }
+}
+
+/// Names are single file names, so requests cannot write outside the directory
+fn validate_name(name: &str) -> Result<(), Status> {
+    let valid =
+        name.len() <= MAX_NAME_LENGTH && !name.starts_with('.') && name.chars().all(is_name_char);
+    if valid {
+        Ok(())
+    } else {
+        Err(Status::invalid_argument(format!(
+            "recording.name must be a file name of at most {} letters, digits, spaces, \
+             '-', '_' or '.', not starting with '.'",
+            MAX_NAME_LENGTH
+        )))
+    }
+}
+
+fn is_name_char(c: char) -> bool {
+    c.is_alphanumeric() || matches!(c, '-' | '_' | '.' | ' ')
+}
@@ -0,0 +1,207 @@
+//! Replay of recorded runs.
+//!
+//! A `StartAcquisition` with `replay` set publishes the spectra of a file from the replay
+//! directory instead of synthesized scans, through the same streams, recording and fault
+//! injection as any session. A blocking task reads the file an event at a time ahead of
+//! the acquisition loop, and [`Replay::due`] spaces the events as they were recorded,
+//! scaled by the requested pacing.
+//!
+//! An mzML file is spaced by the retention times of its spectra. A stream log is spaced
+//! by the arrival times of its events, to the microsecond, and its scans are published
+//! as they were captured but for their session ID and timestamp. Its parameter changes
+//! are published too; the sessions it captured open and close with the replay session.
+
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::path::{Component, Path, PathBuf};
+use std::time::Duration;
+
+use tokio::sync::mpsc;
+use tokio::time::Instant;
+use tonic::Status;
+use tracing::{info, warn};
+
+use crate::config::ReplayConfig;
+use crate::mzml::MzmlReader;
+use crate::proto::stream_event::Event;
+use crate::proto::{ReplayOptions, ReplayPacing};
+use crate::stream_log::{self, StreamLogReader};
+
+/// Events read ahead of the acquisition loop
+const READ_AHEAD: usize = 256;
+
+/// Speed-up of `REPLAY_PACING_ACCELERATED` when a request does not set one
+const DEFAULT_SPEED: f64 = 10.0;
+
+pub struct Replay {
+    options: ReplayOptions,
+    path: PathBuf,
+    events: mpsc::Receiver<io::Result<ReplayEvent>>,
+    /// Speed-up of the original spacing; `None` publishes without waiting
+    speed: Option<f64>,
+    /// Wall-clock time and offset of the first event
+    origin: Option<(Instant, f64)>,
+}
+
+/// An event of the replayed file
+pub struct ReplayEvent {
+    /// Seconds into the recording: the retention time of a spectrum, the arrival time of
+    /// a stream log event
+    pub offset: f64,
+    pub event: Event,
+}
+
+impl Replay {
+    /// Opens a file for replay; fails if it is missing or has nothing readable to replay
+    pub fn open(config: &ReplayConfig, options: &ReplayOptions) -> Result<Self, Status> {
+        let speed = match options.pacing() {
+            ReplayPacing::RealTime => Some(1.0),
+            ReplayPacing::Accelerated if options.speed == 0.0 => Some(DEFAULT_SPEED),
+            ReplayPacing::Accelerated => {
+                if !(options.speed.is_finite() && options.speed > 0.0) {
+                    return Err(Status::invalid_argument("replay.speed must be positive"));
+                }
+                Some(options.speed)
+            }
+            ReplayPacing::AsFastAsPossible => None,
+        };
+
+        let path = resolve(&config.directory, &options.file)?;
+        let cannot_read = |e: io::Error| {
+            Status::invalid_argument(format!("Cannot read {}: {}", path.display(), e))
+        };
+        let mut source = Source::open(&path).map_err(|e| match e.kind() {
+            io::ErrorKind::NotFound => {
+                Status::not_found(format!("Replay file {} not found", path.display()))
+            }
+            io::ErrorKind::InvalidData => cannot_read(e),
+            _ => Status::internal(format!("Cannot open {}: {}", path.display(), e)),
+        })?;
+        // A file that is neither mzML nor a stream log fails here rather than faulting
+        // the session
+        let first = source.next_event().map_err(cannot_read)?.ok_or_else(|| {
+            Status::invalid_argument(format!("{} has nothing to replay", path.display()))
+        })?;
+
+        let (sender, events) = mpsc::channel(READ_AHEAD);
+        let file = path.clone();
+        tokio::task::spawn_blocking(move || {
+            let mut next = Ok(Some(first));
+            // Stops early when the session ends and drops the receiver
+            while let Some(event) = next.transpose() {
+                let failed = event.is_err();
+                if sender.blocking_send(event).is_err() || failed {
+                    return;
+                }
+                next = source.next_event();
+            }
+            if source.truncated() {
+                warn!(
+                    "{} was cut short; replayed up to its last complete block",
+                    file.display()
+                );
+            }
+        });
+
+        info!(
+            "Replaying {} (pacing: {:?})",
+            path.display(),
+            options.pacing()
+        );
+        Ok(Self {
+            options: options.clone(),
+            path,
+            events,
+            speed,
+            origin: None,
+        })
+    }
+
+    pub fn options(&self) -> &ReplayOptions {
+        &self.options
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The next event of the file, or `None` after the last one
+    pub async fn next_event(&mut self) -> io::Result<Option<ReplayEvent>> {
+        self.events.recv().await.transpose()
+    }
+
+    /// When the event at `offset` is due, or `None` to publish it immediately. Events
+    /// recorded out of order are due at once.
+    pub fn due(&mut self, offset: f64) -> Option<Instant> {
+        let speed = self.speed?;
+        let (start, first) = *self.origin.get_or_insert_with(|| (Instant::now(), offset));
+        let wait = Duration::try_from_secs_f64((offset - first) / speed).unwrap_or_default();
+        Some(start + wait)
+    }
+
+    /// Moves the remaining schedule back, after the session was paused
+    pub fn delay(&mut self, by: Duration) {
+        if let Some((start, _)) = &mut self.origin {
+            *start += by;
+        }
+    }
+}
+
+/// A replayed file, read on a blocking task
+enum Source {
+    Mzml(Box<MzmlReader<BufReader<File>>>),
+    StreamLog(Box<StreamLogReader<BufReader<File>>>),
+}
+
+impl Source {
+    /// Opens a stream log if the file starts like one, mzML otherwise
+    fn open(path: &Path) -> io::Result<Self> {
+        if stream_log::is_stream_log(path)? {
+            StreamLogReader::open(path).map(|reader| Source::StreamLog(Box::new(reader)))
+        } else {
+            MzmlReader::open(path).map(|reader| Source::Mzml(Box::new(reader)))
+        }
+    }
+
+    fn next_event(&mut self) -> io::Result<Option<ReplayEvent>> {
+        match self {
+            Source::Mzml(reader) => Ok(reader.next_scan()?.map(|scan| ReplayEvent {
+                offset: scan.retention_time * 60.0,
+                event: Event::Scan(scan),
+            })),
+            Source::StreamLog(reader) => {
+                while let Some(record) = reader.next_record()? {
+                    let offset = record.elapsed_us as f64 / 1e6;
+                    // Gaps were seen by the capturing subscriber only, and the replay
+                    // session opens and closes itself
+                    if let Some(event @ (Event::Scan(_) | Event::ParameterChanged(_))) =
+                        record.event.and_then(|event| event.event)
+                    {
+                        return Ok(Some(ReplayEvent { offset, event }));
+                    }
+                }
+                Ok(None)
+            }
+        }
+    }
+
+    /// Whether a stream log ended without its index
+    fn truncated(&self) -> bool {
+        matches!(self, Source::StreamLog(reader) if reader.truncated())
+    }
+}
+
+/// Files are named relative to the replay directory and cannot leave it
+fn resolve(directory: &Path, file: &str) -> Result<PathBuf, Status> {
+    let relative = Path::new(file);
+    let inside = !file.is_empty()
+        && relative
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)));
+    if !inside {
+        return Err(Status::invalid_argument(
+            "replay.file must be a relative path inside the replay directory",
+        ));
+    }
+    Ok(directory.join(relative))
+}
@@ -0,0 +1,279 @@
+//! REST/JSON gateway for dashboards and scripts.
+//!
+//! A small HTTP surface over the same instruments as the gRPC service: the instrument
+//! list, status, instrument info, and starting and stopping acquisitions. Bodies are the
+//! proto messages as JSON, with proto field names and enum value names. Requests carry
+//! the same `authorization` or `x-api-key` and `instrument-id` headers as gRPC calls;
+//! `?instrument_id=` selects an instrument too. Errors return the matching HTTP status
+//! with a `{"code", "message"}` body.
+
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use axum::body::Bytes;
+use axum::extract::{Query, Request, State};
+use axum::http::{HeaderMap, HeaderValue, StatusCode};
+use axum::middleware::{self, Next};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use hyper_util::server::conn::auto;
+use hyper_util::server::graceful::GracefulShutdown;
+use hyper_util::service::TowerToHyperService;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use tokio::net::{TcpListener, TcpStream};
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
+use tokio_stream::wrappers::ReceiverStream;
+use tokio_stream::StreamExt;
+use tonic::metadata::MetadataMap;
+use tonic::service::Interceptor;
+use tonic::{Code, Extensions, Status};
+use tracing::{debug, info};
+
+use crate::auth::AuthInterceptor;
+use crate::fleet::{Fleet, INSTRUMENT_ID_METADATA};
+use crate::proto::simulator_service_server::SimulatorService;
+use crate::proto::*;
+use crate::tls;
+use crate::web::Cors;
+
+#[derive(Clone)]
+struct Gateway {
+    fleet: Fleet,
+    auth: AuthInterceptor,
+}
+
+/// Optional instrument selection in the query string
+#[derive(Deserialize)]
+struct Target {
+    instrument_id: Option<String>,
+}
+
+impl Gateway {
+    /// Builds an authenticated gRPC request from the HTTP headers
+    fn request<T>(
+        &self,
+        mut headers: HeaderMap,
+        instrument_id: Option<String>,
+        message: T,
+    ) -> Result<tonic::Request<T>, ApiError> {
+        if let Some(id) = instrument_id {
+            let value = HeaderValue::try_from(id)
+                .map_err(|_| Status::invalid_argument("instrument_id is not a valid ID"))?;
+            headers.insert(INSTRUMENT_ID_METADATA, value);
+        }
+
+        let request = tonic::Request::from_parts(
+            MetadataMap::from_headers(headers),
+            Extensions::default(),
+            (),
+        );
+        let (metadata, extensions, ()) = self.auth.clone().call(request)?.into_parts();
+        Ok(tonic::Request::from_parts(metadata, extensions, message))
+    }
+}
+
+/// Serves the REST gateway until `shutdown` resolves, then finishes the requests in
+/// flight. With an `acceptor` the gateway speaks HTTPS, with the same certificates and
+/// client certificate requirement as the gRPC server.
+pub async fn serve(
+    fleet: Fleet,
+    auth: AuthInterceptor,
+    cors: Arc<Cors>,
+    addr: SocketAddr,
+    acceptor: Option<TlsAcceptor>,
+    shutdown: impl Future<Output = ()> + Send + 'static,
+) -> anyhow::Result<()> {
+    let app = Router::new()
+        .route("/api/v1/instruments", get(list_instruments))
+        .route("/api/v1/status", get(status))
+        .route("/api/v1/info", get(info))
+        .route("/api/v1/acquisition/start", post(start))
+        .route("/api/v1/acquisition/stop", post(stop))
+        .with_state(Gateway { fleet, auth })
+        .layer(middleware::from_fn_with_state(cors, cors_headers));
+
+    let listener = TcpListener::bind(addr).await?;
+    match acceptor {
+        Some(acceptor) => {
+            info!("REST gateway listening on https://{}/api/v1", addr);
+            serve_tls(tls::incoming(listener, acceptor), app, shutdown).await;
+        }
+        None => {
+            info!("REST gateway listening on http://{}/api/v1", addr);
+            axum::serve(listener, app)
+                .with_graceful_shutdown(shutdown)
+                .await?;
+        }
+    }
+    Ok(())
+}
+
+/// Serves HTTP/1.1 and HTTP/2 on established TLS streams, which `axum::serve` cannot
+/// take, with the same graceful shutdown
+async fn serve_tls(
+    mut incoming: ReceiverStream<io::Result<TlsStream<TcpStream>>>,
+    app: Router,
+    shutdown: impl Future<Output = ()>,
+) {
+    let builder = auto::Builder::new(TokioExecutor::new());
+    let service = TowerToHyperService::new(app);
+    let graceful = GracefulShutdown::new();
+    tokio::pin!(shutdown);
+
+    loop {
+        let stream = tokio::select! {
+            stream = incoming.next() => match stream {
+                Some(Ok(stream)) => stream,
+                Some(Err(_)) => continue,
+                None => break,
+            },
+            _ = &mut shutdown => break,
+        };
+        let connection = builder
+            .serve_connection(TokioIo::new(stream), service.clone())
+            .into_owned();
+        let connection = graceful.watch(connection);
+        tokio::spawn(async move {
+            if let Err(e) = connection.await {
+                debug!("REST connection ended with an error: {}", e);
+            }
+        });
+    }
+
+    // Stops the accept loop, then waits for open connections to finish their requests
+    drop(incoming);
+    graceful.shutdown().await;
+}
+
+async fn cors_headers(State(cors): State<Arc<Cors>>, request: Request, next: Next) -> Response {
+    if Cors::is_preflight(request.method(), request.headers()) {
+        return cors.preflight(request.headers());
+    }
+    let request_headers = request.headers().clone();
+    let mut response = next.run(request).await;
+    cors.apply(&request_headers, response.headers_mut());
+    response
+}
+
+async fn list_instruments(
+    State(gateway): State<Gateway>,
+    headers: HeaderMap,
+) -> Result<Json<ListInstrumentsResponse>, ApiError> {
+    let request = gateway.request(headers, None, ListInstrumentsRequest {})?;
+    Ok(Json(
+        gateway.fleet.list_instruments(request).await?.into_inner(),
+    ))
+}
+
+async fn status(
+    State(gateway): State<Gateway>,
+    Query(target): Query<Target>,
+    headers: HeaderMap,
+) -> Result<Json<StatusResponse>, ApiError> {
+    let request = gateway.request(headers, target.instrument_id, GetStatusRequest {})?;
+    Ok(Json(gateway.fleet.get_status(request).await?.into_inner()))
+}
+
+async fn info(
+    State(gateway): State<Gateway>,
+    Query(target): Query<Target>,
+    headers: HeaderMap,
+) -> Result<Json<InstrumentInfoResponse>, ApiError> {
+    let request = gateway.request(headers, target.instrument_id, GetInstrumentInfoRequest {})?;
+    Ok(Json(
+        gateway
+            .fleet
+            .get_instrument_info(request)
+            .await?
+            .into_inner(),
+    ))
+}
+
+async fn start(
+    State(gateway): State<Gateway>,
+    Query(target): Query<Target>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<Json<StartAcquisitionResponse>, ApiError> {
+    let message: StartAcquisitionRequest = parse_body(&body)?;
+    let request = gateway.request(headers, target.instrument_id, message)?;
+    Ok(Json(
+        gateway.fleet.start_acquisition(request).await?.into_inner(),
+    ))
+}
+
+async fn stop(
+    State(gateway): State<Gateway>,
+    Query(target): Query<Target>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<Json<StopAcquisitionResponse>, ApiError> {
+    let message: StopAcquisitionRequest = parse_body(&body)?;
+    let request = gateway.request(headers, target.instrument_id, message)?;
+    Ok(Json(
+        gateway.fleet.stop_acquisition(request).await?.into_inner(),
+    ))
+}
+
+/// Parses a JSON request body; an empty body is the default message
+fn parse_body<T: DeserializeOwned + Default>(body: &[u8]) -> Result<T, ApiError> {
+    if body.iter().all(u8::is_ascii_whitespace) {
+        return Ok(T::default());
+    }
+    serde_json::from_slice(body)
+        .map_err(|e| Status::invalid_argument(format!("Invalid JSON body: {}", e)).into())
+}
+
+/// A gRPC status returned as an HTTP error
+struct ApiError(Status);
+
+impl From<Status> for ApiError {
+    fn from(status: Status) -> Self {
+        Self(status)
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        // The mapping used by Google's HTTP/JSON transcoding
+        let http_status = match self.0.code() {
+            Code::Ok => StatusCode::OK,
+            Code::InvalidArgument | Code::FailedPrecondition | Code::OutOfRange => {
+                StatusCode::BAD_REQUEST
+            }
+            Code::Unauthenticated => StatusCode::UNAUTHORIZED,
+            Code::PermissionDenied => StatusCode::FORBIDDEN,
+            Code::NotFound => StatusCode::NOT_FOUND,
+            Code::AlreadyExists | Code::Aborted => StatusCode::CONFLICT,
+            Code::ResourceExhausted => StatusCode::TOO_MANY_REQUESTS,
+            Code::Unimplemented => StatusCode::NOT_IMPLEMENTED,
+            Code::Unavailable => StatusCode::SERVICE_UNAVAILABLE,
+            Code::DeadlineExceeded => StatusCode::GATEWAY_TIMEOUT,
+            Code::Cancelled => StatusCode::from_u16(499).expect("499 is a valid status"),
+            Code::Unknown | Code::Internal | Code::DataLoss => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        let body = serde_json::json!({
+            "code": code_name(self.0.code()),
+            "message": self.0.message(),
+        });
+        (http_status, Json(body)).into_response()
+    }
+}
+
+/// The canonical name of a gRPC code, e.g. `NOT_FOUND`
+fn code_name(code: Code) -> String {
+    let mut name = String::new();
+    for (i, c) in format!("{:?}", code).chars().enumerate() {
+        if c.is_ascii_uppercase() && i > 0 {
+            name.push('_');
+        }
+        name.push(c.to_ascii_uppercase());
+    }
+    name
+}
@@ -1,75 +1,368 @@
 use std::pin::Pin;
-use std::sync::atomic::{AtomicI64, AtomicU8, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicI64, Ordering};
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
-use tokio::sync::{broadcast, Mutex};
-use tokio_stream::wrappers::BroadcastStream;
+use prost::Message;
+use tokio::sync::{broadcast, mpsc, oneshot, watch, Mutex};
+use tokio_stream::wrappers::errors::BroadcastStreamRecvError;
+use tokio_stream::wrappers::ReceiverStream;
 use tokio_stream::Stream;
 use tokio_stream::StreamExt;
-use tokio::time::MissedTickBehavior;
+use tokio::task::JoinHandle;
+use tokio::time::{timeout_at, MissedTickBehavior};
 use tonic::{Request, Response, Status};
-use tracing::{info, warn};
+use tracing::{info, info_span, warn, Instrument, Span};
 
+use crate::auth::{authorize, Role};
+use crate::batching::{batch_scans, BatchLimits};
+use crate::config::{
+    InstrumentConfig, MethodConfig, PowerConfig, RecordingConfig, ReplayConfig,
+    SimulationConfig, StatusLogConfig,
+};
+use crate::encoding::SpectrumEncoder;
+use crate::faults::{FaultAction, FaultInjector, FaultPlan, SessionFaults};
+use crate::metrics::Metrics;
+use crate::network::{self, NetworkImpairment};
+use crate::power::{Power, PowerMode, Readiness};
 use crate::proto::*;
-use crate::simulator::ScanGenerator;
+use crate::recording::Recorder;
+use crate::replay::{Replay, ReplayEvent};
+use crate::shutdown::DrainOnShutdown;
+use crate::simulator::{current_timestamp_ms, AcquisitionCycle, ScanGenerator};
+use crate::status_log::{StatusInputs, StatusLog, MIN_INTERVAL_SECONDS};
+use crate::tune::{SignalEffect, TuneState};
+
+/// Capacity of the broadcast queue shared by all streaming subscribers.
+/// Large to reduce lag/drops during high-rate streaming and stress tests.
+pub const SCAN_QUEUE_CAPACITY: usize = 100_000;
+
+/// A session waiting in Starting for `TriggerStart`; the sender carries the trigger time
+struct PendingTrigger {
+    session_id: String,
+    sender: oneshot::Sender<i64>,
+}
+
+/// How a session waits for its external start trigger
+struct TriggerWait {
+    receiver: oneshot::Receiver<i64>,
+    timeout: Option<Duration>,
+    start_on_timeout: bool,
+}
+
+/// What a session was asked for besides its simulation parameters
+struct SessionPlan {
+    max_scans: Option<i32>,
+    max_duration_seconds: Option<f64>,
+    trigger: Option<TriggerWait>,
+    recorder: Option<Recorder>,
+    replay: Option<Replay>,
+}
+
+/// A running session's limits, outputs and progress
+struct SessionRun {
+    session_id: String,
+    max_scans: Option<i32>,
+    max_duration_seconds: Option<f64>,
+    start_time: std::time::Instant,
+    faults: SessionFaults,
+    scans_generated: i64,
+    retention_time: f64,
+}
 
 /// gRPC service implementation for the LC-MS simulator
+#[derive(Clone)]
 pub struct SimulatorServiceImpl {
-    instrument_name: String,
-    instrument_id: String,
-    state: Arc<AtomicU8>,
+    instrument: Arc<InstrumentConfig>,
+    power: Arc<Power>,
+    simulation: Arc<SimulationConfig>,
+    method: Arc<MethodConfig>,
+    status_log_config: Arc<StatusLogConfig>,
+    recording: Arc<RecordingConfig>,
+    replay: Arc<ReplayConfig>,
+    state: watch::Sender<AcquisitionState>,
     scan_count: Arc<AtomicI64>,
-    scan_sender: broadcast::Sender<ScanMessage>,
+    scan_sender: broadcast::Sender<StreamEvent>,
     generator: Arc<Mutex<ScanGenerator>>,
     session_id: Arc<Mutex<Option<String>>>,
+    metrics: Arc<Metrics>,
+    acquisition_task: Arc<Mutex<Option<JoinHandle<()>>>>,
+    /// Recording of the running session, which every event it publishes goes to
+    recorder: Arc<Mutex<Option<Recorder>>>,
+    start_trigger: Arc<Mutex<Option<PendingTrigger>>>,
+    faults: Arc<FaultInjector>,
+    tune: Arc<TuneState>,
+    status_log: Arc<StatusLog>,
+    error_message: Arc<Mutex<String>>,
+    shutting_down: Arc<AtomicBool>,
+    close_streams: watch::Sender<bool>,
 }
 
 impl SimulatorServiceImpl {
-    pub fn new(instrument_name: String, instrument_id: String) -> Self {
-        // Large buffer to reduce lag/drops during high-rate streaming and stress tests.
-        let (scan_sender, _) = broadcast::channel(100_000);
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        instrument: InstrumentConfig,
+        power: PowerConfig,
+        simulation: SimulationConfig,
+        method: MethodConfig,
+        status_log: StatusLogConfig,
+        recording: RecordingConfig,
+        replay: ReplayConfig,
+        faults: FaultPlan,
+        metrics: Arc<Metrics>,
+    ) -> Self {
+        let (scan_sender, _) = broadcast::channel(SCAN_QUEUE_CAPACITY);
+        let profile = instrument.profile.profile();
+        let scan_settings = profile.default_scan_settings();
+        let tune = TuneState::new(profile.tune_parameters);
 
         Self {
-            instrument_name,
-            instrument_id,
-            state: Arc::new(AtomicU8::new(AcquisitionState::Idle as u8)),
+            instrument: Arc::new(instrument),
+            power: Arc::new(Power::new(
+                power.mode,
+                power.pump_down_seconds,
+                power.warm_up_seconds,
+            )),
+            simulation: Arc::new(simulation),
+            method: Arc::new(method),
+            status_log_config: Arc::new(status_log),
+            recording: Arc::new(recording),
+            replay: Arc::new(replay),
+            state: watch::Sender::new(AcquisitionState::Idle),
             scan_count: Arc::new(AtomicI64::new(0)),
             scan_sender,
-            generator: Arc::new(Mutex::new(ScanGenerator::new())),
+            generator: Arc::new(Mutex::new(ScanGenerator::new(scan_settings))),
             session_id: Arc::new(Mutex::new(None)),
+            metrics,
+            acquisition_task: Arc::new(Mutex::new(None)),
+            recorder: Arc::new(Mutex::new(None)),
+            start_trigger: Arc::new(Mutex::new(None)),
+            faults: Arc::new(FaultInjector::new(faults)),
+            tune: Arc::new(tune),
+            status_log: Arc::new(StatusLog::new()),
+            error_message: Arc::new(Mutex::new(String::new())),
+            shutting_down: Arc::new(AtomicBool::new(false)),
+            close_streams: watch::Sender::new(false),
+        }
+    }
+
+    pub fn instrument_id(&self) -> &str {
+        &self.instrument.id
+    }
+
+    /// Identity and current state for `ListInstruments`
+    pub async fn summary(&self) -> InstrumentSummary {
+        let instrument = &self.instrument;
+        InstrumentSummary {
+            instrument_id: instrument.id.clone(),
+            instrument_name: instrument.name(),
+            model: instrument.profile.profile().model.to_string(),
+            profile: instrument.profile.name(),
+            state: self.get_state() as i32,
+            instrument_state: self.power.readiness().state as i32,
+            session_id: self.session_id.lock().await.clone().unwrap_or_default(),
+            is_default: false,
+        }
+    }
+
+    pub fn readiness(&self) -> Readiness {
+        self.power.readiness()
+    }
+
+    /// Returns a receiver that observes every acquisition state transition
+    pub fn subscribe_state(&self) -> watch::Receiver<AcquisitionState> {
+        self.state.subscribe()
+    }
+
+    /// Stops a running acquisition and then ends every subscriber stream once it has
+    /// delivered what was already queued. New acquisitions are rejected from the start.
+    /// Gives up waiting for the acquisition at `deadline`.
+    pub async fn shutdown(&self, deadline: tokio::time::Instant) {
+        self.shutting_down.store(true, Ordering::SeqCst);
+
+        // A session waiting for its start trigger ends without starting
+        self.start_trigger.lock().await.take();
+
+        if let Some(task) = self.acquisition_task.lock().await.take() {
+            if !task.is_finished() {
+                info!("Stopping acquisition for shutdown");
+
+                // A session that is still starting would overwrite Stopping with Acquiring
+                let mut state = self.subscribe_state();
+                let _ = timeout_at(
+                    deadline,
+                    state.wait_for(|&state| state != AcquisitionState::Starting),
+                )
+                .await;
+                self.set_state(AcquisitionState::Stopping);
+
+                if timeout_at(deadline, task).await.is_err() {
+                    warn!("Acquisition did not stop within the shutdown grace period");
+                }
+            }
         }
+
+        self.close_streams.send_replace(true);
+    }
+
+    /// Subscribes to the event broadcast; the stream ends after shutdown once drained
+    fn subscribe_events(&self) -> DrainOnShutdown<StreamEvent> {
+        DrainOnShutdown::new(self.scan_sender.subscribe(), self.close_streams.subscribe())
     }
 
     fn get_state(&self) -> AcquisitionState {
-        match self.state.load(Ordering::SeqCst) {
-            0 => AcquisitionState::Idle,
-            1 => AcquisitionState::Starting,
-            2 => AcquisitionState::Acquiring,
-            3 => AcquisitionState::Paused,
-            4 => AcquisitionState::Stopping,
-            5 => AcquisitionState::Completed,
-            6 => AcquisitionState::Faulted,
-            _ => AcquisitionState::Idle,
+        *self.state.borrow()
+    }
+
+    /// Tags the current RPC span with the active session, if any
+    async fn record_session_span(&self) {
+        if let Some(session_id) = self.session_id.lock().await.as_deref() {
+            Span::current().record("session_id", session_id);
         }
     }
 
     fn set_state(&self, state: AcquisitionState) {
-        self.state.store(state as u8, Ordering::SeqCst);
+        self.state.send_replace(state);
+        self.metrics.set_acquisition_state(state);
+    }
+
+    /// Samples the status log with the current acquisition, tune and fault state
+    async fn status_record(&self) -> StatusLogRecord {
+        let session_id = self.session_id.lock().await.clone().unwrap_or_default();
+        let (excursions, since_plan_set) = self.faults.status_excursions();
+        self.status_log.record(StatusInputs {
+            state: self.get_state(),
+            session_id,
+            instrument_state: self.power.readiness().state,
+            spray_voltage: self.tune.readback(SignalEffect::SprayVoltage).unwrap_or(0.0),
+            ion_transfer_temperature: self.tune.readback(SignalEffect::Desolvation).unwrap_or(0.0),
+            excursions: &excursions,
+            since_plan_set,
+        })
+    }
+
+    /// Records an in-band event and broadcasts it to all subscribers
+    async fn publish(&self, session_id: &str, event: stream_event::Event) {
+        let event = stream_event(session_id, event);
+        self.record(&event).await;
+        self.broadcast(event);
+    }
+
+    /// Adds an event to the session's recording, if it has one
+    async fn record(&self, event: &StreamEvent) {
+        if let Some(recorder) = self.recorder.lock().await.as_mut() {
+            recorder.record(event).await;
+        }
+    }
+
+    fn broadcast(&self, event: StreamEvent) {
+        self.metrics.record_event_published();
+        if self.scan_sender.send(event).is_err() {
+            // No receivers, but that's OK
+        }
     }
 
     async fn run_acquisition(
         &self,
-        params: Option<SimulationParameters>,
-        max_scans: Option<i32>,
-        max_duration_seconds: Option<f64>,
+        session_id: String,
+        params: SimulationParameters,
+        plan: SessionPlan,
     ) {
-        let params = params.unwrap_or_default();
+        let SessionPlan {
+            max_scans,
+            max_duration_seconds,
+            trigger,
+            recorder,
+            replay,
+        } = plan;
+
+        let trigger_timestamp_ms = match trigger {
+            Some(trigger) => match self.wait_for_trigger(trigger).await {
+                Ok(timestamp_ms) => timestamp_ms,
+                Err(final_state) => {
+                    if let Some(recorder) = recorder {
+                        recorder.finish().await;
+                    }
+                    self.set_state(final_state);
+                    return;
+                }
+            },
+            None => None,
+        };
+
+        *self.recorder.lock().await = recorder;
+        self.publish(
+            &session_id,
+            stream_event::Event::SessionOpened(SessionOpened {
+                max_scans,
+                max_duration_seconds,
+                simulation: replay.is_none().then(|| params.clone()),
+                trigger_timestamp_ms,
+                replay: replay.as_ref().map(|replay| replay.options().clone()),
+            }),
+        )
+        .await;
+
+        self.set_state(AcquisitionState::Acquiring);
+        let mut run = SessionRun {
+            session_id,
+            max_scans,
+            max_duration_seconds,
+            // Retention time zero
+            start_time: std::time::Instant::now(),
+            faults: self.faults.session(),
+            scans_generated: 0,
+            retention_time: 0.0,
+        };
+
+        let fault = match replay {
+            Some(replay) => self.replay_scans(&mut run, replay).await,
+            None => self.generate_scans(&mut run, &params).await,
+        };
+
+        let final_state = match fault {
+            Some(message) => {
+                warn!(
+                    "Acquisition faulted after {} scans: {}",
+                    run.scans_generated, message
+                );
+                *self.error_message.lock().await = message;
+                AcquisitionState::Faulted
+            }
+            None => {
+                info!("Acquisition complete: {} scans generated", run.scans_generated);
+                AcquisitionState::Completed
+            }
+        };
+
+        let closed = stream_event(
+            &run.session_id,
+            stream_event::Event::SessionClosed(SessionClosed {
+                final_scan_count: run.scans_generated,
+                final_state: final_state as i32,
+                final_retention_time: run.retention_time,
+            }),
+        );
+        // The recording is complete by the time clients see the session end
+        self.record(&closed).await;
+        if let Some(recorder) = self.recorder.lock().await.take() {
+            recorder.finish().await;
+        }
+        self.set_state(final_state);
+        self.broadcast(closed);
+    }
+
+    /// Synthesizes scans at the requested rate; returns the message if the session faults
+    async fn generate_scans(
+        &self,
+        run: &mut SessionRun,
+        params: &SimulationParameters,
+    ) -> Option<String> {
         // Interpret scan_rate as *total scans per second* (MS1 + MS2).
         // Use batching per timer tick to support high throughput (tokio sleep granularity
         // is typically ~1ms, so per-scan sleeps can't hit 10k scans/sec).
-        let scan_rate = if params.scan_rate > 0.0 { params.scan_rate } else { 2.0 };
-        let ms2_per_ms1 = if params.ms2_per_ms1 > 0 { params.ms2_per_ms1 } else { 4 };
+        let scan_rate = params.scan_rate;
+        let ms2_per_ms1 = params.ms2_per_ms1;
         let scans_per_cycle = 1i64 + ms2_per_ms1 as i64;
         let cycles_per_second = scan_rate / scans_per_cycle as f64;
 
@@ -81,149 +374,336 @@ impl SimulatorServiceImpl {
         let mut interval = tokio::time::interval_at(tokio::time::Instant::now(), tick);
         interval.set_missed_tick_behavior(MissedTickBehavior::Burst);
 
-        let min_mz = if params.min_mz > 0.0 { params.min_mz } else { 200.0 };
-        let max_mz = if params.max_mz > 0.0 { params.max_mz } else { 2000.0 };
-
-        let ms1_peak_count = params.ms1_peak_count.filter(|v| *v > 0).map(|v| v as usize);
-        let ms2_peak_count = params.ms2_peak_count.filter(|v| *v > 0).map(|v| v as usize);
-
-        let start_time = std::time::Instant::now();
-        let mut scans_generated = 0i64;
+        let mut cycle = AcquisitionCycle::new(params);
 
         info!(
             "Starting acquisition: scan_rate={} scans/s, ms2_per_ms1={}, ms1_peaks={:?}, ms2_peaks={:?}",
             scan_rate,
             ms2_per_ms1,
-            ms1_peak_count,
-            ms2_peak_count
+            cycle.ms1_peak_count,
+            cycle.ms2_peak_count
         );
 
-        self.set_state(AcquisitionState::Acquiring);
-
         loop {
             interval.tick().await;
 
-            // Check termination conditions
-            if self.get_state() == AcquisitionState::Stopping {
-                break;
-            }
-
-            if let Some(max) = max_scans {
-                if scans_generated >= max as i64 {
-                    break;
-                }
+            if self.session_over(run) {
+                return None;
             }
-
-            if let Some(max_secs) = max_duration_seconds {
-                if start_time.elapsed().as_secs_f64() > max_secs {
-                    break;
-                }
+            if let Some(message) = self.apply_faults(run, |_| interval.reset()).await {
+                return Some(message);
             }
 
             cycle_accumulator += cycles_per_tick;
             let cycles_to_run = cycle_accumulator.floor() as i64;
             cycle_accumulator -= cycles_to_run as f64;
 
-            if cycles_to_run <= 0 {
-                continue;
-            }
-
-            for _ in 0..cycles_to_run {
+            for _ in 0..cycles_to_run * scans_per_cycle {
                 // Re-check termination conditions within the batch.
-                if self.get_state() == AcquisitionState::Stopping {
-                    break;
-                }
-                if let Some(max) = max_scans {
-                    if scans_generated >= max as i64 {
-                        break;
-                    }
+                if self.session_over(run) {
+                    return None;
                 }
-                if let Some(max_secs) = max_duration_seconds {
-                    if start_time.elapsed().as_secs_f64() > max_secs {
-                        break;
-                    }
+                if let Some(message) = self.apply_faults(run, |_| interval.reset()).await {
+                    return Some(message);
                 }
 
-                // Generate MS1 scan
-                let ms1_scan = {
+                let generation_start = Instant::now();
+                let scan = {
                     let mut gen = self.generator.lock().await;
-                    gen.generate_ms1(min_mz, max_mz, ms1_peak_count)
+                    if cycle.next_is_survey() {
+                        gen.set_conditions(self.tune.conditions());
+                    }
+                    cycle.next_scan(&mut gen)
                 };
+                self.metrics.record_scan_generated(scan.ms_order, generation_start.elapsed());
+                self.emit_scan(run, scan).await;
+            }
+        }
+    }
+
+    /// Publishes a file's events on their original schedule; returns the message if the
+    /// session faults, which includes the file turning out to be unreadable
+    async fn replay_scans(&self, run: &mut SessionRun, mut replay: Replay) -> Option<String> {
+        let mut state = self.subscribe_state();
+        // A limit too far out to represent never ends the session
+        let duration_limit = run.max_duration_seconds.and_then(|seconds| {
+            let limit = Duration::try_from_secs_f64(seconds).ok()?;
+            tokio::time::Instant::now().checked_add(limit)
+        });
+
+        loop {
+            if self.session_over(run) {
+                return None;
+            }
+            if let Some(message) = self.apply_faults(run, |stall| replay.delay(stall)).await {
+                return Some(message);
+            }
 
-                if self.scan_sender.send(ms1_scan.clone()).is_err() {
-                    // No receivers, but that's OK
+            let read_start = Instant::now();
+            let ReplayEvent { offset, event } = match replay.next_event().await {
+                Ok(Some(event)) => event,
+                Ok(None) => {
+                    info!("Replay of {} finished", replay.path().display());
+                    return None;
+                }
+                Err(e) => {
+                    return Some(format!("Replay of {} failed: {}", replay.path().display(), e))
                 }
-                scans_generated += 1;
-                self.scan_count.fetch_add(1, Ordering::SeqCst);
+            };
+            if let stream_event::Event::Scan(scan) = &event {
+                self.metrics.record_scan_generated(scan.ms_order, read_start.elapsed());
+            }
 
-                // Generate MS2 scans
-                for _ in 0..ms2_per_ms1 {
-                    if self.get_state() == AcquisitionState::Stopping {
-                        break;
+            match replay.due(offset) {
+                Some(due) => {
+                    // The session ends before a scan due after its duration limit
+                    let limit = duration_limit.filter(|&limit| limit < due);
+                    let stopping = state.wait_for(|&state| state == AcquisitionState::Stopping);
+                    let stopped = timeout_at(limit.unwrap_or(due), stopping).await.is_ok();
+                    if stopped || limit.is_some() {
+                        return None;
                     }
+                }
+                // Lets subscribers keep up with an unpaced replay
+                None => tokio::task::yield_now().await,
+            }
 
-                    if let Some(max) = max_scans {
-                        if scans_generated >= max as i64 {
-                            break;
-                        }
-                    }
+            match event {
+                stream_event::Event::Scan(mut scan) => {
+                    scan.timestamp_ms = current_timestamp_ms();
+                    self.emit_scan(run, scan).await;
+                }
+                event => self.publish(&run.session_id, event).await,
+            }
+        }
+    }
 
-                    let ms2_scan = {
-                        let mut gen = self.generator.lock().await;
-                        let (precursor_mz, precursor_int) = gen.select_precursor(&ms1_scan);
-                        gen.generate_ms2(precursor_mz, precursor_int, ms2_peak_count)
-                    };
+    /// Whether the session was stopped or reached its scan or duration limit
+    fn session_over(&self, run: &SessionRun) -> bool {
+        self.get_state() == AcquisitionState::Stopping
+            || run
+                .max_scans
+                .is_some_and(|max| run.scans_generated >= max as i64)
+            || run
+                .max_duration_seconds
+                .is_some_and(|max| run.start_time.elapsed().as_secs_f64() > max)
+    }
 
-                    if self.scan_sender.send(ms2_scan).is_err() {
-                        // No receivers
-                    }
-                    scans_generated += 1;
-                    self.scan_count.fetch_add(1, Ordering::SeqCst);
+    /// Publishes a scan of the session, with any injected data faults
+    async fn emit_scan(&self, run: &mut SessionRun, mut scan: ScanMessage) {
+        scan.session_id = run.session_id.clone();
+        run.retention_time = scan.retention_time;
+        run.faults.corrupt(&self.faults, &mut scan);
+        self.publish(&run.session_id, stream_event::Event::Scan(scan))
+            .await;
+        run.scans_generated += 1;
+        self.scan_count.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// Stays in Starting until `TriggerStart` arrives. Returns the trigger time, `None`
+    /// to start without a trigger after a timeout, or the state the session ends in.
+    async fn wait_for_trigger(&self, trigger: TriggerWait) -> Result<Option<i64>, AcquisitionState> {
+        info!("Waiting for start trigger (timeout: {:?})", trigger.timeout);
+        let mut state = self.subscribe_state();
+        let stopping = async {
+            let _ = state.wait_for(|&state| state == AcquisitionState::Stopping).await;
+        };
+        let timeout = async {
+            match trigger.timeout {
+                Some(timeout) => tokio::time::sleep(timeout).await,
+                None => std::future::pending().await,
+            }
+        };
+
+        let outcome = tokio::select! {
+            triggered = trigger.receiver => match triggered {
+                Ok(timestamp_ms) => {
+                    info!("Start trigger received");
+                    Ok(Some(timestamp_ms))
                 }
+                // Dropped at shutdown
+                Err(_) => Err(AcquisitionState::Completed),
+            },
+            _ = stopping => {
+                info!("Acquisition stopped while waiting for start trigger");
+                Err(AcquisitionState::Completed)
             }
-        }
+            _ = timeout => {
+                let timeout = trigger.timeout.unwrap_or_default();
+                if trigger.start_on_timeout {
+                    warn!("No start trigger within {:?}, starting without one", timeout);
+                    Ok(None)
+                } else {
+                    let message = format!("No start trigger received within {:?}", timeout);
+                    warn!("{}", message);
+                    *self.error_message.lock().await = message;
+                    Err(AcquisitionState::Faulted)
+                }
+            }
+        };
+
+        self.start_trigger.lock().await.take();
+        outcome
+    }
 
-        info!("Acquisition complete: {} scans generated", scans_generated);
-        self.set_state(AcquisitionState::Completed);
+    /// Carries out injected faults that are due; returns the message if the session faults.
+    /// `resume` is called with the length of each injected stall once it is over.
+    async fn apply_faults(
+        &self,
+        run: &mut SessionRun,
+        mut resume: impl FnMut(Duration),
+    ) -> Option<String> {
+        while let Some(action) =
+            run.faults
+                .check(&self.faults, run.scans_generated, run.start_time.elapsed())
+        {
+            match action {
+                FaultAction::Fault(message) => return Some(message),
+                FaultAction::Stall(duration) => {
+                    warn!("Injected stall: pausing scan generation for {:?}", duration);
+                    let stall_start = Instant::now();
+                    let mut state = self.subscribe_state();
+                    let stopping = state.wait_for(|&state| state == AcquisitionState::Stopping);
+                    let _ = tokio::time::timeout(duration, stopping).await;
+                    // Resume at the configured rate instead of bursting to catch up
+                    resume(stall_start.elapsed());
+                }
+            }
+        }
+        None
     }
 }
 
 #[tonic::async_trait]
 impl simulator_service_server::SimulatorService for SimulatorServiceImpl {
     type StreamScansStream = Pin<Box<dyn Stream<Item = Result<ScanMessage, Status>> + Send>>;
+    type StreamEventsStream = Pin<Box<dyn Stream<Item = Result<StreamEvent, Status>> + Send>>;
+    type StreamScanBatchesStream = Pin<Box<dyn Stream<Item = Result<ScanBatch, Status>> + Send>>;
+    type StreamStatusLogStream = ReceiverStream<Result<StatusLogRecord, Status>>;
 
     async fn stream_scans(
         &self,
         request: Request<StreamScansRequest>,
     ) -> Result<Response<Self::StreamScansStream>, Status> {
-        let _req = request.into_inner();
-        let receiver = self.scan_sender.subscribe();
+        authorize(&request, Role::Observer)?;
 
-        let stream = BroadcastStream::new(receiver).filter_map(|result| {
+        let req = request.into_inner();
+        let encoder = SpectrumEncoder::from_request(req.encoding)?;
+        let impairment = NetworkImpairment::from_request(req.impairment)?;
+        let subscriber = self.metrics.subscribe("StreamScans");
+        let events = self.subscribe_events();
+
+        let stream = events.filter_map(move |result| {
             match result {
-                Ok(scan) => Some(Ok(scan)),
-                Err(e) => {
-                    warn!("Broadcast error: {:?}", e);
+                Ok(StreamEvent {
+                    event: Some(stream_event::Event::Scan(mut scan)),
+                    ..
+                }) => {
+                    if let Some(encoder) = &encoder {
+                        encoder.encode(&mut scan);
+                    }
+                    subscriber.delivered(scan.encoded_len());
+                    Some(Ok(scan))
+                }
+                Ok(_) => {
+                    subscriber.delivered(0);
+                    None
+                }
+                Err(BroadcastStreamRecvError::Lagged(missed)) => {
+                    warn!("Scan subscriber lagged, {} events dropped", missed);
+                    subscriber.dropped(missed);
                     None
                 }
             }
         });
 
-        Ok(Response::new(Box::pin(stream)))
+        let stream = network::impair(stream, impairment, self.faults.network());
+        Ok(Response::new(Box::pin(self.faults.abortable(stream))))
+    }
+
+    async fn stream_events(
+        &self,
+        request: Request<StreamScansRequest>,
+    ) -> Result<Response<Self::StreamEventsStream>, Status> {
+        authorize(&request, Role::Observer)?;
+
+        let req = request.into_inner();
+        let encoder = SpectrumEncoder::from_request(req.encoding)?;
+        let impairment = NetworkImpairment::from_request(req.impairment)?;
+        let subscriber = self.metrics.subscribe("StreamEvents");
+        let events = self.subscribe_events();
+
+        // Track the last delivered scan so a lagging subscriber learns where its gap starts.
+        let mut last_session_id = String::new();
+        let mut last_scan_number = 0;
+
+        let stream = events
+            .map(move |result| match result {
+                Ok(mut event) => {
+                    if let Some(stream_event::Event::Scan(scan)) = &mut event.event {
+                        last_scan_number = scan.scan_number;
+                        if let Some(encoder) = &encoder {
+                            encoder.encode(scan);
+                        }
+                    }
+                    last_session_id.clone_from(&event.session_id);
+                    subscriber.delivered(event.encoded_len());
+                    event
+                }
+                Err(BroadcastStreamRecvError::Lagged(missed)) => {
+                    warn!("Event subscriber lagged, {} events dropped", missed);
+                    subscriber.dropped(missed);
+                    StreamEvent {
+                        session_id: last_session_id.clone(),
+                        timestamp_ms: current_timestamp_ms(),
+                        event: Some(stream_event::Event::GapDetected(GapDetected {
+                            missed_events: missed as i64,
+                            last_scan_number,
+                        })),
+                    }
+                }
+            })
+            .map(Ok);
+
+        let stream = network::impair(stream, impairment, self.faults.network());
+        Ok(Response::new(Box::pin(self.faults.abortable(stream))))
+    }
+
+    async fn stream_scan_batches(
+        &self,
+        request: Request<StreamScanBatchesRequest>,
+    ) -> Result<Response<Self::StreamScanBatchesStream>, Status> {
+        authorize(&request, Role::Observer)?;
+
+        let req = request.into_inner();
+        let limits = BatchLimits::from_request(&req);
+        let stream = req.stream.unwrap_or_default();
+        let encoder = SpectrumEncoder::from_request(stream.encoding)?;
+        let impairment = NetworkImpairment::from_request(stream.impairment)?;
+        let subscriber = self.metrics.subscribe("StreamScanBatches");
+        let events = self.subscribe_events();
+
+        let batches = batch_scans(events, encoder, limits, subscriber);
+        let batches = network::impair(batches, impairment, self.faults.network());
+        Ok(Response::new(Box::pin(self.faults.abortable(batches))))
     }
 
     async fn get_status(
         &self,
-        _request: Request<GetStatusRequest>,
+        request: Request<GetStatusRequest>,
     ) -> Result<Response<StatusResponse>, Status> {
+        authorize(&request, Role::Observer)?;
+
         let session_id = self.session_id.lock().await.clone().unwrap_or_default();
+        let error_message = self.error_message.lock().await.clone();
 
         Ok(Response::new(StatusResponse {
             state: self.get_state() as i32,
             scan_count: self.scan_count.load(Ordering::SeqCst),
             current_retention_time: 0.0, // Could track this
             session_id,
-            error_message: String::new(),
+            error_message,
+            instrument_state: self.power.readiness().state as i32,
         }))
     }
 
@@ -231,43 +711,148 @@ impl simulator_service_server::SimulatorService for SimulatorServiceImpl {
         &self,
         request: Request<StartAcquisitionRequest>,
     ) -> Result<Response<StartAcquisitionResponse>, Status> {
+        authorize(&request, Role::Controller)?;
+
+        if self.shutting_down.load(Ordering::SeqCst) {
+            return Err(Status::unavailable("Simulator is shutting down"));
+        }
+
         let current_state = self.get_state();
-        if current_state != AcquisitionState::Idle && current_state != AcquisitionState::Completed {
+        if !matches!(
+            current_state,
+            AcquisitionState::Idle | AcquisitionState::Completed | AcquisitionState::Faulted
+        ) {
             return Ok(Response::new(StartAcquisitionResponse {
                 success: false,
                 session_id: String::new(),
                 error_message: format!("Cannot start acquisition in state {:?}", current_state),
+                recording_path: String::new(),
+            }));
+        }
+
+        let readiness = self.power.readiness();
+        if !readiness.is_ready() {
+            return Ok(Response::new(StartAcquisitionResponse {
+                success: false,
+                session_id: String::new(),
+                error_message: not_ready_message(&readiness),
+                recording_path: String::new(),
             }));
         }
 
         let req = request.into_inner();
+        let params = self.simulation.resolve(req.simulation);
+        let scan_settings = self
+            .instrument
+            .profile
+            .profile()
+            .scan_settings(&params)
+            .map_err(Status::invalid_argument)?;
+        let trigger_timeout = req
+            .trigger_timeout_seconds
+            .or(self.method.trigger_timeout_seconds);
+        if trigger_timeout.is_some_and(|s| !s.is_finite() || s <= 0.0) {
+            return Err(Status::invalid_argument(
+                "trigger_timeout_seconds must be positive",
+            ));
+        }
+        let max_scans = req.max_scans.or(self.method.max_scans);
+        if max_scans.is_some_and(|max| max <= 0) {
+            return Err(Status::invalid_argument("max_scans must be positive"));
+        }
+        let max_duration = req.max_duration_seconds.or(self.method.max_duration_seconds);
+        if max_duration.is_some_and(|s| !s.is_finite() || s <= 0.0) {
+            return Err(Status::invalid_argument(
+                "max_duration_seconds must be positive and finite",
+            ));
+        }
+
+        if let Some(error_message) = self.faults.check_start()? {
+            return Ok(Response::new(StartAcquisitionResponse {
+                success: false,
+                session_id: String::new(),
+                error_message,
+                recording_path: String::new(),
+            }));
+        }
+
         let session_id = uuid::Uuid::new_v4().to_string()[..8].to_string();
+        let replay = req
+            .replay
+            .as_ref()
+            .map(|options| Replay::open(&self.replay, options))
+            .transpose()?;
+        let recorder = req
+            .recording
+            .as_ref()
+            .map(|options| {
+                Recorder::start(&self.recording, options, &self.instrument, &session_id)
+            })
+            .transpose()?;
+        let recording_path = recorder
+            .as_ref()
+            .map(|recorder| recorder.path().display().to_string())
+            .unwrap_or_default();
 
         *self.session_id.lock().await = Some(session_id.clone());
+        self.error_message.lock().await.clear();
         self.scan_count.store(0, Ordering::SeqCst);
         self.set_state(AcquisitionState::Starting);
 
         // Reset generator
-        *self.generator.lock().await = ScanGenerator::new();
+        *self.generator.lock().await = ScanGenerator::new(scan_settings);
 
         // Clone what we need for the async task
-        let self_clone = SimulatorServiceImpl {
-            instrument_name: self.instrument_name.clone(),
-            instrument_id: self.instrument_id.clone(),
-            state: Arc::clone(&self.state),
-            scan_count: Arc::clone(&self.scan_count),
-            scan_sender: self.scan_sender.clone(),
-            generator: Arc::clone(&self.generator),
-            session_id: Arc::clone(&self.session_id),
+        let self_clone = self.clone();
+
+        let task_session_id = session_id.clone();
+
+        let trigger = if req.wait_for_trigger {
+            let (sender, receiver) = oneshot::channel();
+            *self.start_trigger.lock().await = Some(PendingTrigger {
+                session_id: session_id.clone(),
+                sender,
+            });
+            Some(TriggerWait {
+                receiver,
+                // Timeouts beyond what a Duration holds never expire
+                timeout: trigger_timeout
+                    .map(|s| Duration::try_from_secs_f64(s).unwrap_or(Duration::MAX)),
+                start_on_timeout: req.start_on_trigger_timeout,
+            })
+        } else {
+            None
         };
 
-        let max_scans = req.max_scans;
-        let max_duration = req.max_duration_seconds;
-        let params = req.simulation;
+        // The session gets its own trace, linked to the RPC that started it
+        Span::current().record("session_id", session_id.as_str());
+        let session_span = info_span!(
+            parent: None,
+            "acquisition",
+            instrument_id = %self.instrument.id,
+            session_id = %session_id
+        );
+        session_span.follows_from(Span::current());
 
-        tokio::spawn(async move {
-            self_clone.run_acquisition(params, max_scans, max_duration).await;
-        });
+        let task = tokio::spawn(
+            async move {
+                self_clone
+                    .run_acquisition(
+                        task_session_id,
+                        params,
+                        SessionPlan {
+                            max_scans,
+                            max_duration_seconds: max_duration,
+                            trigger,
+                            recorder,
+                            replay,
+                        },
+                    )
+                    .await;
+            }
+            .instrument(session_span),
+        );
+        *self.acquisition_task.lock().await = Some(task);
 
         info!("Started acquisition session: {}", session_id);
 
@@ -275,13 +860,17 @@ impl simulator_service_server::SimulatorService for SimulatorServiceImpl {
             success: true,
             session_id,
             error_message: String::new(),
+            recording_path,
         }))
     }
 
     async fn stop_acquisition(
         &self,
-        _request: Request<StopAcquisitionRequest>,
+        request: Request<StopAcquisitionRequest>,
     ) -> Result<Response<StopAcquisitionResponse>, Status> {
+        authorize(&request, Role::Controller)?;
+
+        self.record_session_span().await;
         self.set_state(AcquisitionState::Stopping);
 
         let final_count = self.scan_count.load(Ordering::SeqCst);
@@ -295,8 +884,11 @@ impl simulator_service_server::SimulatorService for SimulatorServiceImpl {
 
     async fn pause_acquisition(
         &self,
-        _request: Request<PauseAcquisitionRequest>,
+        request: Request<PauseAcquisitionRequest>,
     ) -> Result<Response<PauseAcquisitionResponse>, Status> {
+        authorize(&request, Role::Controller)?;
+
+        self.record_session_span().await;
         Ok(Response::new(PauseAcquisitionResponse {
             success: false,
             error_message: "Pause not implemented in simulator".to_string(),
@@ -305,33 +897,280 @@ impl simulator_service_server::SimulatorService for SimulatorServiceImpl {
 
     async fn resume_acquisition(
         &self,
-        _request: Request<ResumeAcquisitionRequest>,
+        request: Request<ResumeAcquisitionRequest>,
     ) -> Result<Response<ResumeAcquisitionResponse>, Status> {
+        authorize(&request, Role::Controller)?;
+
+        self.record_session_span().await;
         Ok(Response::new(ResumeAcquisitionResponse {
             success: false,
             error_message: "Resume not implemented in simulator".to_string(),
         }))
     }
 
+    async fn trigger_start(
+        &self,
+        request: Request<TriggerStartRequest>,
+    ) -> Result<Response<TriggerStartResponse>, Status> {
+        authorize(&request, Role::Controller)?;
+
+        let requested = request.into_inner().session_id;
+        let mut pending = self.start_trigger.lock().await;
+        let error_message = match pending.as_ref() {
+            None => Some("No acquisition is waiting for a start trigger".to_string()),
+            Some(trigger) if !requested.is_empty() && trigger.session_id != requested => Some(
+                format!("Session {} is not waiting for a start trigger", requested),
+            ),
+            Some(_) => None,
+        };
+        if let Some(error_message) = error_message {
+            return Ok(Response::new(TriggerStartResponse {
+                success: false,
+                session_id: String::new(),
+                trigger_timestamp_ms: 0,
+                error_message,
+            }));
+        }
+
+        let trigger = pending.take().expect("checked above");
+        let timestamp_ms = current_timestamp_ms();
+        // The session may have timed out or stopped while this call waited for the lock
+        let success = trigger.sender.send(timestamp_ms).is_ok();
+        Ok(Response::new(TriggerStartResponse {
+            success,
+            session_id: trigger.session_id,
+            trigger_timestamp_ms: if success { timestamp_ms } else { 0 },
+            error_message: if success {
+                String::new()
+            } else {
+                "The session stopped waiting for a start trigger".to_string()
+            },
+        }))
+    }
+
+    /// Served by [`crate::fleet::Fleet`]; a single instrument only lists itself
+    async fn list_instruments(
+        &self,
+        request: Request<ListInstrumentsRequest>,
+    ) -> Result<Response<ListInstrumentsResponse>, Status> {
+        authorize(&request, Role::Observer)?;
+
+        let summary = InstrumentSummary {
+            is_default: true,
+            ..self.summary().await
+        };
+        Ok(Response::new(ListInstrumentsResponse {
+            instruments: vec![summary],
+        }))
+    }
+
     async fn get_instrument_info(
         &self,
-        _request: Request<GetInstrumentInfoRequest>,
+        request: Request<GetInstrumentInfoRequest>,
     ) -> Result<Response<InstrumentInfoResponse>, Status> {
+        authorize(&request, Role::Observer)?;
+
+        let instrument = &self.instrument;
+        let profile = instrument.profile.profile();
         Ok(Response::new(InstrumentInfoResponse {
-            instrument_name: self.instrument_name.clone(),
-            instrument_id: self.instrument_id.clone(),
-            model: "Orbitrap Exploris 480".to_string(),
-            serial_number: self.instrument_id.clone(),
-            firmware_version: "1.0.0".to_string(),
+            instrument_name: instrument.name(),
+            instrument_id: instrument.id.clone(),
+            model: profile.model.to_string(),
+            serial_number: instrument.serial_number(),
+            firmware_version: instrument
+                .firmware_version
+                .clone()
+                .unwrap_or_else(|| profile.firmware_version.to_string()),
             simulator_version: env!("CARGO_PKG_VERSION").to_string(),
-            supported_analyzers: vec!["Orbitrap".to_string()],
-            supported_fragmentation_types: vec![
-                FragmentationType::FragmentationHcd as i32,
-                FragmentationType::FragmentationCid as i32,
-            ],
-            max_resolution: 480000.0,
-            min_mz: 50.0,
-            max_mz: 6000.0,
+            supported_analyzers: profile.analyzer_names(),
+            supported_fragmentation_types: profile
+                .fragmentation_types
+                .iter()
+                .map(|&fragmentation| fragmentation as i32)
+                .collect(),
+            max_resolution: profile.max_resolution(),
+            min_mz: profile.min_mz,
+            max_mz: profile.max_mz,
+            profile: instrument.profile.name(),
+            supported_resolutions: profile.resolutions.to_vec(),
+            max_scan_rate: profile.max_scan_rate.unwrap_or(0.0),
         }))
     }
+
+    async fn get_instrument_values(
+        &self,
+        request: Request<GetInstrumentValuesRequest>,
+    ) -> Result<Response<InstrumentValuesResponse>, Status> {
+        authorize(&request, Role::Observer)?;
+
+        let names = request.into_inner().names;
+        let values = self.tune.values(&names).map_err(Status::invalid_argument)?;
+        Ok(Response::new(InstrumentValuesResponse { values }))
+    }
+
+    async fn set_instrument_values(
+        &self,
+        request: Request<SetInstrumentValuesRequest>,
+    ) -> Result<Response<InstrumentValuesResponse>, Status> {
+        authorize(&request, Role::Controller)?;
+
+        let mut setpoints: Vec<(String, f64)> =
+            request.into_inner().setpoints.into_iter().collect();
+        setpoints.sort_by(|a, b| a.0.cmp(&b.0));
+        let changes = self.tune.set(&setpoints).map_err(Status::invalid_argument)?;
+
+        let session_id = self.session_id.lock().await.clone().unwrap_or_default();
+        for change in changes {
+            info!(
+                "Instrument value {} changed from {} to {}",
+                change.name, change.old_setpoint, change.new_setpoint
+            );
+            self.publish(
+                &session_id,
+                stream_event::Event::ParameterChanged(ParameterChanged {
+                    name: change.name.to_string(),
+                    old_value: change.old_setpoint.to_string(),
+                    new_value: change.new_setpoint.to_string(),
+                }),
+            )
+            .await;
+        }
+
+        let names: Vec<String> = setpoints.into_iter().map(|(name, _)| name).collect();
+        let values = self.tune.values(&names).map_err(Status::invalid_argument)?;
+        Ok(Response::new(InstrumentValuesResponse { values }))
+    }
+
+    async fn set_instrument_mode(
+        &self,
+        request: Request<SetInstrumentModeRequest>,
+    ) -> Result<Response<InstrumentModeResponse>, Status> {
+        authorize(&request, Role::Controller)?;
+
+        let requested = request.into_inner().mode;
+        let mode = PowerMode::from_proto(requested).ok_or_else(|| {
+            Status::invalid_argument(format!("Unknown instrument mode {}", requested))
+        })?;
+
+        let current_state = self.get_state();
+        if !matches!(
+            current_state,
+            AcquisitionState::Idle | AcquisitionState::Completed | AcquisitionState::Faulted
+        ) {
+            return Err(Status::failed_precondition(format!(
+                "Cannot change instrument mode in acquisition state {:?}",
+                current_state
+            )));
+        }
+
+        let previous = self.power.set_mode(mode);
+        let readiness = self.power.readiness();
+        if previous != mode {
+            info!(
+                "Instrument mode changed from {:?} to {:?} ({:?})",
+                previous, mode, readiness.state
+            );
+        }
+        Ok(Response::new(readiness.to_response()))
+    }
+
+    async fn get_instrument_mode(
+        &self,
+        request: Request<GetInstrumentModeRequest>,
+    ) -> Result<Response<InstrumentModeResponse>, Status> {
+        authorize(&request, Role::Observer)?;
+
+        Ok(Response::new(self.power.readiness().to_response()))
+    }
+
+    async fn stream_status_log(
+        &self,
+        request: Request<StreamStatusLogRequest>,
+    ) -> Result<Response<Self::StreamStatusLogStream>, Status> {
+        authorize(&request, Role::Observer)?;
+
+        let interval = match request.into_inner().interval_seconds {
+            0.0 => self.status_log_config.interval_seconds,
+            interval if interval.is_finite() && interval >= MIN_INTERVAL_SECONDS => interval,
+            _ => {
+                return Err(Status::invalid_argument(format!(
+                    "interval_seconds must be finite and at least {}",
+                    MIN_INTERVAL_SECONDS
+                )))
+            }
+        };
+
+        let (sender, receiver) = mpsc::channel(1);
+        let service = self.clone();
+        let mut close_streams = self.close_streams.subscribe();
+        tokio::spawn(async move {
+            let mut ticks = tokio::time::interval(Duration::from_secs_f64(interval));
+            ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            loop {
+                tokio::select! {
+                    _ = ticks.tick() => {}
+                    _ = sender.closed() => return,
+                    _ = close_streams.wait_for(|&closing| closing) => return,
+                }
+                let record = service.status_record().await;
+                if sender.send(Ok(record)).await.is_err() {
+                    return;
+                }
+            }
+        });
+
+        Ok(Response::new(ReceiverStream::new(receiver)))
+    }
+
+    async fn set_fault_plan(
+        &self,
+        request: Request<SetFaultPlanRequest>,
+    ) -> Result<Response<FaultPlanResponse>, Status> {
+        authorize(&request, Role::Admin)?;
+
+        let plan = FaultPlan::from(request.into_inner().plan.unwrap_or_default());
+        plan.validate().map_err(Status::invalid_argument)?;
+
+        if plan.is_empty() {
+            info!("Fault plan cleared");
+        } else {
+            warn!("Fault plan set: {:?}", plan);
+        }
+        self.faults.set_plan(plan);
+
+        Ok(Response::new(FaultPlanResponse {
+            plan: Some(self.faults.plan().into()),
+        }))
+    }
+
+    async fn get_fault_plan(
+        &self,
+        request: Request<GetFaultPlanRequest>,
+    ) -> Result<Response<FaultPlanResponse>, Status> {
+        authorize(&request, Role::Observer)?;
+
+        Ok(Response::new(FaultPlanResponse {
+            plan: Some(self.faults.plan().into()),
+        }))
+    }
+}
+
+fn not_ready_message(readiness: &Readiness) -> String {
+    match readiness.state {
+        InstrumentState::NotReady => format!(
+            "Instrument is not ready: {} ({:.0} s remaining)",
+            readiness.reason,
+            readiness.seconds_until_ready.ceil()
+        ),
+        state => format!("Instrument is not ready: {:?}", state),
+    }
+}
+
+/// An in-band event of a session, stamped now
+fn stream_event(session_id: &str, event: stream_event::Event) -> StreamEvent {
+    StreamEvent {
+        session_id: session_id.to_string(),
+        timestamp_ms: current_timestamp_ms(),
+        event: Some(event),
+    }
 }
@@ -0,0 +1,111 @@
+//! Graceful shutdown: termination signals and draining of subscriber streams.
+
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+
+use tokio::sync::broadcast::{self, error::RecvError, error::TryRecvError};
+use tokio::sync::watch;
+use tokio_stream::wrappers::errors::BroadcastStreamRecvError;
+use tokio_stream::Stream;
+
+/// Resolves on SIGINT (Ctrl+C) or, on Unix, SIGTERM
+pub async fn signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("Failed to install Ctrl+C handler");
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("Failed to install SIGTERM handler")
+            .recv()
+            .await;
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {}
+        _ = terminate => {}
+    }
+}
+
+/// Streams a broadcast subscription until shutdown has been requested and every item
+/// already queued for this subscriber has been yielded.
+///
+/// Once shutdown is requested the receiver is drained with `try_recv`, so the stream
+/// ends only when its queue is empty; a `Pending` from the runtime's cooperative
+/// scheduling budget is not mistaken for that.
+pub struct DrainOnShutdown<T> {
+    /// The next item or the shutdown request, whichever comes first; `None` once
+    /// draining has begun
+    next: Option<Pin<Box<dyn Future<Output = Next<T>> + Send>>>,
+    /// The receiver while draining
+    draining: Option<broadcast::Receiver<T>>,
+}
+
+/// An item, or `None` for the shutdown request, with the receivers to continue with
+type Next<T> = (
+    Option<Result<T, RecvError>>,
+    broadcast::Receiver<T>,
+    watch::Receiver<bool>,
+);
+
+async fn next<T: Clone>(
+    mut receiver: broadcast::Receiver<T>,
+    mut shutdown: watch::Receiver<bool>,
+) -> Next<T> {
+    let item = tokio::select! {
+        biased;
+        item = receiver.recv() => Some(item),
+        // A dropped sender also means the server is going away
+        _ = shutdown.wait_for(|&closing| closing) => None,
+    };
+    (item, receiver, shutdown)
+}
+
+impl<T: Clone + Send + 'static> DrainOnShutdown<T> {
+    pub fn new(receiver: broadcast::Receiver<T>, shutdown: watch::Receiver<bool>) -> Self {
+        Self {
+            next: Some(Box::pin(next(receiver, shutdown))),
+            draining: None,
+        }
+    }
+}
+
+impl<T: Clone + Send + 'static> Stream for DrainOnShutdown<T> {
+    type Item = Result<T, BroadcastStreamRecvError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if let Some(pending) = self.next.as_mut() {
+            let (item, receiver, shutdown) = ready!(pending.as_mut().poll(cx));
+            match item {
+                Some(item) => {
+                    self.next = Some(Box::pin(next(receiver, shutdown)));
+                    return Poll::Ready(match item {
+                        Ok(item) => Some(Ok(item)),
+                        Err(RecvError::Closed) => None,
+                        Err(RecvError::Lagged(n)) => Some(Err(BroadcastStreamRecvError::Lagged(n))),
+                    });
+                }
+                None => {
+                    self.next = None;
+                    self.draining = Some(receiver);
+                }
+            }
+        }
+
+        let Some(receiver) = self.draining.as_mut() else {
+            return Poll::Ready(None);
+        };
+        Poll::Ready(match receiver.try_recv() {
+            Ok(item) => Some(Ok(item)),
+            Err(TryRecvError::Lagged(n)) => Some(Err(BroadcastStreamRecvError::Lagged(n))),
+            Err(TryRecvError::Empty | TryRecvError::Closed) => None,
+        })
+    }
+}
@@ -4,31 +4,64 @@ use rand::rngs::StdRng;
 use rand_distr::{Distribution, Normal};
 use std::time::{SystemTime, UNIX_EPOCH};
 
-use crate::proto::{FragmentationType, Polarity, ScanMessage};
+use crate::profiles::{Analyzer, ScanSettings};
+use crate::proto::{FragmentationType, Polarity, ScanMessage, SimulationParameters};
+use crate::tune::SignalConditions;
+
+/// Smallest spray fluctuation factor, so an unstable spray dims MS1 scans without
+/// blanking them
+const MIN_FLUCTUATION: f64 = 0.05;
 
 /// Generates realistic-looking mass spectrometry scans
 pub struct ScanGenerator {
     scan_number: i32,
     retention_time: f64,
+    settings: ScanSettings,
+    conditions: SignalConditions,
     random: StdRng,
 }
 
 impl ScanGenerator {
-    pub fn new() -> Self {
+    pub fn new(settings: ScanSettings) -> Self {
         Self {
             scan_number: 0,
             retention_time: 0.0,
+            settings,
+            conditions: SignalConditions::default(),
             random: StdRng::from_entropy(),
         }
     }
 
+    /// A generator whose scans are the same for the same seed and calls
+    pub fn with_seed(settings: ScanSettings, seed: u64) -> Self {
+        Self {
+            random: StdRng::seed_from_u64(seed),
+            ..Self::new(settings)
+        }
+    }
+
+    /// Applies the ion source conditions to subsequent MS1 scans; MS2 scans follow
+    /// through their precursor intensity
+    pub fn set_conditions(&mut self, conditions: SignalConditions) {
+        self.conditions = conditions;
+    }
+
     /// Generates an MS1 (survey) scan
     pub fn generate_ms1(&mut self, min_mz: f64, max_mz: f64, peak_count_override: Option<usize>) -> ScanMessage {
         self.scan_number += 1;
 
         // Generate realistic peak count (500-2000 for MS1) unless overridden (stress tests).
         let peak_count = peak_count_override.unwrap_or_else(|| self.random.gen_range(500..2000));
-        let (mz_values, intensity_values) = self.generate_spectrum(peak_count, min_mz, max_mz, 1e6, 1e8);
+        let (mz_values, mut intensity_values) = self.generate_spectrum(peak_count, min_mz, max_mz, 1e6, 1e8);
+
+        // Source conditions scale the whole spectrum, with scan-to-scan spray fluctuation
+        let fluctuation = Normal::new(1.0, self.conditions.instability).map_or(1.0, |normal| {
+            normal.sample(&mut self.random).max(MIN_FLUCTUATION)
+        });
+        let scale = self.conditions.intensity_scale * fluctuation;
+        for intensity in &mut intensity_values {
+            *intensity *= scale;
+        }
 
         // Calculate aggregates
         let (base_peak_mz, base_peak_intensity, tic) = calculate_aggregates(&mz_values, &intensity_values);
@@ -48,12 +81,14 @@ impl ScanGenerator {
             isolation_width: None,
             collision_energy: None,
             fragmentation_type: FragmentationType::FragmentationUnknown as i32,
-            analyzer: "Orbitrap".to_string(),
-            resolution_at_mz200: 120000.0,
+            analyzer: Analyzer::Orbitrap.name().to_string(),
+            resolution_at_mz200: self.settings.ms1_resolution,
             mass_accuracy_ppm: 3.0,
             polarity: Polarity::Positive as i32,
             timestamp_ms: current_timestamp_ms(),
             trailer_extra: Default::default(),
+            session_id: String::new(),
+            encoded_spectrum: None,
         };
 
         // Advance retention time (~0.5 seconds per cycle)
@@ -73,7 +108,7 @@ impl ScanGenerator {
         let max_mz = precursor_mz * 0.95;
         let (mz_values, intensity_values) = self.generate_spectrum(
             peak_count,
-            100.0,
+            100.0_f64.min(precursor_mz * 0.5),
             max_mz,
             precursor_intensity * 0.01,
             precursor_intensity * 0.5,
@@ -97,13 +132,15 @@ impl ScanGenerator {
             precursor_intensity: Some(precursor_intensity),
             isolation_width: Some(1.6),
             collision_energy: Some(30.0),
-            fragmentation_type: FragmentationType::FragmentationHcd as i32,
-            analyzer: "Orbitrap".to_string(),
-            resolution_at_mz200: 30000.0, // Lower resolution for MS2
-            mass_accuracy_ppm: 5.0,
+            fragmentation_type: self.settings.fragmentation_type as i32,
+            analyzer: self.settings.ms2_analyzer.name().to_string(),
+            resolution_at_mz200: self.settings.ms2_resolution,
+            mass_accuracy_ppm: self.settings.ms2_mass_accuracy_ppm,
             polarity: Polarity::Positive as i32,
             timestamp_ms: current_timestamp_ms(),
             trailer_extra: Default::default(),
+            session_id: String::new(),
+            encoded_spectrum: None,
         }
     }
 
@@ -123,8 +160,8 @@ impl ScanGenerator {
         let base_peak_count = peak_count / 5; // ~20% are "real" peaks
 
         for _ in 0..base_peak_count {
-            let base_mz = self.random.gen_range(min_mz..max_mz);
-            let base_intensity = self.random.gen_range(min_intensity..max_intensity);
+            let base_mz = self.sample_range(min_mz, max_mz);
+            let base_intensity = self.sample_range(min_intensity, max_intensity);
 
             // Add the monoisotopic peak
             mz_values.push(base_mz);
@@ -134,14 +171,14 @@ impl ScanGenerator {
             // Real isotopic patterns depend on elemental composition
             let isotope_spacing = 1.003355; // ~1 Da for peptides
 
-            // A+1 isotope (~60-80% of A)
-            if self.random.gen_bool(0.8) {
+            // A+1 isotope (~60-80% of A); isotopes beyond the scan range are not recorded
+            if self.random.gen_bool(0.8) && base_mz + isotope_spacing <= max_mz {
                 mz_values.push(base_mz + isotope_spacing);
                 intensity_values.push(base_intensity * self.random.gen_range(0.4..0.8));
             }
 
             // A+2 isotope (~20-40% of A)
-            if self.random.gen_bool(0.6) {
+            if self.random.gen_bool(0.6) && base_mz + 2.0 * isotope_spacing <= max_mz {
                 mz_values.push(base_mz + 2.0 * isotope_spacing);
                 intensity_values.push(base_intensity * self.random.gen_range(0.1..0.4));
             }
@@ -149,10 +186,10 @@ impl ScanGenerator {
 
         // Add noise peaks
         let noise_count = peak_count - mz_values.len();
-        let noise_normal = Normal::new(0.0, min_intensity * 0.1).unwrap();
+        let noise_normal = Normal::new(0.0, (min_intensity * 0.1).max(0.0)).unwrap();
 
         for _ in 0..noise_count {
-            let mz = self.random.gen_range(min_mz..max_mz);
+            let mz = self.sample_range(min_mz, max_mz);
             let noise: f64 = noise_normal.sample(&mut self.random).abs();
             let intensity = min_intensity * 0.01 + noise;
 
@@ -170,6 +207,16 @@ impl ScanGenerator {
         (sorted_mz, sorted_intensity)
     }
 
+    /// A uniform value in `low..high`, or `low` when the range is empty, e.g. the
+    /// intensities of fragments of a precursor with no signal
+    fn sample_range(&mut self, low: f64, high: f64) -> f64 {
+        if low < high {
+            self.random.gen_range(low..high)
+        } else {
+            low
+        }
+    }
+
     /// Returns a random precursor from a simulated MS1 spectrum
     pub fn select_precursor(&mut self, ms1_scan: &ScanMessage) -> (f64, f64) {
         if ms1_scan.mz_values.is_empty() {
@@ -198,6 +245,51 @@ impl ScanGenerator {
     }
 }
 
+/// The MS1/MS2 duty cycle of a data-dependent acquisition: an MS1 survey scan, then
+/// `ms2_per_ms1` MS2 scans of precursors picked from it. The server's acquisition loop
+/// and offline generation both step through it one scan at a time, on their own clocks.
+pub struct AcquisitionCycle {
+    min_mz: f64,
+    max_mz: f64,
+    ms2_per_ms1: i32,
+    pub ms1_peak_count: Option<usize>,
+    pub ms2_peak_count: Option<usize>,
+    /// Survey scan of the current cycle and the MS2 scans still to take from it
+    survey: Option<(ScanMessage, i32)>,
+}
+
+impl AcquisitionCycle {
+    pub fn new(params: &SimulationParameters) -> Self {
+        Self {
+            min_mz: params.min_mz,
+            max_mz: params.max_mz,
+            ms2_per_ms1: params.ms2_per_ms1,
+            ms1_peak_count: params.ms1_peak_count.filter(|v| *v > 0).map(|v| v as usize),
+            ms2_peak_count: params.ms2_peak_count.filter(|v| *v > 0).map(|v| v as usize),
+            survey: None,
+        }
+    }
+
+    /// Whether the next scan is an MS1 survey scan, which source conditions apply to
+    pub fn next_is_survey(&self) -> bool {
+        !matches!(self.survey, Some((_, remaining)) if remaining > 0)
+    }
+
+    /// Generates the next scan of the cycle
+    pub fn next_scan(&mut self, generator: &mut ScanGenerator) -> ScanMessage {
+        if let Some((survey, remaining)) = &mut self.survey {
+            if *remaining > 0 {
+                *remaining -= 1;
+                let (precursor_mz, precursor_int) = generator.select_precursor(survey);
+                return generator.generate_ms2(precursor_mz, precursor_int, self.ms2_peak_count);
+            }
+        }
+        let survey = generator.generate_ms1(self.min_mz, self.max_mz, self.ms1_peak_count);
+        self.survey = Some((survey.clone(), self.ms2_per_ms1));
+        survey
+    }
+}
+
 fn calculate_aggregates(mz_values: &[f64], intensity_values: &[f64]) -> (f64, f64, f64) {
     if mz_values.is_empty() || intensity_values.is_empty() {
         return (0.0, 0.0, 0.0);
@@ -218,7 +310,7 @@ fn calculate_aggregates(mz_values: &[f64], intensity_values: &[f64]) -> (f64, f6
     (mz_values[max_idx], max_intensity, tic)
 }
 
-fn current_timestamp_ms() -> i64 {
+pub fn current_timestamp_ms() -> i64 {
     SystemTime::now()
         .duration_since(UNIX_EPOCH)
         .expect("Time went backwards")
@@ -227,6 +319,6 @@ fn current_timestamp_ms() -> i64 {
 
 impl Default for ScanGenerator {
     fn default() -> Self {
-        Self::new()
+        Self::new(ScanSettings::default())
     }
 }
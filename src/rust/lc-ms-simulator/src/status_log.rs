@@ -0,0 +1,265 @@
+//! Simulated instrument status log.
+//!
+//! Real instruments record vacuum, temperature and ion source readings every few seconds.
+//! Here every reading drifts around its nominal value with its own time constant, and
+//! acquisitions add gas load to the vacuum and draw spray current. Scripted excursions
+//! from the fault plan push a reading towards a target, e.g. a slowly failing vacuum.
+
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+use rand_distr::{Distribution, StandardNormal};
+use serde::{Deserialize, Serialize};
+
+use crate::proto::{self, AcquisitionState, InstrumentState, StatusLogRecord};
+use crate::simulator::current_timestamp_ms;
+
+/// Shortest interval a subscriber may request
+pub const MIN_INTERVAL_SECONDS: f64 = 0.05;
+
+/// Time constant of the vacuum and temperature response to acquisitions starting or
+/// stopping
+const LOAD_TIME_CONSTANT_SECONDS: f64 = 20.0;
+
+/// A scripted change to one status reading, timed from when the fault plan was set
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StatusExcursion {
+    /// Record field to change, e.g. `high_vacuum_mbar`
+    pub reading: String,
+    pub after_seconds: f64,
+    /// Time to reach the target; 0 jumps to it
+    pub ramp_seconds: f64,
+    pub target: f64,
+}
+
+impl StatusExcursion {
+    pub fn validate(&self) -> Result<(), String> {
+        let Some(reading) = Reading::from_name(&self.reading) else {
+            let names: Vec<_> = READINGS.iter().map(|(name, _)| *name).collect();
+            return Err(format!(
+                "reading '{}' is not one of {}",
+                self.reading,
+                names.join(", ")
+            ));
+        };
+        if !self.after_seconds.is_finite() || self.after_seconds < 0.0 {
+            return Err("after_seconds must not be negative".to_string());
+        }
+        if !self.ramp_seconds.is_finite() || self.ramp_seconds < 0.0 {
+            return Err("ramp_seconds must not be negative".to_string());
+        }
+        if !self.target.is_finite() || (reading.channel().logarithmic && self.target <= 0.0) {
+            return Err(format!(
+                "target must be a positive {} reading",
+                self.reading
+            ));
+        }
+        Ok(())
+    }
+
+    /// Moves `value` towards the target by how far the excursion has progressed
+    fn apply(&self, value: f64, logarithmic: bool, elapsed: Duration) -> f64 {
+        let elapsed = elapsed.as_secs_f64() - self.after_seconds;
+        if elapsed < 0.0 {
+            return value;
+        }
+        let progress = if self.ramp_seconds > 0.0 {
+            (elapsed / self.ramp_seconds).min(1.0)
+        } else {
+            1.0
+        };
+
+        if logarithmic {
+            // Pressures rise by decades, so ramp them on a log scale
+            (value.ln() + (self.target.ln() - value.ln()) * progress).exp()
+        } else {
+            value + (self.target - value) * progress
+        }
+    }
+}
+
+/// Readings the simulator models itself; source voltage and temperature are tune
+/// readbacks
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Reading {
+    ForeVacuum,
+    HighVacuum,
+    IonGauge,
+    OrbitrapTemperature,
+    AmbientTemperature,
+    SprayCurrent,
+}
+
+const READINGS: [(&str, Reading); 6] = [
+    ("fore_vacuum_mbar", Reading::ForeVacuum),
+    ("high_vacuum_mbar", Reading::HighVacuum),
+    ("ion_gauge_mbar", Reading::IonGauge),
+    ("orbitrap_temperature_c", Reading::OrbitrapTemperature),
+    ("ambient_temperature_c", Reading::AmbientTemperature),
+    ("spray_current_ua", Reading::SprayCurrent),
+];
+
+/// Drift of one reading around its nominal value
+#[derive(Debug, Clone, Copy)]
+struct ChannelModel {
+    nominal: f64,
+    /// Drift is relative (log scale) rather than absolute
+    logarithmic: bool,
+    /// Standard deviation of the drift
+    sigma: f64,
+    time_constant_seconds: f64,
+    /// Relative (log scale) or absolute change at full acquisition load
+    load_effect: f64,
+}
+
+impl Reading {
+    fn from_name(name: &str) -> Option<Self> {
+        READINGS
+            .iter()
+            .find(|(reading, _)| *reading == name)
+            .map(|(_, reading)| *reading)
+    }
+
+    #[rustfmt::skip]
+    fn channel(self) -> ChannelModel {
+        let (nominal, logarithmic, sigma, time_constant_seconds, load_effect) = match self {
+            Reading::ForeVacuum => (1.6, true, 0.03, 600.0, 0.05),
+            Reading::HighVacuum => (2.5e-5, true, 0.05, 300.0, 0.1),
+            // HCD and C-trap gas reach the analyzer during acquisitions
+            Reading::IonGauge => (4.0e-10, true, 0.05, 300.0, 0.35),
+            Reading::OrbitrapTemperature => (29.5, false, 0.01, 900.0, 0.02),
+            Reading::AmbientTemperature => (22.0, false, 0.3, 1800.0, 0.0),
+            // Scaled by spray voltage and LC flow, which only runs during acquisitions
+            Reading::SprayCurrent => (0.4, true, 0.1, 2.0, 0.0),
+        };
+        ChannelModel { nominal, logarithmic, sigma, time_constant_seconds, load_effect }
+    }
+}
+
+/// Inputs from the rest of the simulator for one record
+pub struct StatusInputs<'a> {
+    pub state: AcquisitionState,
+    pub session_id: String,
+    pub instrument_state: InstrumentState,
+    pub spray_voltage: f64,
+    pub ion_transfer_temperature: f64,
+    pub excursions: &'a [StatusExcursion],
+    /// Time since the fault plan holding the excursions was set
+    pub since_plan_set: Duration,
+}
+
+struct StatusModel {
+    last_update: Instant,
+    /// Current drift of each reading, indexed like [`READINGS`]
+    drift: [f64; READINGS.len()],
+    /// Acquisition load, approaching 1 while acquiring and 0 otherwise
+    load: f64,
+    random: StdRng,
+}
+
+/// Status readings shared by every status log subscriber, so concurrent streams
+/// report the same instrument
+pub struct StatusLog {
+    model: Mutex<StatusModel>,
+}
+
+impl StatusLog {
+    pub fn new() -> Self {
+        Self {
+            model: Mutex::new(StatusModel {
+                last_update: Instant::now(),
+                drift: [0.0; READINGS.len()],
+                load: 0.0,
+                random: StdRng::from_entropy(),
+            }),
+        }
+    }
+
+    /// Advances the readings to now and returns them as a record
+    pub fn record(&self, inputs: StatusInputs) -> StatusLogRecord {
+        let mut model = self.model.lock().unwrap();
+        let now = Instant::now();
+        let dt = now.duration_since(model.last_update).as_secs_f64();
+        model.last_update = now;
+
+        let acquiring = inputs.state == AcquisitionState::Acquiring;
+        let target_load = if acquiring { 1.0 } else { 0.0 };
+        let decay = (-dt / LOAD_TIME_CONSTANT_SECONDS).exp();
+        model.load = target_load + (model.load - target_load) * decay;
+        let load = model.load;
+
+        let mut values = [0.0; READINGS.len()];
+        for (index, (_, reading)) in READINGS.iter().enumerate() {
+            let channel = reading.channel();
+
+            // Exact Ornstein-Uhlenbeck step, so the drift is independent of the interval
+            let decay = (-dt / channel.time_constant_seconds).exp();
+            let noise: f64 = StandardNormal.sample(&mut model.random);
+            let drift =
+                model.drift[index] * decay + channel.sigma * (1.0 - decay * decay).sqrt() * noise;
+            model.drift[index] = drift;
+
+            let mut value = if channel.logarithmic {
+                channel.nominal * (drift + channel.load_effect * load).exp()
+            } else {
+                channel.nominal + drift + channel.load_effect * load
+            };
+            if *reading == Reading::SprayCurrent {
+                value = if acquiring && inputs.spray_voltage >= 1000.0 {
+                    value * inputs.spray_voltage / 3500.0
+                } else {
+                    0.0
+                };
+            }
+
+            for excursion in inputs.excursions {
+                if Reading::from_name(&excursion.reading) == Some(*reading) {
+                    value = excursion.apply(value, channel.logarithmic, inputs.since_plan_set);
+                }
+            }
+            values[index] = value;
+        }
+
+        let [fore_vacuum_mbar, high_vacuum_mbar, ion_gauge_mbar, orbitrap_temperature_c, ambient_temperature_c, spray_current_ua] =
+            values;
+        StatusLogRecord {
+            timestamp_ms: current_timestamp_ms(),
+            acquisition_state: inputs.state as i32,
+            session_id: inputs.session_id,
+            fore_vacuum_mbar,
+            high_vacuum_mbar,
+            ion_gauge_mbar,
+            orbitrap_temperature_c,
+            ambient_temperature_c,
+            spray_voltage_v: inputs.spray_voltage,
+            spray_current_ua,
+            ion_transfer_temperature_c: inputs.ion_transfer_temperature,
+            instrument_state: inputs.instrument_state as i32,
+        }
+    }
+}
+
+impl From<proto::StatusExcursion> for StatusExcursion {
+    fn from(excursion: proto::StatusExcursion) -> Self {
+        Self {
+            reading: excursion.reading,
+            after_seconds: excursion.after_seconds,
+            ramp_seconds: excursion.ramp_seconds,
+            target: excursion.target,
+        }
+    }
+}
+
+impl From<StatusExcursion> for proto::StatusExcursion {
+    fn from(excursion: StatusExcursion) -> Self {
+        Self {
+            reading: excursion.reading,
+            after_seconds: excursion.after_seconds,
+            ramp_seconds: excursion.ramp_seconds,
+            target: excursion.target,
+        }
+    }
+}
@@ -0,0 +1,463 @@
+//! Stream logs: event streams recorded with their timing.
+//!
+//! A stream log holds [`StreamEvent`]s exactly as they arrived, each stamped with the
+//! microseconds since the first one, so a replay can reproduce a stream bit for bit and
+//! on its original schedule. Records are gathered into blocks of length-delimited
+//! protobuf, optionally zstd-compressed, and an index of the blocks closes the file; the
+//! layout is documented with `StreamLogHeader` in `simulator.proto`.
+//!
+//! [`StreamLogWriter`] flushes every block as it completes, so a log cut short by a
+//! crash keeps everything up to its last block, and [`StreamLogReader`] reads such a log
+//! up to that point.
+
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::time::Duration;
+
+use prost::Message;
+
+use crate::proto::stream_event::Event;
+use crate::proto::stream_log_frame::Frame;
+use crate::proto::*;
+
+/// First and last bytes of every stream log
+pub const MAGIC: &[u8; 8] = b"LCMSLOG\0";
+
+/// File extension of stream logs
+pub const EXTENSION: &str = "lcmslog";
+
+/// Version written to `StreamLogHeader.version`
+pub const FORMAT_VERSION: u32 = 1;
+
+/// Uncompressed size at which a block is written
+const BLOCK_SIZE: usize = 64 * 1024;
+
+/// Longest span of a block, so a slow stream still reaches the disk regularly
+const BLOCK_SPAN_US: i64 = 1_000_000;
+
+/// Largest frame a reader accepts, against reading a corrupt length
+const MAX_FRAME_SIZE: u64 = 256 * 1024 * 1024;
+
+/// Index offset and magic
+const FOOTER_SIZE: i64 = 16;
+
+pub struct StreamLogWriter<W: Write> {
+    out: W,
+    /// Bytes written so far, which is the offset of the next frame
+    written: u64,
+    zstd: bool,
+    block: Vec<u8>,
+    /// Index entry of the block being gathered; the offset is set when it is written
+    entry: Option<StreamLogBlockEntry>,
+    index: StreamLogIndex,
+}
+
+impl StreamLogWriter<BufWriter<File>> {
+    /// Starts a log at `path`, which must not exist yet
+    pub fn create(path: &Path, header: StreamLogHeader) -> io::Result<Self> {
+        let file = File::options().write(true).create_new(true).open(path)?;
+        Self::new(BufWriter::new(file), header)
+    }
+}
+
+impl<W: Write> StreamLogWriter<W> {
+    /// Writes the magic and the header, with the version set to [`FORMAT_VERSION`]
+    pub fn new(mut out: W, mut header: StreamLogHeader) -> io::Result<Self> {
+        header.version = FORMAT_VERSION;
+        let header_bytes = header.encode_length_delimited_to_vec();
+        out.write_all(MAGIC)?;
+        out.write_all(&header_bytes)?;
+        Ok(Self {
+            out,
+            written: (MAGIC.len() + header_bytes.len()) as u64,
+            zstd: header.compression() == StreamLogCompression::Zstd,
+            block: Vec::with_capacity(BLOCK_SIZE),
+            entry: None,
+            index: StreamLogIndex::default(),
+        })
+    }
+
+    /// Appends an event that arrived `elapsed` after the first one
+    pub fn write(&mut self, elapsed: Duration, event: &StreamEvent) -> io::Result<()> {
+        let elapsed_us = i64::try_from(elapsed.as_micros()).unwrap_or(i64::MAX);
+        let record = StreamLogRecord {
+            elapsed_us,
+            event: Some(event.clone()),
+        };
+        record
+            .encode_length_delimited(&mut self.block)
+            .map_err(io::Error::other)?;
+
+        let scans = u32::from(matches!(event.event, Some(Event::Scan(_))));
+        let entry = self.entry.get_or_insert_with(|| StreamLogBlockEntry {
+            first_elapsed_us: elapsed_us,
+            ..Default::default()
+        });
+        entry.record_count += 1;
+        entry.scan_count += scans;
+        self.index.record_count += 1;
+        self.index.scan_count += u64::from(scans);
+        self.index.duration_us = elapsed_us;
+
+        if self.block.len() >= BLOCK_SIZE || elapsed_us - entry.first_elapsed_us >= BLOCK_SPAN_US {
+            self.write_block()?;
+        }
+        Ok(())
+    }
+
+    /// Events written so far
+    pub fn record_count(&self) -> u64 {
+        self.index.record_count
+    }
+
+    /// Writes the last block, the index and the footer
+    pub fn finish(mut self) -> io::Result<W> {
+        self.write_block()?;
+        let index_offset = self.written;
+        let index = std::mem::take(&mut self.index);
+        self.write_frame(Frame::Index(index))?;
+        self.out.write_all(&index_offset.to_le_bytes())?;
+        self.out.write_all(MAGIC)?;
+        self.out.flush()?;
+        Ok(self.out)
+    }
+
+    fn write_block(&mut self) -> io::Result<()> {
+        let Some(mut entry) = self.entry.take() else {
+            return Ok(());
+        };
+        let data = if self.zstd {
+            zstd::bulk::compress(&self.block, 0)?
+        } else {
+            self.block.clone()
+        };
+        self.block.clear();
+
+        entry.offset = self.written;
+        self.write_frame(Frame::Block(data))?;
+        // Complete blocks survive a crash of the writing process
+        self.out.flush()?;
+        self.index.blocks.push(entry);
+        Ok(())
+    }
+
+    fn write_frame(&mut self, frame: Frame) -> io::Result<()> {
+        let bytes = StreamLogFrame { frame: Some(frame) }.encode_length_delimited_to_vec();
+        self.out.write_all(&bytes)?;
+        self.written += bytes.len() as u64;
+        Ok(())
+    }
+}
+
+pub struct StreamLogReader<R: Read> {
+    input: R,
+    header: StreamLogHeader,
+    /// Records of the current block and the position of the next one
+    block: Vec<u8>,
+    position: usize,
+    index: Option<StreamLogIndex>,
+    /// Whether the log ended without its index
+    truncated: bool,
+}
+
+impl StreamLogReader<BufReader<File>> {
+    pub fn open(path: &Path) -> io::Result<Self> {
+        Self::new(BufReader::new(File::open(path)?))
+    }
+}
+
+impl<R: Read> StreamLogReader<R> {
+    /// Reads the magic and the header
+    pub fn new(mut input: R) -> io::Result<Self> {
+        let mut magic = [0; MAGIC.len()];
+        match input.read_exact(&mut magic) {
+            Ok(()) if &magic == MAGIC => {}
+            Ok(()) => return Err(invalid_data("Not a stream log")),
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                return Err(invalid_data("Not a stream log"))
+            }
+            Err(e) => return Err(e),
+        }
+        let header = read_message::<StreamLogHeader>(&mut input)?
+            .ok_or_else(|| invalid_data("Stream log header is incomplete"))?;
+        if header.version > FORMAT_VERSION {
+            return Err(invalid_data(format!(
+                "Stream log version {} is newer than the supported version {}",
+                header.version, FORMAT_VERSION
+            )));
+        }
+
+        Ok(Self {
+            input,
+            header,
+            block: Vec::new(),
+            position: 0,
+            index: None,
+            truncated: false,
+        })
+    }
+
+    pub fn header(&self) -> &StreamLogHeader {
+        &self.header
+    }
+
+    /// The next record, or `None` after the last one. A log that was cut short ends
+    /// after its last complete block, and [`Self::truncated`] tells so.
+    pub fn next_record(&mut self) -> io::Result<Option<StreamLogRecord>> {
+        loop {
+            if self.position < self.block.len() {
+                let mut records = &self.block[self.position..];
+                let remaining = records.len();
+                let record = StreamLogRecord::decode_length_delimited(&mut records)
+                    .map_err(|e| invalid_data(format!("Corrupt stream log record: {}", e)))?;
+                self.position += remaining - records.len();
+                return Ok(Some(record));
+            }
+            if self.index.is_some() || self.truncated {
+                return Ok(None);
+            }
+
+            match read_message::<StreamLogFrame>(&mut self.input)? {
+                Some(StreamLogFrame {
+                    frame: Some(Frame::Block(data)),
+                }) => {
+                    self.block = match self.header.compression() {
+                        StreamLogCompression::Zstd => zstd::stream::decode_all(&data[..])?,
+                        StreamLogCompression::None => data,
+                    };
+                    self.position = 0;
+                }
+                Some(StreamLogFrame {
+                    frame: Some(Frame::Index(index)),
+                }) => self.index = Some(index),
+                Some(StreamLogFrame { frame: None }) => {}
+                None => self.truncated = true,
+            }
+        }
+    }
+
+    /// The index, once every record was read; `None` before then and for a truncated log
+    pub fn index(&self) -> Option<&StreamLogIndex> {
+        self.index.as_ref()
+    }
+
+    /// Whether the log ended without its index
+    pub fn truncated(&self) -> bool {
+        self.truncated
+    }
+}
+
+/// Reads the index through the footer, without reading the records. `None` if the log
+/// has no footer, as when it was cut short.
+pub fn read_index<R: Read + Seek>(input: &mut R) -> io::Result<Option<StreamLogIndex>> {
+    if input.seek(SeekFrom::End(0))? < (MAGIC.len() as u64) + FOOTER_SIZE as u64 {
+        return Ok(None);
+    }
+    input.seek(SeekFrom::End(-FOOTER_SIZE))?;
+    let mut footer = [0; FOOTER_SIZE as usize];
+    input.read_exact(&mut footer)?;
+    let (offset, magic) = footer.split_at(8);
+    if magic != MAGIC {
+        return Ok(None);
+    }
+
+    let offset = u64::from_le_bytes(offset.try_into().expect("8 bytes"));
+    input.seek(SeekFrom::Start(offset))?;
+    match read_message::<StreamLogFrame>(input)? {
+        Some(StreamLogFrame {
+            frame: Some(Frame::Index(index)),
+        }) => Ok(Some(index)),
+        _ => Err(invalid_data(
+            "Stream log footer does not point at the index",
+        )),
+    }
+}
+
+/// Whether the file at `path` starts like a stream log
+pub fn is_stream_log(path: &Path) -> io::Result<bool> {
+    let mut magic = [0; MAGIC.len()];
+    match File::open(path)?.read_exact(&mut magic) {
+        Ok(()) => Ok(&magic == MAGIC),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// Reads a length-delimited message; `None` at the end of the input or if the input ends
+/// within the message
+fn read_message<M: Message + Default>(input: &mut impl Read) -> io::Result<Option<M>> {
+    let Some(length) = read_length(input)? else {
+        return Ok(None);
+    };
+    if length > MAX_FRAME_SIZE {
+        return Err(invalid_data(format!(
+            "Stream log frame of {} bytes exceeds the limit",
+            length
+        )));
+    }
+
+    let mut bytes = vec![0; length as usize];
+    match input.read_exact(&mut bytes) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+    M::decode(&bytes[..])
+        .map(Some)
+        .map_err(|e| invalid_data(format!("Corrupt stream log: {}", e)))
+}
+
+/// Reads a varint length prefix; `None` at the end of the input
+fn read_length(input: &mut impl Read) -> io::Result<Option<u64>> {
+    let mut length = 0u64;
+    for shift in (0..64).step_by(7) {
+        let mut byte = [0];
+        match input.read_exact(&mut byte) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e),
+        }
+        length |= u64::from(byte[0] & 0x7f) << shift;
+        if byte[0] & 0x80 == 0 {
+            return Ok(Some(length));
+        }
+    }
+    Err(invalid_data("Corrupt stream log frame length"))
+}
+
+fn invalid_data(message: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    fn header(compression: StreamLogCompression) -> StreamLogHeader {
+        StreamLogHeader {
+            instrument_id: "SIM-001".to_string(),
+            session_id: "session-1".to_string(),
+            start_timestamp_ms: 1_700_000_000_000,
+            compression: compression as i32,
+            writer: "tests".to_string(),
+            ..Default::default()
+        }
+    }
+
+    /// Scans every 300 ms framed by the session events, so the log spans several blocks
+    fn events() -> Vec<(Duration, StreamEvent)> {
+        let event = |event| StreamEvent {
+            session_id: "session-1".to_string(),
+            timestamp_ms: 1_700_000_000_000,
+            event: Some(event),
+        };
+        let mut events = vec![(
+            Duration::ZERO,
+            event(Event::SessionOpened(SessionOpened::default())),
+        )];
+        for scan_number in 1..=20 {
+            let scan = ScanMessage {
+                scan_number,
+                ms_order: 1,
+                mz_values: vec![400.0, 500.0 + f64::from(scan_number)],
+                intensity_values: vec![1e6, 2e6],
+                ..Default::default()
+            };
+            let elapsed = Duration::from_millis(300 * scan_number as u64);
+            events.push((elapsed, event(Event::Scan(scan))));
+        }
+        events.push((
+            Duration::from_millis(6_050),
+            event(Event::SessionClosed(SessionClosed::default())),
+        ));
+        events
+    }
+
+    fn write(compression: StreamLogCompression) -> Vec<u8> {
+        let mut writer = StreamLogWriter::new(Vec::new(), header(compression)).unwrap();
+        for (elapsed, event) in &events() {
+            writer.write(*elapsed, event).unwrap();
+        }
+        assert_eq!(writer.record_count(), events().len() as u64);
+        writer.finish().unwrap()
+    }
+
+    fn read_all(log: &[u8]) -> (StreamLogReader<&[u8]>, Vec<StreamLogRecord>) {
+        let mut reader = StreamLogReader::new(log).unwrap();
+        let mut records = Vec::new();
+        while let Some(record) = reader.next_record().unwrap() {
+            records.push(record);
+        }
+        (reader, records)
+    }
+
+    fn expected_records() -> Vec<StreamLogRecord> {
+        events()
+            .into_iter()
+            .map(|(elapsed, event)| StreamLogRecord {
+                elapsed_us: elapsed.as_micros() as i64,
+                event: Some(event),
+            })
+            .collect()
+    }
+
+    fn round_trip(compression: StreamLogCompression) {
+        let log = write(compression);
+        let (reader, records) = read_all(&log);
+
+        assert_eq!(reader.header().version, FORMAT_VERSION);
+        assert_eq!(reader.header().compression(), compression);
+        assert_eq!(records, expected_records());
+        assert!(!reader.truncated());
+
+        let index = reader.index().unwrap();
+        assert_eq!(index.record_count, records.len() as u64);
+        assert_eq!(index.scan_count, 20);
+        assert_eq!(index.duration_us, 6_050_000);
+        assert!(index.blocks.len() > 1);
+        assert_eq!(
+            read_index(&mut Cursor::new(&log)).unwrap().as_ref(),
+            Some(index)
+        );
+    }
+
+    #[test]
+    fn round_trip_uncompressed() {
+        round_trip(StreamLogCompression::None);
+    }
+
+    #[test]
+    fn round_trip_zstd() {
+        round_trip(StreamLogCompression::Zstd);
+    }
+
+    #[test]
+    fn truncated_log_keeps_complete_blocks() {
+        let log = write(StreamLogCompression::Zstd);
+        let index = read_index(&mut Cursor::new(&log)).unwrap().unwrap();
+        let cut = &index.blocks[2];
+        let truncated = &log[..cut.offset as usize + 5];
+
+        let (reader, records) = read_all(truncated);
+
+        assert!(reader.truncated());
+        assert!(reader.index().is_none());
+        let complete = index.blocks[..2]
+            .iter()
+            .map(|block| block.record_count as usize)
+            .sum::<usize>();
+        assert_eq!(records, expected_records()[..complete]);
+        assert_eq!(read_index(&mut Cursor::new(truncated)).unwrap(), None);
+    }
+
+    #[test]
+    fn rejects_other_files() {
+        for data in [&b""[..], b"LCMS", b"<?xml version=\"1.0\"?><mzML/>"] {
+            let error = StreamLogReader::new(data).err().unwrap();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        }
+    }
+}
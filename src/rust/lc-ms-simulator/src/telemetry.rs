@@ -0,0 +1,160 @@
+//! Logging and distributed tracing setup.
+//!
+//! Log output is filtered with `RUST_LOG`-style directives and written as text or JSON.
+//! When an OTLP endpoint is configured, spans are also exported over OTLP/gRPC so that
+//! simulator activity shows up in Jaeger next to the .NET client spans.
+
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use opentelemetry::propagation::Extractor;
+use opentelemetry::trace::TracerProvider as _;
+use opentelemetry::{global, KeyValue};
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use opentelemetry_sdk::trace::TracerProvider;
+use opentelemetry_sdk::{runtime, Resource};
+use tonic::codegen::http;
+use tracing::{field, info_span, Span};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{EnvFilter, Layer};
+
+use crate::fleet::INSTRUMENT_ID_METADATA;
+
+const SERVICE_NAME: &str = "lc-ms-simulator";
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+/// Flushes and shuts down the trace exporter when dropped
+pub struct TelemetryGuard {
+    provider: Option<TracerProvider>,
+}
+
+impl Drop for TelemetryGuard {
+    fn drop(&mut self) {
+        if let Some(provider) = self.provider.take() {
+            if let Err(e) = provider.shutdown() {
+                eprintln!("Failed to shut down trace exporter: {}", e);
+            }
+        }
+    }
+}
+
+/// Installs the global subscriber: an `EnvFilter` built from `filter`, a text or JSON
+/// formatter, and an OpenTelemetry layer when `otlp_endpoint` is set.
+pub fn init(
+    filter: &str,
+    format: LogFormat,
+    otlp_endpoint: Option<&str>,
+    instance_id: &str,
+) -> Result<TelemetryGuard> {
+    let filter =
+        EnvFilter::try_new(filter).with_context(|| format!("Invalid log filter '{}'", filter))?;
+
+    let fmt_layer = match format {
+        LogFormat::Text => tracing_subscriber::fmt::layer()
+            .with_target(true)
+            .with_thread_ids(true)
+            .with_file(true)
+            .with_line_number(true)
+            .boxed(),
+        LogFormat::Json => tracing_subscriber::fmt::layer()
+            .json()
+            .with_current_span(true)
+            .with_span_list(false)
+            .with_thread_ids(true)
+            .with_file(true)
+            .with_line_number(true)
+            .boxed(),
+    };
+
+    let provider = otlp_endpoint
+        .map(|endpoint| build_provider(endpoint, instance_id))
+        .transpose()?;
+    let otel_layer = provider
+        .as_ref()
+        .map(|provider| tracing_opentelemetry::layer().with_tracer(provider.tracer(SERVICE_NAME)));
+
+    global::set_text_map_propagator(TraceContextPropagator::new());
+
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(fmt_layer)
+        .with(otel_layer)
+        .try_init()
+        .context("Failed to set tracing subscriber")?;
+
+    Ok(TelemetryGuard { provider })
+}
+
+fn build_provider(endpoint: &str, instance_id: &str) -> Result<TracerProvider> {
+    let exporter = opentelemetry_otlp::SpanExporter::builder()
+        .with_tonic()
+        .with_endpoint(endpoint)
+        .build()
+        .with_context(|| format!("Failed to create OTLP exporter for {}", endpoint))?;
+
+    let provider = TracerProvider::builder()
+        .with_batch_exporter(exporter, runtime::Tokio)
+        .with_resource(Resource::new([
+            KeyValue::new("service.name", SERVICE_NAME),
+            KeyValue::new("service.instance.id", instance_id.to_string()),
+        ]))
+        .build();
+    global::set_tracer_provider(provider.clone());
+
+    Ok(provider)
+}
+
+/// Creates the span for one gRPC call, continuing the caller's trace when the request
+/// carries a W3C `traceparent` header. `session_id` is recorded by the handler.
+pub fn grpc_span(request: &http::Request<()>) -> Span {
+    let path = request.uri().path();
+    let (service, method) = path
+        .trim_start_matches('/')
+        .split_once('/')
+        .unwrap_or(("", path));
+
+    let span = info_span!(
+        "grpc",
+        otel.name = %path.trim_start_matches('/'),
+        otel.kind = "server",
+        rpc.system = "grpc",
+        rpc.service = %service,
+        rpc.method = %method,
+        instrument_id = field::Empty,
+        session_id = field::Empty,
+    );
+    if let Some(id) = request
+        .headers()
+        .get(INSTRUMENT_ID_METADATA)
+        .and_then(|value| value.to_str().ok())
+    {
+        span.record("instrument_id", id);
+    }
+
+    let parent = global::get_text_map_propagator(|propagator| {
+        propagator.extract(&HeaderExtractor(request.headers()))
+    });
+    span.set_parent(parent);
+    span
+}
+
+struct HeaderExtractor<'a>(&'a http::HeaderMap);
+
+impl Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|value| value.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|key| key.as_str()).collect()
+    }
+}
@@ -0,0 +1,126 @@
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::path::Path;
+use std::sync::Arc;
+
+use anyhow::{bail, Context, Result};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio_rustls::rustls::crypto::ring;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use tokio_rustls::rustls::server::WebPkiClientVerifier;
+use tokio_rustls::rustls::{RootCertStore, ServerConfig};
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
+use tokio_stream::wrappers::ReceiverStream;
+use tracing::{debug, warn};
+
+/// Builds a TLS acceptor from PEM files.
+///
+/// When `client_ca` is set, clients must present a certificate signed by one of
+/// its CAs (mutual TLS); otherwise any client may connect over TLS. `http1` also offers
+/// HTTP/1.1 during ALPN, for gRPC-Web clients.
+pub fn load_acceptor(
+    cert: &Path,
+    key: &Path,
+    client_ca: Option<&Path>,
+    http1: bool,
+) -> Result<TlsAcceptor> {
+    let provider = Arc::new(ring::default_provider());
+    let certs = load_certs(cert)?;
+    let key = load_key(key)?;
+
+    let builder = ServerConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()
+        .context("Failed to configure TLS protocol versions")?;
+
+    let builder = match client_ca {
+        Some(path) => {
+            let mut roots = RootCertStore::empty();
+            for ca in load_certs(path)? {
+                roots.add(ca).with_context(|| {
+                    format!("Invalid client CA certificate in {}", path.display())
+                })?;
+            }
+            let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider)
+                .build()
+                .context("Failed to build client certificate verifier")?;
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+
+    let mut config = builder
+        .with_single_cert(certs, key)
+        .context("Server certificate and private key do not match")?;
+    config.alpn_protocols = vec![b"h2".to_vec()];
+    if http1 {
+        config.alpn_protocols.push(b"http/1.1".to_vec());
+    }
+
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+/// Accepts TCP connections and performs TLS handshakes off the accept loop.
+///
+/// Failed handshakes (including rejected client certificates) are logged and
+/// dropped; only established TLS streams are handed to the gRPC server.
+pub fn incoming(
+    listener: TcpListener,
+    acceptor: TlsAcceptor,
+) -> ReceiverStream<io::Result<TlsStream<TcpStream>>> {
+    let (tx, rx) = mpsc::channel(64);
+
+    tokio::spawn(async move {
+        loop {
+            let (stream, peer) = match listener.accept().await {
+                Ok(conn) => conn,
+                Err(e) => {
+                    warn!("Failed to accept TCP connection: {}", e);
+                    continue;
+                }
+            };
+
+            if let Err(e) = stream.set_nodelay(true) {
+                debug!("Failed to set TCP_NODELAY for {}: {}", peer, e);
+            }
+
+            // The server has shut down and dropped the receiving end.
+            if tx.is_closed() {
+                break;
+            }
+
+            let acceptor = acceptor.clone();
+            let conn_tx = tx.clone();
+            tokio::spawn(async move {
+                match acceptor.accept(stream).await {
+                    Ok(tls_stream) => {
+                        let _ = conn_tx.send(Ok(tls_stream)).await;
+                    }
+                    Err(e) => warn!("Rejected TLS connection from {}: {}", peer, e),
+                }
+            });
+        }
+    });
+
+    ReceiverStream::new(rx)
+}
+
+fn load_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>> {
+    let file = File::open(path).with_context(|| format!("Cannot open {}", path.display()))?;
+    let certs = rustls_pemfile::certs(&mut BufReader::new(file))
+        .collect::<Result<Vec<_>, _>>()
+        .with_context(|| format!("Invalid PEM certificate in {}", path.display()))?;
+
+    if certs.is_empty() {
+        bail!("No certificates found in {}", path.display());
+    }
+    Ok(certs)
+}
+
+fn load_key(path: &Path) -> Result<PrivateKeyDer<'static>> {
+    let file = File::open(path).with_context(|| format!("Cannot open {}", path.display()))?;
+    rustls_pemfile::private_key(&mut BufReader::new(file))
+        .with_context(|| format!("Invalid PEM private key in {}", path.display()))?
+        .with_context(|| format!("No private key found in {}", path.display()))
+}
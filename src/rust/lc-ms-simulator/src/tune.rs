@@ -0,0 +1,289 @@
+//! Ion source tune parameters with setpoints and lagging readbacks.
+//!
+//! Each instrument profile has its own parameter table, mirroring the values the
+//! instrument API exposes. A new setpoint is approached exponentially with the
+//! parameter's time constant, so temperatures take minutes to settle while voltages
+//! follow almost at once. Spray voltage and the desolvation temperature feed back into
+//! scan generation through [`SignalConditions`].
+
+use std::sync::Mutex;
+use std::time::Instant;
+
+use rand::Rng;
+
+use crate::proto;
+
+/// Readbacks within this fraction of a parameter's range count as settled
+const SETTLED_TOLERANCE: f64 = 0.005;
+
+/// Effect of a parameter's readback on generated signal
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SignalEffect {
+    None,
+    /// Below ~2.5 kV the spray becomes unstable and weak; above 4.5 kV it discharges
+    SprayVoltage,
+    /// Poor desolvation below the default temperature reduces signal
+    Desolvation,
+}
+
+pub struct TuneParameter {
+    pub name: &'static str,
+    pub display_name: &'static str,
+    pub unit: &'static str,
+    pub min: f64,
+    pub max: f64,
+    pub default: f64,
+    /// First-order time constant of the readback
+    pub time_constant_seconds: f64,
+    /// Amplitude of random readback noise
+    pub readback_noise: f64,
+    pub effect: SignalEffect,
+}
+
+const fn parameter(
+    name: &'static str,
+    display_name: &'static str,
+    unit: &'static str,
+    (min, max, default): (f64, f64, f64),
+    time_constant_seconds: f64,
+    readback_noise: f64,
+    effect: SignalEffect,
+) -> TuneParameter {
+    TuneParameter {
+        name,
+        display_name,
+        unit,
+        min,
+        max,
+        default,
+        time_constant_seconds,
+        readback_noise,
+        effect,
+    }
+}
+
+/// HESI source values on the Q Exactive series
+#[rustfmt::skip]
+pub static Q_EXACTIVE_TUNE: &[TuneParameter] = &[
+    parameter("spray_voltage", "Spray Voltage", "V", (0.0, 6000.0, 3500.0), 0.5, 5.0, SignalEffect::SprayVoltage),
+    parameter("capillary_temperature", "Capillary Temperature", "°C", (0.0, 400.0, 275.0), 60.0, 0.5, SignalEffect::Desolvation),
+    parameter("s_lens_rf_level", "S-Lens RF Level", "%", (0.0, 100.0, 50.0), 0.1, 0.0, SignalEffect::None),
+    parameter("sheath_gas", "Sheath Gas Flow Rate", "arb", (0.0, 100.0, 35.0), 5.0, 0.3, SignalEffect::None),
+    parameter("aux_gas", "Aux Gas Flow Rate", "arb", (0.0, 50.0, 10.0), 5.0, 0.2, SignalEffect::None),
+    parameter("sweep_gas", "Sweep Gas Flow Rate", "arb", (0.0, 20.0, 0.0), 5.0, 0.1, SignalEffect::None),
+    parameter("aux_gas_heater_temperature", "Aux Gas Heater Temperature", "°C", (0.0, 550.0, 300.0), 90.0, 1.0, SignalEffect::None),
+];
+
+/// OptaMax NG source values on Exploris, Tribrid and Astral instruments
+#[rustfmt::skip]
+pub static OPTAMAX_TUNE: &[TuneParameter] = &[
+    parameter("spray_voltage", "Spray Voltage", "V", (0.0, 5000.0, 3500.0), 0.5, 5.0, SignalEffect::SprayVoltage),
+    parameter("ion_transfer_tube_temperature", "Ion Transfer Tube Temp", "°C", (0.0, 400.0, 275.0), 60.0, 0.5, SignalEffect::Desolvation),
+    parameter("rf_lens", "RF Lens", "%", (0.0, 200.0, 70.0), 0.1, 0.0, SignalEffect::None),
+    parameter("sheath_gas", "Sheath Gas", "arb", (0.0, 100.0, 35.0), 5.0, 0.3, SignalEffect::None),
+    parameter("aux_gas", "Aux Gas", "arb", (0.0, 50.0, 7.0), 5.0, 0.2, SignalEffect::None),
+    parameter("sweep_gas", "Sweep Gas", "arb", (0.0, 20.0, 0.0), 5.0, 0.1, SignalEffect::None),
+    parameter("vaporizer_temperature", "Vaporizer Temp", "°C", (0.0, 550.0, 275.0), 90.0, 1.0, SignalEffect::None),
+];
+
+/// Scales and destabilizes MS1 intensities according to the source conditions
+#[derive(Debug, Clone, Copy)]
+pub struct SignalConditions {
+    pub intensity_scale: f64,
+    /// Relative standard deviation of the per-scan intensity multiplier
+    pub instability: f64,
+}
+
+impl Default for SignalConditions {
+    fn default() -> Self {
+        Self {
+            intensity_scale: 1.0,
+            instability: 0.05,
+        }
+    }
+}
+
+struct ParameterState {
+    definition: &'static TuneParameter,
+    setpoint: f64,
+    /// Readback when the setpoint last changed
+    start: f64,
+    changed_at: Instant,
+}
+
+impl ParameterState {
+    /// Noise-free readback: exponential approach from `start` to the setpoint
+    fn ideal_readback(&self, now: Instant) -> f64 {
+        self.setpoint + (self.start - self.setpoint) * self.remaining(now)
+    }
+
+    /// Fraction of the step still to go
+    fn remaining(&self, now: Instant) -> f64 {
+        let tau = self.definition.time_constant_seconds;
+        if tau <= 0.0 {
+            return 0.0;
+        }
+        (-now.duration_since(self.changed_at).as_secs_f64() / tau).exp()
+    }
+}
+
+/// A setpoint change that was applied
+pub struct SetpointChange {
+    pub name: &'static str,
+    pub old_setpoint: f64,
+    pub new_setpoint: f64,
+}
+
+/// Current setpoints of one instrument
+pub struct TuneState {
+    parameters: Mutex<Vec<ParameterState>>,
+}
+
+impl TuneState {
+    /// Starts with every parameter settled at its default
+    pub fn new(table: &'static [TuneParameter]) -> Self {
+        let now = Instant::now();
+        Self {
+            parameters: Mutex::new(
+                table
+                    .iter()
+                    .map(|definition| ParameterState {
+                        definition,
+                        setpoint: definition.default,
+                        start: definition.default,
+                        changed_at: now,
+                    })
+                    .collect(),
+            ),
+        }
+    }
+
+    /// Reads the named parameters, or all of them when `names` is empty
+    pub fn values(&self, names: &[String]) -> Result<Vec<proto::InstrumentValue>, String> {
+        let parameters = self.parameters.lock().unwrap();
+        let now = Instant::now();
+        let mut random = rand::thread_rng();
+
+        let selected: Vec<&ParameterState> = if names.is_empty() {
+            parameters.iter().collect()
+        } else {
+            names
+                .iter()
+                .map(|name| find(&parameters, name))
+                .collect::<Result<_, _>>()?
+        };
+
+        Ok(selected
+            .into_iter()
+            .map(|state| {
+                let definition = state.definition;
+                let noise = if definition.readback_noise > 0.0 {
+                    random.gen_range(-definition.readback_noise..definition.readback_noise)
+                } else {
+                    0.0
+                };
+                let readback = state.ideal_readback(now);
+                proto::InstrumentValue {
+                    name: definition.name.to_string(),
+                    display_name: definition.display_name.to_string(),
+                    unit: definition.unit.to_string(),
+                    setpoint: state.setpoint,
+                    readback: (readback + noise).max(0.0),
+                    min: definition.min,
+                    max: definition.max,
+                    settled: (readback - state.setpoint).abs()
+                        <= SETTLED_TOLERANCE * (definition.max - definition.min),
+                    time_constant_seconds: definition.time_constant_seconds,
+                }
+            })
+            .collect())
+    }
+
+    /// Validates every setpoint, then applies them together. Unchanged values are not
+    /// reported as changes.
+    pub fn set(&self, setpoints: &[(String, f64)]) -> Result<Vec<SetpointChange>, String> {
+        let mut parameters = self.parameters.lock().unwrap();
+        for (name, value) in setpoints {
+            let definition = find(&parameters, name)?.definition;
+            if !(definition.min..=definition.max).contains(value) {
+                return Err(format!(
+                    "{} must be between {} and {} {}, got {}",
+                    name, definition.min, definition.max, definition.unit, value
+                ));
+            }
+        }
+
+        let now = Instant::now();
+        let mut changes = Vec::new();
+        for (name, value) in setpoints {
+            let state = parameters
+                .iter_mut()
+                .find(|state| state.definition.name == name)
+                .expect("validated above");
+            if state.setpoint == *value {
+                continue;
+            }
+            changes.push(SetpointChange {
+                name: state.definition.name,
+                old_setpoint: state.setpoint,
+                new_setpoint: *value,
+            });
+            state.start = state.ideal_readback(now);
+            state.setpoint = *value;
+            state.changed_at = now;
+        }
+        Ok(changes)
+    }
+
+    /// Noise-free readback of the first parameter with this effect
+    pub fn readback(&self, effect: SignalEffect) -> Option<f64> {
+        let parameters = self.parameters.lock().unwrap();
+        let now = Instant::now();
+        parameters
+            .iter()
+            .find(|state| state.definition.effect == effect)
+            .map(|state| state.ideal_readback(now))
+    }
+
+    /// Signal conditions produced by the current readbacks
+    pub fn conditions(&self) -> SignalConditions {
+        let parameters = self.parameters.lock().unwrap();
+        let now = Instant::now();
+        let mut conditions = SignalConditions::default();
+
+        for state in parameters.iter() {
+            let readback = state.ideal_readback(now);
+            match state.definition.effect {
+                SignalEffect::None => {}
+                SignalEffect::SprayVoltage => {
+                    if readback < 1000.0 {
+                        // No stable Taylor cone: essentially chemical noise only
+                        conditions.intensity_scale *= 0.01;
+                        conditions.instability += 1.0;
+                    } else if readback < 2500.0 {
+                        let deficit = (2500.0 - readback) / 1500.0;
+                        conditions.intensity_scale *= 1.0 - 0.8 * deficit;
+                        conditions.instability += 0.5 * deficit;
+                    } else if readback > 4500.0 {
+                        conditions.instability += (readback - 4500.0) / 1000.0;
+                    }
+                }
+                SignalEffect::Desolvation => {
+                    let optimum = state.definition.default;
+                    if readback < optimum {
+                        conditions.intensity_scale *= (readback / optimum).clamp(0.2, 1.0);
+                    }
+                }
+            }
+        }
+
+        conditions
+    }
+}
+
+fn find<'a>(parameters: &'a [ParameterState], name: &str) -> Result<&'a ParameterState, String> {
+    parameters
+        .iter()
+        .find(|state| state.definition.name == name)
+        .ok_or_else(|| format!("Unknown instrument value '{}'", name))
+}
@@ -0,0 +1,338 @@
+//! gRPC-Web and CORS for browser clients.
+//!
+//! Browsers cannot read HTTP/2 trailers, so gRPC-Web moves them into the body as a final
+//! frame flagged 0x80, and `application/grpc-web-text` additionally base64-encodes the
+//! body. [`GrpcWebLayer`] translates such requests to plain gRPC in front of the tonic
+//! router, so every RPC, the auth interceptor and server streaming work unchanged. It
+//! also answers CORS preflights, which the REST gateway shares through [`Cors`].
+
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use bytes::{BufMut, Bytes, BytesMut};
+use http::header::{self, HeaderMap, HeaderValue};
+use http::{Method, Request, Response, StatusCode};
+use http_body::{Body, Frame, SizeHint};
+use http_body_util::{BodyExt, Full, Limited};
+use tonic::body::BoxBody;
+use tonic::Status;
+use tower::{Layer, Service};
+
+/// Largest `application/grpc-web-text` request body accepted; requests are single
+/// messages, since browsers cannot stream them
+const MAX_TEXT_REQUEST_BYTES: usize = 4 * 1024 * 1024;
+
+/// Flag of the gRPC-Web frame that carries the trailers
+const TRAILERS_FLAG: u8 = 0x80;
+
+/// Headers a browser may read from gRPC-Web responses; needed for errors
+const EXPOSED_HEADERS: &str = "grpc-status, grpc-message, grpc-status-details-bin";
+
+/// Cross-origin access for browser clients
+pub struct Cors {
+    /// Allowed `Origin` values; `*` allows any origin
+    allowed_origins: Vec<String>,
+}
+
+impl Cors {
+    pub fn new(allowed_origins: &[String]) -> Self {
+        Self {
+            allowed_origins: allowed_origins.to_vec(),
+        }
+    }
+
+    pub fn is_preflight(method: &Method, headers: &HeaderMap) -> bool {
+        method == Method::OPTIONS && headers.contains_key(header::ACCESS_CONTROL_REQUEST_METHOD)
+    }
+
+    /// `Access-Control-Allow-Origin` for a request, if its origin is allowed
+    fn allow_origin(&self, request: &HeaderMap) -> Option<HeaderValue> {
+        let origin = request.get(header::ORIGIN)?;
+        if self.allowed_origins.iter().any(|allowed| allowed == "*") {
+            return Some(HeaderValue::from_static("*"));
+        }
+        let allowed = self
+            .allowed_origins
+            .iter()
+            .any(|allowed| allowed.as_bytes() == origin.as_bytes());
+        allowed.then(|| origin.clone())
+    }
+
+    /// Answers a preflight; without allow headers when the origin is not allowed, so the
+    /// browser blocks the actual request
+    pub fn preflight<B: Default>(&self, request: &HeaderMap) -> Response<B> {
+        let mut response = Response::new(B::default());
+        *response.status_mut() = StatusCode::NO_CONTENT;
+        let headers = response.headers_mut();
+        headers.insert(header::VARY, HeaderValue::from_static("origin"));
+
+        if let Some(origin) = self.allow_origin(request) {
+            headers.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, origin);
+            headers.insert(
+                header::ACCESS_CONTROL_ALLOW_METHODS,
+                HeaderValue::from_static("GET, POST, OPTIONS"),
+            );
+            if let Some(requested) = request.get(header::ACCESS_CONTROL_REQUEST_HEADERS) {
+                headers.insert(header::ACCESS_CONTROL_ALLOW_HEADERS, requested.clone());
+            }
+            headers.insert(
+                header::ACCESS_CONTROL_MAX_AGE,
+                HeaderValue::from_static("86400"),
+            );
+        }
+        response
+    }
+
+    /// Adds the CORS headers for an allowed origin to a response
+    pub fn apply(&self, request: &HeaderMap, response: &mut HeaderMap) {
+        response.append(header::VARY, HeaderValue::from_static("origin"));
+        if let Some(origin) = self.allow_origin(request) {
+            response.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, origin);
+            response.insert(
+                header::ACCESS_CONTROL_EXPOSE_HEADERS,
+                HeaderValue::from_static(EXPOSED_HEADERS),
+            );
+        }
+    }
+}
+
+/// gRPC-Web body format
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum WebMode {
+    Binary,
+    /// Base64, for clients that cannot handle binary responses
+    Text,
+}
+
+impl WebMode {
+    fn from_request(headers: &HeaderMap) -> Option<Self> {
+        let content_type = headers.get(header::CONTENT_TYPE)?.to_str().ok()?;
+        if content_type.starts_with("application/grpc-web-text") {
+            Some(WebMode::Text)
+        } else if content_type.starts_with("application/grpc-web") {
+            Some(WebMode::Binary)
+        } else {
+            None
+        }
+    }
+
+    fn content_type(self) -> HeaderValue {
+        HeaderValue::from_static(match self {
+            WebMode::Binary => "application/grpc-web+proto",
+            WebMode::Text => "application/grpc-web-text+proto",
+        })
+    }
+
+    fn encode(self, data: Bytes) -> Bytes {
+        match self {
+            WebMode::Binary => data,
+            WebMode::Text => STANDARD.encode(data).into(),
+        }
+    }
+}
+
+/// Serves gRPC-Web alongside gRPC when enabled; otherwise passes every request through
+#[derive(Clone)]
+pub struct GrpcWebLayer {
+    cors: Option<Arc<Cors>>,
+}
+
+impl GrpcWebLayer {
+    pub fn new(enabled: bool, cors: Arc<Cors>) -> Self {
+        Self {
+            cors: enabled.then_some(cors),
+        }
+    }
+}
+
+impl<S> Layer<S> for GrpcWebLayer {
+    type Service = GrpcWeb<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        GrpcWeb {
+            inner,
+            cors: self.cors.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct GrpcWeb<S> {
+    inner: S,
+    cors: Option<Arc<Cors>>,
+}
+
+impl<S> Service<Request<BoxBody>> for GrpcWeb<S>
+where
+    S: Service<Request<BoxBody>, Response = Response<BoxBody>> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = Response<GrpcWebBody>;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, S::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request<BoxBody>) -> Self::Future {
+        // The service that was polled ready handles this call; a clone takes its place
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+
+        let web = self.cors.clone().and_then(|cors| {
+            if Cors::is_preflight(request.method(), request.headers()) {
+                return Some((cors, None));
+            }
+            WebMode::from_request(request.headers()).map(|mode| (cors, Some(mode)))
+        });
+
+        match web {
+            None => {
+                let response = inner.call(request);
+                Box::pin(async move { Ok(response.await?.map(GrpcWebBody::grpc)) })
+            }
+            Some((cors, None)) => {
+                let response = cors.preflight(request.headers());
+                Box::pin(async move { Ok(response) })
+            }
+            Some((cors, Some(mode))) => Box::pin(async move {
+                let request_headers = request.headers().clone();
+                let response = match into_grpc_request(request, mode).await {
+                    Ok(request) => inner.call(request).await?,
+                    Err(status) => status.into_http(),
+                };
+
+                let (mut parts, body) = response.into_parts();
+                parts
+                    .headers
+                    .insert(header::CONTENT_TYPE, mode.content_type());
+                cors.apply(&request_headers, &mut parts.headers);
+                Ok(Response::from_parts(parts, GrpcWebBody::web(body, mode)))
+            }),
+        }
+    }
+}
+
+/// Turns a gRPC-Web request into the gRPC request tonic expects
+async fn into_grpc_request(
+    request: Request<BoxBody>,
+    mode: WebMode,
+) -> Result<Request<BoxBody>, Status> {
+    let (mut parts, body) = request.into_parts();
+    parts.headers.insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static("application/grpc"),
+    );
+    parts.headers.remove(header::CONTENT_LENGTH);
+
+    let body = match mode {
+        WebMode::Binary => body,
+        WebMode::Text => {
+            let text = Limited::new(body, MAX_TEXT_REQUEST_BYTES)
+                .collect()
+                .await
+                .map_err(|e| Status::invalid_argument(format!("Cannot read request: {}", e)))?
+                .to_bytes();
+
+            // Every chunk a client flushed is padded on its own, so decode in groups of
+            // four characters rather than as one string
+            let mut decoded = Vec::with_capacity(text.len() / 4 * 3);
+            for group in text.chunks(4) {
+                STANDARD.decode_vec(group, &mut decoded).map_err(|e| {
+                    Status::invalid_argument(format!("Invalid grpc-web-text body: {}", e))
+                })?;
+            }
+            tonic::body::boxed(Full::new(Bytes::from(decoded)))
+        }
+    };
+    Ok(Request::from_parts(parts, body))
+}
+
+/// Response body that is either passed through or re-framed as gRPC-Web
+pub struct GrpcWebBody {
+    inner: BoxBody,
+    mode: Option<WebMode>,
+}
+
+impl GrpcWebBody {
+    fn grpc(inner: BoxBody) -> Self {
+        Self { inner, mode: None }
+    }
+
+    fn web(inner: BoxBody, mode: WebMode) -> Self {
+        Self {
+            inner,
+            mode: Some(mode),
+        }
+    }
+}
+
+impl Default for GrpcWebBody {
+    fn default() -> Self {
+        Self::grpc(tonic::body::empty_body())
+    }
+}
+
+impl Body for GrpcWebBody {
+    type Data = Bytes;
+    type Error = Status;
+
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let this = self.get_mut();
+        let Some(mode) = this.mode else {
+            return Pin::new(&mut this.inner).poll_frame(cx);
+        };
+
+        loop {
+            let frame = match ready!(Pin::new(&mut this.inner).poll_frame(cx)) {
+                Some(Ok(frame)) => frame,
+                other => return Poll::Ready(other),
+            };
+            // Messages are already length-prefixed; trailers become the final frame
+            let data = match frame.into_data() {
+                Ok(data) => data,
+                Err(frame) => match frame.into_trailers() {
+                    Ok(trailers) => trailers_frame(&trailers),
+                    Err(_) => continue,
+                },
+            };
+            return Poll::Ready(Some(Ok(Frame::data(mode.encode(data)))));
+        }
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        match self.mode {
+            None => self.inner.size_hint(),
+            Some(_) => SizeHint::default(),
+        }
+    }
+}
+
+/// Encodes trailers as an HTTP/1 header block in a frame flagged as trailers
+fn trailers_frame(trailers: &HeaderMap) -> Bytes {
+    let mut block = BytesMut::new();
+    for (name, value) in trailers {
+        block.put_slice(name.as_str().as_bytes());
+        block.put_slice(b": ");
+        block.put_slice(value.as_bytes());
+        block.put_slice(b"\r\n");
+    }
+
+    let mut frame = BytesMut::with_capacity(5 + block.len());
+    frame.put_u8(TRAILERS_FLAG);
+    frame.put_u32(block.len() as u32);
+    frame.put(block);
+    frame.freeze()
+}